@@ -1,4 +1,13 @@
+use std::{env, path::PathBuf};
+
 fn main() {
-    tonic_build::compile_protos("proto/light.proto")
+    // migrations are embedded for the readiness check
+    println!("cargo:rerun-if-changed=migrations");
+
+    // the descriptor set is served by grpc reflection
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("light_descriptor.bin"))
+        .compile(&["proto/light.proto"], &["proto"])
         .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
 }
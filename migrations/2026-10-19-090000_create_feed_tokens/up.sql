@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS feed_tokens (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  token VARCHAR UNIQUE NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  revoked_at TIMESTAMPTZ
+);
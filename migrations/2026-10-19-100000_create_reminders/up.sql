@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS reminder_rules (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  minutes_before INTEGER NOT NULL CHECK (minutes_before > 0),
+  channel VARCHAR NOT NULL,
+  target VARCHAR,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS reminders (
+  id SERIAL PRIMARY KEY,
+  rule_id INTEGER NOT NULL,
+    CONSTRAINT fk_rule
+      FOREIGN KEY (rule_id) REFERENCES reminder_rules(id) ON DELETE CASCADE,
+  user_email VARCHAR NOT NULL,
+  task_key VARCHAR NOT NULL,
+  title VARCHAR NOT NULL,
+  due_at TIMESTAMPTZ NOT NULL,
+  fire_at TIMESTAMPTZ NOT NULL,
+  state VARCHAR NOT NULL DEFAULT 'pending',
+  attempts INTEGER NOT NULL DEFAULT 0,
+  sent_at TIMESTAMPTZ,
+  UNIQUE (rule_id, task_key, due_at)
+);
+
+CREATE INDEX IF NOT EXISTS reminders_pending ON reminders (fire_at) WHERE state = 'pending';
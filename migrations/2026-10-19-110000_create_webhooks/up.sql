@@ -0,0 +1,29 @@
+CREATE TABLE IF NOT EXISTS webhook_subscriptions (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  url VARCHAR NOT NULL,
+  secret VARCHAR NOT NULL,
+  -- empty for every event
+  event_types TEXT[] NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+  id SERIAL PRIMARY KEY,
+  subscription_id INTEGER NOT NULL,
+    CONSTRAINT fk_subscription
+      FOREIGN KEY (subscription_id) REFERENCES webhook_subscriptions(id) ON DELETE CASCADE,
+  event_type VARCHAR NOT NULL,
+  payload JSONB NOT NULL,
+  state VARCHAR NOT NULL DEFAULT 'pending',
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  last_status INTEGER,
+  last_error VARCHAR,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  delivered_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_pending ON webhook_deliveries (next_attempt_at) WHERE state = 'pending';
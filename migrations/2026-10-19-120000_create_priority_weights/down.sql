@@ -0,0 +1 @@
+DROP TABLE priority_weights;
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS priority_weights (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR UNIQUE NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  weights JSONB NOT NULL,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
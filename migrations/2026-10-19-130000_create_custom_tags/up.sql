@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS custom_tags (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  name VARCHAR NOT NULL,
+  colour VARCHAR NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE (user_email, name)
+);
+
+CREATE TABLE IF NOT EXISTS task_tags (
+  tag_id INTEGER NOT NULL,
+    CONSTRAINT fk_tag
+      FOREIGN KEY (tag_id) REFERENCES custom_tags(id) ON DELETE CASCADE,
+  -- `local:<id>` or `firefly:<id>`
+  task_key VARCHAR NOT NULL,
+  PRIMARY KEY (tag_id, task_key)
+);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS checklist_items (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  -- `local:<id>` or `firefly:<id>`
+  task_key VARCHAR NOT NULL,
+  position INTEGER NOT NULL,
+  title VARCHAR NOT NULL,
+  is_done BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  completed_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS checklist_items_task ON checklist_items (user_email, task_key, position);
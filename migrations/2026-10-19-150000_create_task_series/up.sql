@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS task_series (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  title VARCHAR NOT NULL,
+  -- how often it repeats, see `lumos::recurrence::Frequency`
+  frequency JSONB NOT NULL,
+  -- IANA name, which the times below are local to
+  timezone VARCHAR NOT NULL,
+  starts_at TIMESTAMP NOT NULL,
+  -- the last day an occurrence may fall on
+  until DATE,
+  -- occurrences up to here have been made into tasks, or were skipped
+  generated_until TIMESTAMP,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS task_series_user ON task_series (user_email);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS task_merges (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  -- `local:<id>` or `firefly:<id>`; the first is the task the others are merged into
+  task_keys TEXT[] NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS task_merges_user ON task_merges (user_email);
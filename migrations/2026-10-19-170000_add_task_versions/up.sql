@@ -0,0 +1,10 @@
+-- bumped whenever the local tasks change, so writes can be made conditional on it
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 1;
+
+-- each local task carries a version of its own too, which starts at 1
+UPDATE tasks
+SET local_tasks = (
+  SELECT COALESCE(jsonb_agg(task || '{"version": 1}'), '[]')
+  FROM jsonb_array_elements(local_tasks) AS task
+)
+WHERE jsonb_typeof(local_tasks) = 'array';
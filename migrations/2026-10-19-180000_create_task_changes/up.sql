@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS task_changes (
+  -- changes of a user are written one transaction at a time (their tasks row is locked), so their
+  -- sequence numbers only ever increase in the order they are committed
+  seq BIGSERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  -- `local:<id>` or `firefly:<id>`
+  task_key TEXT NOT NULL,
+  -- `created`, `updated` or `deleted`
+  kind VARCHAR NOT NULL,
+  -- the task after the change; NULL when it was deleted
+  task JSONB,
+  changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS task_changes_user_seq ON task_changes (user_email, seq);
+
+-- tasks from before the log began are logged as created, so that syncing from the start gets them
+-- (Firefly tasks are a placeholder object until the first sync)
+INSERT INTO task_changes (user_email, task_key, kind, task)
+SELECT user_email, origin || ':' || (task->>'id'), 'created', task
+FROM tasks,
+  LATERAL (VALUES ('local', local_tasks), ('firefly', firefly_tasks)) AS cached (origin, list),
+  jsonb_array_elements(CASE jsonb_typeof(list) WHEN 'array' THEN list ELSE '[]' END) AS task;
@@ -0,0 +1,2 @@
+DROP TABLE task_audit;
+DROP FUNCTION task_audit_append_only;
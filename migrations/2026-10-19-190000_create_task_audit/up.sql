@@ -0,0 +1,30 @@
+CREATE TABLE IF NOT EXISTS task_audit (
+  id BIGSERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  -- `local:<id>` or `firefly:<id>`
+  task_key TEXT NOT NULL,
+  -- `created`, `updated`, `deleted`, or `vanished` (missing from an unfiltered Firefly sync)
+  action VARCHAR NOT NULL,
+  -- `user`, `sync` or `system`
+  actor VARCHAR NOT NULL,
+  -- the fields of the task that changed
+  fields TEXT[] NOT NULL,
+  -- the task before and after; NULL before it was created and after it was deleted
+  old_value JSONB,
+  new_value JSONB,
+  recorded_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS task_audit_task ON task_audit (user_email, task_key, id);
+
+CREATE OR REPLACE FUNCTION task_audit_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'task_audit is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER task_audit_append_only
+  BEFORE UPDATE OR DELETE ON task_audit
+  FOR EACH STATEMENT EXECUTE FUNCTION task_audit_append_only();
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS trashed_tasks (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  -- `local:<id>` or `firefly:<id>`
+  task_key TEXT NOT NULL,
+  -- the task as it was when it was deleted; NULL once purged
+  task JSONB,
+  deleted_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  -- Firefly tasks stay hidden once purged from the trash, so their rows are kept
+  purged_at TIMESTAMPTZ,
+  UNIQUE (user_email, task_key)
+);
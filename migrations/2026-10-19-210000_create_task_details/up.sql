@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS task_details (
+  id SERIAL PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  -- `firefly:<id>`; local tasks have no details beyond the task itself
+  task_key TEXT NOT NULL,
+  detail JSONB NOT NULL,
+  fetched_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE (user_email, task_key)
+);
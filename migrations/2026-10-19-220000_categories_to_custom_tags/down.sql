@@ -0,0 +1,3 @@
+-- The custom tags made from categories can't be told apart from those the user made, so they are
+-- left as they are.
+SELECT 1;
@@ -0,0 +1,46 @@
+-- Tasks imported from calendars used to carry their categories as `Category` tags; they become
+-- custom tags, which are kept apart from the tasks.
+CREATE TEMPORARY TABLE imported_categories AS
+SELECT DISTINCT t.user_email, task.value->>'id' AS task_id, tag.value->'Category'->>'name' AS name
+FROM tasks t
+  CROSS JOIN LATERAL jsonb_array_elements(
+    CASE WHEN jsonb_typeof(t.local_tasks) = 'array' THEN t.local_tasks ELSE '[]' END
+  ) task
+  CROSS JOIN LATERAL jsonb_array_elements(
+    CASE WHEN jsonb_typeof(task.value->'tags') = 'array' THEN task.value->'tags' ELSE '[]' END
+  ) tag
+WHERE tag.value ? 'Category';
+
+INSERT INTO custom_tags (user_email, name, colour)
+SELECT DISTINCT user_email, left(btrim(name), 64), '#808080'
+FROM imported_categories
+WHERE btrim(name) <> ''
+ON CONFLICT (user_email, name) DO NOTHING;
+
+INSERT INTO task_tags (tag_id, task_key)
+SELECT c.id, 'local:' || i.task_id
+FROM imported_categories i
+  JOIN custom_tags c ON c.user_email = i.user_email AND c.name = left(btrim(i.name), 64)
+ON CONFLICT DO NOTHING;
+
+UPDATE tasks t
+SET local_tasks = (
+  SELECT jsonb_agg(
+    jsonb_set(
+      task.value,
+      '{tags}',
+      COALESCE(
+        (SELECT jsonb_agg(tag.value ORDER BY tag.ord)
+         FROM jsonb_array_elements(task.value->'tags') WITH ORDINALITY tag(value, ord)
+         WHERE NOT tag.value ? 'Category'),
+        '[]'
+      )
+    )
+    ORDER BY task.ord
+  )
+  FROM jsonb_array_elements(t.local_tasks) WITH ORDINALITY task(value, ord)
+)
+WHERE jsonb_typeof(t.local_tasks) = 'array'
+  AND t.user_email IN (SELECT user_email FROM imported_categories);
+
+DROP TABLE imported_categories;
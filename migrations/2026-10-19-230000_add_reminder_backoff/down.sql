@@ -0,0 +1 @@
+ALTER TABLE reminders DROP COLUMN next_attempt_at;
@@ -0,0 +1,2 @@
+-- a reminder that failed to go out is retried with a backoff, not on every tick
+ALTER TABLE reminders ADD COLUMN IF NOT EXISTS next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
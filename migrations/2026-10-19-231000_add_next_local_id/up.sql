@@ -0,0 +1,24 @@
+-- local task ids are handed out by lantern and never twice, so that what is kept by task key
+-- (tags, checklists, the trash, the logs) can't carry over to another task; 0 means "unnumbered"
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS next_local_id BIGINT NOT NULL DEFAULT 1;
+
+-- past every id in use, in the trash or in the changelog
+UPDATE tasks
+SET next_local_id = GREATEST(
+  1,
+  (
+    SELECT MAX((task->>'id')::BIGINT) + 1
+    FROM jsonb_array_elements(local_tasks) AS task
+  ),
+  (
+    SELECT MAX(split_part(task_key, ':', 2)::BIGINT) + 1
+    FROM trashed_tasks
+    WHERE trashed_tasks.user_email = tasks.user_email AND task_key LIKE 'local:%'
+  ),
+  (
+    SELECT MAX(split_part(task_key, ':', 2)::BIGINT) + 1
+    FROM task_changes
+    WHERE task_changes.user_email = tasks.user_email AND task_key LIKE 'local:%'
+  )
+)
+WHERE jsonb_typeof(local_tasks) = 'array';
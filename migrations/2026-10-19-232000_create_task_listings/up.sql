@@ -0,0 +1,13 @@
+-- the tasks a paged GetTasks listed, kept for the pages after the first
+CREATE TABLE IF NOT EXISTS task_listings (
+  id VARCHAR PRIMARY KEY,
+  user_email VARCHAR NOT NULL,
+    CONSTRAINT fk_email
+      FOREIGN KEY (user_email) REFERENCES users(email),
+  tasks JSONB NOT NULL,
+  -- the version the local tasks were at when they were listed
+  version BIGINT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS task_listings_user ON task_listings (user_email, created_at);
@@ -1,32 +1,147 @@
 use color_eyre::Result;
 use dotenvy::dotenv;
-use lantern::lumos::rpc::{LanternServer, TaskService};
-use tokio::sync::mpsc;
+use lantern::lumos::health;
+use lantern::lumos::http::{self, AppState};
+use lantern::lumos::recurrence;
+use lantern::lumos::redact;
+use lantern::lumos::reminder;
+use lantern::lumos::rpc::{light, LanternServer, TaskService};
+use lantern::lumos::shutdown::{self, Shutdown};
+use lantern::lumos::source::{self, Sources};
+use lantern::lumos::telemetry;
+use lantern::lumos::trash;
+use lantern::lumos::webhook;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tonic::transport::Server;
 
+/// How long in-flight requests and background work get to finish once a shutdown starts.
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exit code used when the shutdown deadline passed before everything finished.
+const EXIT_DEADLINE_EXCEEDED: u8 = 2;
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     dotenv().ok();
     color_eyre::install()?;
+    redact::install_panic_hook();
+    let _telemetry = telemetry::init()?;
     let addr = "[::1]:8080".parse().unwrap();
-    println!("Starting server on 127.0.0.1:8080");
+    let http_addr: SocketAddr = "[::1]:8081".parse().unwrap();
+    let deadline = std::env::var("LANTERN_SHUTDOWN_TIMEOUT")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+    tracing::info!("Starting server on 127.0.0.1:8080");
+
+    let shutdown = Shutdown::new();
+    let config = source::Config::from_env()?;
+    let db_conn = lantern::orm::pool()?;
+    let sources = Sources::connect(&config, db_conn.clone()).await?;
+    let tasks = Arc::new(TaskService::new(
+        config.email,
+        sources,
+        db_conn.clone(),
+        shutdown.clone(),
+    ));
+    let readiness = tasks.readiness();
+    let svc = LanternServer::from_arc(tasks.clone());
+
+    let (health_reporter, health_svc) = tonic_health::server::health_reporter();
+    shutdown.spawn(health::report(
+        health_reporter,
+        readiness.clone(),
+        shutdown.clone(),
+    ));
+
+    shutdown.spawn(reminder::run(
+        db_conn.clone(),
+        tasks.reminder_channels(),
+        shutdown.clone(),
+    ));
+    shutdown.spawn(webhook::run(db_conn.clone(), shutdown.clone()));
+    shutdown.spawn(recurrence::run(db_conn.clone(), shutdown.clone()));
+    shutdown.spawn(trash::run(db_conn.clone(), shutdown.clone()));
 
-    let svc = LanternServer::new(TaskService::new().await);
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(light::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()?;
+
+    tracing::info!(
+        "Serving health checks, metrics and the REST gateway on {}",
+        http_addr
+    );
+    let state = AppState {
+        readiness,
+        db_conn,
+        tasks,
+    };
+    let http = axum::Server::bind(&http_addr)
+        .serve(http::router(state).into_make_service())
+        .with_graceful_shutdown({
+            let shutdown = shutdown.clone();
+            async move { shutdown.triggered().await }
+        });
+    shutdown.spawn(async move {
+        if let Err(e) = http.await {
+            tracing::error!("Error = {:?}", e);
+        }
+    });
 
     let serve = Server::builder()
         .accept_http1(true)
+        .add_service(health_svc)
+        .add_service(reflection)
         .add_service(tonic_web::enable(svc))
-        .serve(addr);
+        .serve_with_shutdown(addr, {
+            let shutdown = shutdown.clone();
+            async move { shutdown.triggered().await }
+        });
+    let mut server = tokio::spawn(serve);
 
-    tokio::spawn(async move {
-        if let Err(e) = serve.await {
-            eprintln!("Error = {:?}", e);
-        };
+    tokio::select! {
+        signal = shutdown::signal() => tracing::info!("received {}, shutting down", signal),
+        res = &mut server => {
+            // the server stopped on its own, which only happens if something went wrong
+            tracing::error!("Error = {:?}", res);
+            shutdown.trigger();
+            shutdown.drain(deadline).await;
+            return Ok(ExitCode::FAILURE);
+        }
+    }
 
-        tx.send(()).unwrap();
-    });
+    // stop accepting connections, then give in-flight rpcs and tracked tasks the rest of the
+    // deadline to finish
+    shutdown.trigger();
+    let started = Instant::now();
+    let served = match tokio::time::timeout(deadline, &mut server).await {
+        Ok(Ok(Ok(()))) => true,
+        Ok(res) => {
+            tracing::error!("Error = {:?}", res);
+            false
+        }
+        Err(_) => {
+            tracing::error!("in-flight requests did not finish within {:?}", deadline);
+            server.abort();
+            return Ok(ExitCode::from(EXIT_DEADLINE_EXCEEDED));
+        }
+    };
+
+    let remaining = deadline.saturating_sub(started.elapsed());
+    if !shutdown.drain(remaining).await {
+        tracing::error!("background tasks did not finish within {:?}", deadline);
+        return Ok(ExitCode::from(EXIT_DEADLINE_EXCEEDED));
+    }
 
-    rx.recv().await;
-    Ok(())
+    tracing::info!("shutdown complete");
+    Ok(if served {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
 }
@@ -1,6 +1,28 @@
 // #![allow(unused)]
+pub mod audit;
+pub mod changelog;
+pub mod checklist;
+pub mod custom_tag;
+pub mod dedup;
+pub mod detail;
 pub mod error;
+pub mod event;
 pub mod filter;
+pub mod gateway;
+pub mod health;
+pub mod http;
+pub mod ics;
+pub mod listing;
+pub mod metrics;
+pub mod priority;
+pub mod recurrence;
+pub mod redact;
+pub mod reminder;
 pub mod rpc;
+pub mod shutdown;
+pub mod source;
 pub mod task;
+pub mod telemetry;
+pub mod trash;
 pub mod user;
+pub mod webhook;
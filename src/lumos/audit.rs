@@ -0,0 +1,155 @@
+//! An append-only record of what happened to each task, when, and who did it.
+//!
+//! Like the [change log](super::changelog), entries are worked out by diffing tasks whenever they
+//! are written, but they keep the task from before the change as well as after it, and say which
+//! fields changed and whether the user, a Firefly sync or lantern itself changed them. The table
+//! refuses updates and deletes, so history can't be rewritten.
+//!
+//! Firefly tasks are only recorded from syncs that got every one of them. Those missing from one
+//! were removed at Firefly rather than by anyone in lantern, and are recorded as
+//! [vanished](Action::Vanished) rather than deleted; those the user hides are deleted.
+use super::task::{task_key, AVTask};
+use crate::models::{NewTaskAuditPG, TaskAuditPG};
+
+use diesel::prelude::*;
+use std::collections::HashMap;
+use strum::EnumString;
+use strum_macros::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Action {
+    Created,
+    Updated,
+    Deleted,
+    /// The task was missing from an unfiltered Firefly sync.
+    Vanished,
+}
+
+/// Who changed a task.
+///
+/// There is no actor for an operator, as nothing in lantern lets one change a user's tasks; even
+/// purging the trash only gets rid of tasks that the user already deleted, which is recorded then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Actor {
+    /// The user, through the API.
+    User,
+    /// A sync with Firefly.
+    Sync,
+    /// Lantern itself, e.g. making the occurrences of a series.
+    System,
+}
+
+/// The fields that differ between `old` and `new`, by their name in the task's JSON.
+fn changed_fields(old: Option<&serde_json::Value>, new: Option<&serde_json::Value>) -> Vec<String> {
+    let fields = |task: Option<&serde_json::Value>| {
+        task.and_then(serde_json::Value::as_object)
+            .cloned()
+            .unwrap_or_default()
+    };
+    let (old, new) = (fields(old), fields(new));
+    let mut changed = old
+        .keys()
+        .chain(new.keys())
+        .filter(|field| old.get(*field) != new.get(*field))
+        .cloned()
+        .collect::<Vec<_>>();
+    changed.sort();
+    changed.dedup();
+    changed
+}
+
+/// Records how `actor` turned `before` into `after`, both lists of tasks from `origin`.
+///
+/// Firefly tasks from a sync must come from an unfiltered one, as any missing from `after` are
+/// recorded as vanished.
+pub fn record(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    actor: Actor,
+    before: &[AVTask],
+    after: &[AVTask],
+) -> QueryResult<()> {
+    let old = before
+        .iter()
+        .map(|task| (task.id, task))
+        .collect::<HashMap<_, _>>();
+    let new = after
+        .iter()
+        .map(|task| (task.id, task))
+        .collect::<HashMap<_, _>>();
+
+    let mut entries = vec![];
+    for task in after {
+        match old.get(&task.id) {
+            None => entries.push((Action::Created, task.id, None, Some(task))),
+            Some(old) if *old != task => {
+                entries.push((Action::Updated, task.id, Some(*old), Some(task)))
+            }
+            Some(_) => {}
+        }
+    }
+    let gone = if actor == Actor::Sync {
+        Action::Vanished
+    } else {
+        Action::Deleted
+    };
+    for task in before.iter().filter(|task| !new.contains_key(&task.id)) {
+        entries.push((gone, task.id, Some(task), None));
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let (actions, actor) = (
+        entries
+            .iter()
+            .map(|(action, ..)| action.to_string())
+            .collect::<Vec<_>>(),
+        actor.to_string(),
+    );
+    let rows = entries
+        .into_iter()
+        .zip(&actions)
+        .map(|((_, id, old, new), action)| {
+            let old = old.map(|task| serde_json::to_value(task).unwrap());
+            let new = new.map(|task| serde_json::to_value(task).unwrap());
+            NewTaskAuditPG {
+                user_email: email,
+                task_key: task_key(origin, id),
+                action,
+                actor: &actor,
+                fields: changed_fields(old.as_ref(), new.as_ref()),
+                old_value: old,
+                new_value: new,
+            }
+        })
+        .collect::<Vec<_>>();
+    diesel::insert_into(crate::schema::task_audit::table)
+        .values(&rows)
+        .execute(db_conn)?;
+    Ok(())
+}
+
+/// Gets up to `limit` entries for the task `key` of `email`, newest first, from before the entry
+/// `before` if given.
+pub fn history(
+    db_conn: &mut PgConnection,
+    email: &str,
+    key: &str,
+    before: Option<i64>,
+    limit: i64,
+) -> QueryResult<Vec<TaskAuditPG>> {
+    use crate::schema::task_audit::dsl::*;
+
+    let mut query = task_audit
+        .filter(user_email.eq(email))
+        .filter(task_key.eq(key))
+        .into_boxed();
+    if let Some(before) = before {
+        query = query.filter(id.lt(before));
+    }
+    query.order(id.desc()).limit(limit).load(db_conn)
+}
@@ -0,0 +1,220 @@
+//! A log of every change to a user's tasks, for clients that work offline and sync later.
+//!
+//! Like [events](super::event), changes are worked out by diffing tasks whenever they are written,
+//! and are numbered by a sequence that only ever increases for a user, so a client can ask for
+//! everything [since] the last change it saw. Clients [push] the changes they made
+//! offline to the local tasks, each against the [version](AVTask::version) of the task it was
+//! made to; those made to a task that changed since are reported back as conflicts rather than
+//! applied.
+//!
+//! Firefly tasks are only logged from syncs that got every one of them, so a Firefly task missing
+//! from one is gone at Firefly and is logged as deleted like a local task.
+use super::audit::Actor;
+use super::task::{task_key, AVTask};
+use super::user::utils::{cached_tasks, from_client, update_local_tasks, LocalTasks};
+use crate::models::{NewTaskChangePG, TaskChangePG};
+
+use diesel::prelude::*;
+use std::collections::HashMap;
+use strum::EnumString;
+use strum_macros::Display;
+
+/// How many changes are synced or pushed at once, at most.
+pub const MAX_CHANGES: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// Logs the changes that turn `before` into `after`, both lists of tasks from `origin`.
+///
+/// Firefly tasks from a sync must come from an unfiltered one, as any missing from `after` are
+/// logged as deleted.
+pub fn record(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    before: &[AVTask],
+    after: &[AVTask],
+) -> QueryResult<()> {
+    let old = before
+        .iter()
+        .map(|task| (task.id, task))
+        .collect::<HashMap<_, _>>();
+    let mut changes = vec![];
+    for task in after {
+        let kind = match old.get(&task.id) {
+            None => ChangeKind::Created,
+            Some(old) if *old != task => ChangeKind::Updated,
+            Some(_) => continue,
+        };
+        changes.push((kind, task.id, Some(task)));
+    }
+    let new = after.iter().map(|task| task.id).collect::<Vec<_>>();
+    for task in before.iter().filter(|task| !new.contains(&task.id)) {
+        changes.push((ChangeKind::Deleted, task.id, None));
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let kinds = changes
+        .iter()
+        .map(|(kind, _, _)| kind.to_string())
+        .collect::<Vec<_>>();
+    let rows = changes
+        .iter()
+        .zip(&kinds)
+        .map(|((_, id, task), kind)| NewTaskChangePG {
+            user_email: email,
+            task_key: task_key(origin, *id),
+            kind,
+            task: task.map(|task| serde_json::to_value(task).unwrap()),
+        })
+        .collect::<Vec<_>>();
+    diesel::insert_into(crate::schema::task_changes::table)
+        .values(&rows)
+        .execute(db_conn)?;
+    Ok(())
+}
+
+/// Gets up to `limit` changes of `email` after the change `since`, in order.
+pub fn since(
+    db_conn: &mut PgConnection,
+    email: &str,
+    since: i64,
+    limit: i64,
+) -> QueryResult<Vec<TaskChangePG>> {
+    use crate::schema::task_changes::dsl::*;
+
+    task_changes
+        .filter(user_email.eq(email))
+        .filter(seq.gt(since))
+        .order(seq)
+        .limit(limit)
+        .load(db_conn)
+}
+
+/// A change a client made to a local task while it was offline.
+#[derive(Debug, Clone)]
+pub enum Mutation {
+    /// Adds `task`, numbered by lantern if its id is 0; an id of its own must be one no task has
+    /// had.
+    Create(AVTask),
+    /// Replaces the task with the same id by `task`, as readied by [`from_client`].
+    Update {
+        task: AVTask,
+        base_version: u64,
+    },
+    Delete {
+        task_id: usize,
+        base_version: u64,
+    },
+}
+
+/// What became of a [`Mutation`].
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    /// The mutation was applied, leaving the task as given (if it wasn't deleted).
+    Applied(Option<AVTask>),
+    /// The mutation was not applied, as the task changed or is gone; the task is as given, if it
+    /// still exists.
+    Conflict {
+        reason: &'static str,
+        task: Option<AVTask>,
+    },
+}
+
+/// Applies `mutations` to the local tasks of `email`, in order, skipping those that conflict with
+/// what the tasks are by then.
+///
+/// A mutation conflicts if its `base_version` isn't the version of the task (0 applies it
+/// regardless), if it updates a task that is gone, or if it creates a task with an id that a task
+/// has had. Deleting a task that is already gone is not a conflict, so pushes can be retried.
+///
+/// Each mutation is written by itself, so that the ones after it see the versions it bumped; all
+/// of them are written together or not at all.
+pub fn push(
+    db_conn: &mut PgConnection,
+    email: &str,
+    mutations: Vec<Mutation>,
+) -> QueryResult<Vec<Outcome>> {
+    db_conn.transaction(|db_conn| {
+        let mut outcomes = Vec::with_capacity(mutations.len());
+        for mutation in mutations {
+            let result = update_local_tasks(db_conn, email, Actor::User, |loc_tasks| {
+                apply(loc_tasks, mutation)
+            })?;
+            // versions are only bumped once the tasks are written, so the task is read back
+            let mut current = |id| {
+                let (loc_tasks, _) = cached_tasks(db_conn, email)?;
+                QueryResult::Ok(loc_tasks.into_iter().find(|task| task.id == id))
+            };
+            outcomes.push(match result {
+                Ok(Some(id)) => Outcome::Applied(current(id)?),
+                Ok(None) => Outcome::Applied(None),
+                Err((reason, id)) => Outcome::Conflict {
+                    reason,
+                    task: id.map(&mut current).transpose()?.flatten(),
+                },
+            });
+        }
+        Ok(outcomes)
+    })
+}
+
+/// Applies `mutation` to `loc_tasks`, giving the id of the task it leaves, or why it conflicts
+/// along with the id of the task it conflicts with.
+fn apply(
+    loc_tasks: &mut LocalTasks,
+    mutation: Mutation,
+) -> Result<Option<usize>, (&'static str, Option<usize>)> {
+    let position = |loc_tasks: &[AVTask], id| loc_tasks.iter().position(|task| task.id == id);
+    let check = |task: &AVTask, base_version| {
+        if base_version != 0 && task.version != base_version {
+            return Err(("the task changed since", Some(task.id)));
+        }
+        Ok(())
+    };
+
+    match mutation {
+        Mutation::Create(mut task) => {
+            from_client(&mut task, None);
+            if task.id == 0 {
+                task.id = loc_tasks.next_id();
+            } else if !loc_tasks.claim(task.id) {
+                let existing = position(loc_tasks, task.id).map(|_| task.id);
+                return Err(("a task has had this id", existing));
+            }
+            let id = task.id;
+            loc_tasks.insert(0, task);
+            Ok(Some(id))
+        }
+        Mutation::Update {
+            mut task,
+            base_version,
+        } => {
+            let at = position(loc_tasks, task.id).ok_or(("no such task", None))?;
+            check(&loc_tasks[at], base_version)?;
+            from_client(&mut task, Some(&loc_tasks[at]));
+            let id = task.id;
+            loc_tasks[at] = task;
+            Ok(Some(id))
+        }
+        Mutation::Delete {
+            task_id,
+            base_version,
+        } => {
+            let Some(at) = position(loc_tasks, task_id) else {
+                return Ok(None);
+            };
+            check(&loc_tasks[at], base_version)?;
+            loc_tasks.remove(at);
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! Checklists that break a task, local or Firefly, into ordered steps.
+//!
+//! Like [custom tags](super::custom_tag), items are kept apart from the tasks by
+//! [task key](task_key) so that they survive Firefly syncs, and are attached to tasks whenever
+//! they are read, along with the fraction of them that is done.
+//!
+//! Positions are 0-based and kept contiguous within a task.
+use super::task::{task_key, AVTask, ChecklistItem, ORIGINS};
+use super::user::utils::find_task;
+use crate::models::{ChecklistItemPG, NewChecklistItemPG};
+
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use std::collections::{HashMap, HashSet};
+
+const MAX_TITLE_LEN: usize = 200;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChecklistError {
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("{0}")]
+    NotFound(&'static str),
+
+    #[error(transparent)]
+    Database(#[from] DieselError),
+}
+
+/// A task's checklist, as returned by every change to it.
+#[derive(Debug, Clone)]
+pub struct Checklist {
+    pub task_key: String,
+    pub items: Vec<ChecklistItemPG>,
+}
+
+impl Checklist {
+    /// The fraction of items that are done, if there are any.
+    pub fn progress(&self) -> Option<f64> {
+        progress(&self.items)
+    }
+}
+
+fn progress(items: &[ChecklistItemPG]) -> Option<f64> {
+    if items.is_empty() {
+        return None;
+    }
+    let done = items.iter().filter(|item| item.is_done).count();
+    Some(done as f64 / items.len() as f64)
+}
+
+fn validate_title(title: &str) -> Result<&str, ChecklistError> {
+    let title = title.trim();
+    if title.is_empty() || title.chars().count() > MAX_TITLE_LEN {
+        return Err(ChecklistError::Invalid(format!(
+            "item titles must be between 1 and {} characters",
+            MAX_TITLE_LEN
+        )));
+    }
+    Ok(title)
+}
+
+/// Checks that the task `task_id` of `origin` exists, and gives its key.
+fn find(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    task_id: usize,
+) -> Result<String, ChecklistError> {
+    if !ORIGINS.contains(&origin) {
+        return Err(ChecklistError::Invalid(format!(
+            "origin must be one of {}",
+            ORIGINS.join(", ")
+        )));
+    }
+    if find_task(db_conn, email, origin, task_id)?.is_none() {
+        return Err(ChecklistError::NotFound("no such task"));
+    }
+    Ok(task_key(origin, task_id))
+}
+
+/// Gets the checklist of the task `key` of `email`, in order.
+pub fn items(db_conn: &mut PgConnection, email: &str, key: &str) -> QueryResult<Checklist> {
+    use crate::schema::checklist_items::dsl::*;
+
+    let items = checklist_items
+        .filter(user_email.eq(email))
+        .filter(task_key.eq(key))
+        .order((position, id))
+        .load(db_conn)?;
+    Ok(Checklist {
+        task_key: key.to_string(),
+        items,
+    })
+}
+
+/// Adds an item titled `item_title` to the checklist of the task `task_id` of `origin`, at `at`, or
+/// at the end if that is past it (or not given).
+pub fn add(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    task_id: usize,
+    item_title: &str,
+    at: Option<usize>,
+) -> Result<Checklist, ChecklistError> {
+    use crate::schema::checklist_items::dsl::*;
+
+    let new_title = validate_title(item_title)?;
+    let key = find(db_conn, email, origin, task_id)?;
+
+    db_conn.transaction(|db_conn| {
+        let len = items(db_conn, email, &key)?.items.len();
+        let at = at.unwrap_or(len).min(len) as i32;
+        diesel::update(
+            checklist_items
+                .filter(user_email.eq(email))
+                .filter(task_key.eq(&key))
+                .filter(position.ge(at)),
+        )
+        .set(position.eq(position + 1))
+        .execute(db_conn)?;
+        diesel::insert_into(checklist_items)
+            .values(&NewChecklistItemPG {
+                user_email: email,
+                task_key: &key,
+                position: at,
+                title: new_title,
+            })
+            .execute(db_conn)?;
+        Ok(items(db_conn, email, &key)?)
+    })
+}
+
+/// Puts the checklist of the task `task_id` of `origin` in the order of `order`, which must name
+/// every item on it exactly once.
+pub fn reorder(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    task_id: usize,
+    order: &[i32],
+) -> Result<Checklist, ChecklistError> {
+    use crate::schema::checklist_items::dsl::*;
+
+    let key = find(db_conn, email, origin, task_id)?;
+
+    db_conn.transaction(|db_conn| {
+        let current = items(db_conn, email, &key)?;
+        let known = current
+            .items
+            .iter()
+            .map(|item| item.id)
+            .collect::<HashSet<_>>();
+        let given = order.iter().copied().collect::<HashSet<_>>();
+        if given.len() != order.len() || given != known {
+            return Err(ChecklistError::Invalid(String::from(
+                "the order must name every item of the checklist exactly once",
+            )));
+        }
+
+        for (at, item) in order.iter().enumerate() {
+            diesel::update(checklist_items.find(item))
+                .set(position.eq(at as i32))
+                .execute(db_conn)?;
+        }
+        Ok(items(db_conn, email, &key)?)
+    })
+}
+
+/// Ticks off (or un-ticks) the item `item` of `email`.
+pub fn set_done(
+    db_conn: &mut PgConnection,
+    email: &str,
+    item: i32,
+    done: bool,
+) -> Result<Checklist, ChecklistError> {
+    use crate::schema::checklist_items::dsl::*;
+
+    let updated = diesel::update(
+        checklist_items
+            .filter(id.eq(item))
+            .filter(user_email.eq(email)),
+    )
+    .set((is_done.eq(done), completed_at.eq(done.then(Utc::now))))
+    .get_result::<ChecklistItemPG>(db_conn)
+    .optional()?
+    .ok_or(ChecklistError::NotFound("no such checklist item"))?;
+    Ok(items(db_conn, email, &updated.task_key)?)
+}
+
+/// Deletes the item `item` of `email`, closing up the gap it leaves.
+pub fn delete(
+    db_conn: &mut PgConnection,
+    email: &str,
+    item: i32,
+) -> Result<Checklist, ChecklistError> {
+    use crate::schema::checklist_items::dsl::*;
+
+    db_conn.transaction(|db_conn| {
+        let deleted = diesel::delete(
+            checklist_items
+                .filter(id.eq(item))
+                .filter(user_email.eq(email)),
+        )
+        .get_result::<ChecklistItemPG>(db_conn)
+        .optional()?
+        .ok_or(ChecklistError::NotFound("no such checklist item"))?;
+        diesel::update(
+            checklist_items
+                .filter(user_email.eq(email))
+                .filter(task_key.eq(&deleted.task_key))
+                .filter(position.gt(deleted.position)),
+        )
+        .set(position.eq(position - 1))
+        .execute(db_conn)?;
+        Ok(items(db_conn, email, &deleted.task_key)?)
+    })
+}
+
+/// Drops the checklists of the tasks `keys` of `email`, as they are gone for good.
+pub fn forget(db_conn: &mut PgConnection, email: &str, keys: &[String]) -> QueryResult<()> {
+    use crate::schema::checklist_items::dsl::*;
+
+    diesel::delete(
+        checklist_items
+            .filter(user_email.eq(email))
+            .filter(task_key.eq_any(keys)),
+    )
+    .execute(db_conn)?;
+    Ok(())
+}
+
+/// Puts the checklists of `email` on their `tasks` of `origin`, along with their progress.
+pub fn apply(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    tasks: &mut [AVTask],
+) -> QueryResult<()> {
+    use crate::schema::checklist_items;
+
+    let all = checklist_items::table
+        .filter(checklist_items::user_email.eq(email))
+        .filter(checklist_items::task_key.like(format!("{}:%", origin)))
+        .order((checklist_items::position, checklist_items::id))
+        .load::<ChecklistItemPG>(db_conn)?;
+    let mut by_task = HashMap::<String, Vec<ChecklistItemPG>>::new();
+    for item in all {
+        by_task.entry(item.task_key.clone()).or_default().push(item);
+    }
+
+    for task in tasks {
+        let items = by_task
+            .remove(&task_key(origin, task.id))
+            .unwrap_or_default();
+        task.progress = progress(&items);
+        task.checklist = items.into_iter().map(ChecklistItem::from).collect();
+    }
+    Ok(())
+}
+
+impl From<ChecklistItemPG> for ChecklistItem {
+    fn from(item: ChecklistItemPG) -> Self {
+        ChecklistItem {
+            id: item.id,
+            title: item.title,
+            is_done: item.is_done,
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! Tags the user makes up (e.g. "revision" or "coursework"), which they can put on any of their
+//! tasks, local or Firefly.
+//!
+//! Which task carries which tag is kept apart from the tasks, by [task key](task_key), as the
+//! Firefly tasks are replaced on every sync. The tags are attached to tasks as [`Tag::Custom`]
+//! whenever they are read.
+use super::task::{task_key, AVTask, Tag, ORIGINS};
+use super::user::utils::find_task;
+use crate::models::{CustomTagPG, NewCustomTagPG, TaskTagPG};
+
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use std::collections::HashMap;
+
+/// Colour of tags created without one.
+const DEFAULT_COLOUR: &str = "#808080";
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TagError {
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("a tag named {0} already exists")]
+    Exists(String),
+
+    #[error("{0}")]
+    NotFound(&'static str),
+
+    #[error(transparent)]
+    Database(#[from] DieselError),
+}
+
+fn validate_name(name: &str) -> Result<&str, TagError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(TagError::Invalid(format!(
+            "tag names must be between 1 and {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(name)
+}
+
+/// Colours are `#rrggbb`; returned lowercased.
+fn validate_colour(colour: &str) -> Result<String, TagError> {
+    let valid = colour.len() == 7
+        && colour.starts_with('#')
+        && colour[1..].chars().all(|c| c.is_ascii_hexdigit());
+    if !valid {
+        return Err(TagError::Invalid(String::from(
+            "colours must be of the form #rrggbb",
+        )));
+    }
+    Ok(colour.to_ascii_lowercase())
+}
+
+/// Turns the violation of the unique `(user_email, name)` constraint into [`TagError::Exists`].
+fn name_taken(name: &str) -> impl FnOnce(DieselError) -> TagError + '_ {
+    move |e| match e {
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            TagError::Exists(name.to_string())
+        }
+        e => TagError::Database(e),
+    }
+}
+
+/// Gets the tags of `email`, by name.
+pub fn list(db_conn: &mut PgConnection, email: &str) -> QueryResult<Vec<CustomTagPG>> {
+    use crate::schema::custom_tags::dsl::*;
+
+    custom_tags
+        .filter(user_email.eq(email))
+        .order(name)
+        .load(db_conn)
+}
+
+pub fn create(
+    db_conn: &mut PgConnection,
+    email: &str,
+    new_name: &str,
+    new_colour: Option<&str>,
+) -> Result<CustomTagPG, TagError> {
+    use crate::schema::custom_tags;
+
+    let new_name = validate_name(new_name)?;
+    let new_colour = validate_colour(new_colour.unwrap_or(DEFAULT_COLOUR))?;
+    diesel::insert_into(custom_tags::table)
+        .values(&NewCustomTagPG {
+            user_email: email,
+            name: new_name,
+            colour: &new_colour,
+        })
+        .get_result(db_conn)
+        .map_err(name_taken(new_name))
+}
+
+/// Renames and/or recolours the tag `tag` of `email`.
+pub fn update(
+    db_conn: &mut PgConnection,
+    email: &str,
+    tag: i32,
+    new_name: Option<&str>,
+    new_colour: Option<&str>,
+) -> Result<CustomTagPG, TagError> {
+    use crate::schema::custom_tags::dsl::*;
+
+    let new_name = new_name.map(validate_name).transpose()?;
+    let new_colour = new_colour.map(validate_colour).transpose()?;
+    let target = custom_tags.filter(id.eq(tag)).filter(user_email.eq(email));
+
+    let updated = match (new_name, new_colour) {
+        (Some(new_name), Some(new_colour)) => diesel::update(target)
+            .set((name.eq(new_name), colour.eq(new_colour)))
+            .get_result(db_conn),
+        (Some(new_name), None) => diesel::update(target)
+            .set(name.eq(new_name))
+            .get_result(db_conn),
+        (None, Some(new_colour)) => diesel::update(target)
+            .set(colour.eq(new_colour))
+            .get_result(db_conn),
+        (None, None) => target.first(db_conn),
+    };
+    updated
+        .optional()
+        .map_err(name_taken(new_name.unwrap_or_default()))?
+        .ok_or(TagError::NotFound("no such tag"))
+}
+
+/// Deletes the tag `tag` of `email`, taking it off every task.
+pub fn delete(db_conn: &mut PgConnection, email: &str, tag: i32) -> Result<(), TagError> {
+    use crate::schema::custom_tags::dsl::*;
+
+    let deleted = diesel::delete(custom_tags.filter(id.eq(tag)).filter(user_email.eq(email)))
+        .execute(db_conn)?;
+    if deleted == 0 {
+        return Err(TagError::NotFound("no such tag"));
+    }
+    Ok(())
+}
+
+/// Puts the tag `tag` on the task `task_id` of `origin`. Tagging a task twice does nothing.
+///
+/// Firefly tasks must have been seen in a sync to be tagged.
+pub fn attach(
+    db_conn: &mut PgConnection,
+    email: &str,
+    tag: i32,
+    origin: &str,
+    task_id: usize,
+) -> Result<(), TagError> {
+    use crate::schema::task_tags;
+
+    find_tag(db_conn, email, tag)?;
+    if !ORIGINS.contains(&origin) {
+        return Err(invalid_origin());
+    }
+    if find_task(db_conn, email, origin, task_id)?.is_none() {
+        return Err(TagError::NotFound("no such task"));
+    }
+
+    diesel::insert_into(task_tags::table)
+        .values(&TaskTagPG {
+            tag_id: tag,
+            task_key: task_key(origin, task_id),
+        })
+        .on_conflict_do_nothing()
+        .execute(db_conn)?;
+    Ok(())
+}
+
+/// Takes the tag `tag` off the task `task_id` of `origin`, if it was on it.
+pub fn detach(
+    db_conn: &mut PgConnection,
+    email: &str,
+    tag: i32,
+    origin: &str,
+    task_id: usize,
+) -> Result<(), TagError> {
+    use crate::schema::task_tags::dsl::*;
+
+    find_tag(db_conn, email, tag)?;
+    if !ORIGINS.contains(&origin) {
+        return Err(invalid_origin());
+    }
+    diesel::delete(
+        task_tags
+            .filter(tag_id.eq(tag))
+            .filter(task_key.eq(super::task::task_key(origin, task_id))),
+    )
+    .execute(db_conn)?;
+    Ok(())
+}
+
+/// Takes every tag off the tasks `keys` of `email`, as they are gone for good.
+pub fn forget(db_conn: &mut PgConnection, email: &str, keys: &[String]) -> QueryResult<()> {
+    use crate::schema::{custom_tags, task_tags};
+
+    let tags_of_user = custom_tags::table
+        .filter(custom_tags::user_email.eq(email))
+        .select(custom_tags::id);
+    diesel::delete(
+        task_tags::table
+            .filter(task_tags::tag_id.eq_any(tags_of_user))
+            .filter(task_tags::task_key.eq_any(keys)),
+    )
+    .execute(db_conn)?;
+    Ok(())
+}
+
+/// Puts the tags named in `tagged` on the tasks of `origin` they are paired with, making any tag
+/// `email` doesn't have yet. Names that aren't valid tag names are skipped.
+///
+/// The tasks are taken to exist, e.g. as they were just imported.
+pub fn attach_by_name(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    tagged: &[(usize, Vec<String>)],
+) -> QueryResult<()> {
+    use crate::schema::{custom_tags, task_tags};
+
+    let mut names = tagged
+        .iter()
+        .flat_map(|(_, names)| names)
+        .filter_map(|name| validate_name(name).ok())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    db_conn.transaction(|db_conn| {
+        let new_tags = names
+            .iter()
+            .map(|new_name| NewCustomTagPG {
+                user_email: email,
+                name: new_name,
+                colour: DEFAULT_COLOUR,
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(custom_tags::table)
+            .values(&new_tags)
+            .on_conflict_do_nothing()
+            .execute(db_conn)?;
+        let ids = custom_tags::table
+            .filter(custom_tags::user_email.eq(email))
+            .filter(custom_tags::name.eq_any(&names))
+            .select((custom_tags::name, custom_tags::id))
+            .load::<(String, i32)>(db_conn)?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        let rows = tagged
+            .iter()
+            .flat_map(|(task_id, task_names)| {
+                task_names
+                    .iter()
+                    .filter_map(|name| ids.get(validate_name(name).ok()?))
+                    .map(|tag| TaskTagPG {
+                        tag_id: *tag,
+                        task_key: task_key(origin, *task_id),
+                    })
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(task_tags::table)
+            .values(&rows)
+            .on_conflict_do_nothing()
+            .execute(db_conn)?;
+        Ok(())
+    })
+}
+
+fn find_tag(db_conn: &mut PgConnection, email: &str, tag: i32) -> Result<CustomTagPG, TagError> {
+    use crate::schema::custom_tags::dsl::*;
+
+    custom_tags
+        .filter(id.eq(tag))
+        .filter(user_email.eq(email))
+        .first(db_conn)
+        .optional()?
+        .ok_or(TagError::NotFound("no such tag"))
+}
+
+fn invalid_origin() -> TagError {
+    TagError::Invalid(format!("origin must be one of {}", ORIGINS.join(", ")))
+}
+
+/// Puts the tags of `email` on their `tasks` of `origin`, replacing any custom tags on them.
+pub fn apply(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    tasks: &mut [AVTask],
+) -> QueryResult<()> {
+    use crate::schema::{custom_tags, task_tags};
+
+    let tagged = task_tags::table
+        .inner_join(custom_tags::table)
+        .filter(custom_tags::user_email.eq(email))
+        .filter(task_tags::task_key.like(format!("{}:%", origin)))
+        .order(custom_tags::name)
+        .select((task_tags::task_key, custom_tags::all_columns))
+        .load::<(String, CustomTagPG)>(db_conn)?;
+    let mut by_task = HashMap::<String, Vec<CustomTagPG>>::new();
+    for (key, tag) in tagged {
+        by_task.entry(key).or_default().push(tag);
+    }
+
+    for task in tasks {
+        task.tags.retain(|tag| !matches!(tag, Tag::Custom { .. }));
+        let Some(tags) = by_task.get(&task_key(origin, task.id)) else {
+            continue;
+        };
+        task.tags.extend(tags.iter().map(|tag| Tag::Custom {
+            id: tag.id,
+            name: tag.name.clone(),
+            colour: tag.colour.clone(),
+        }));
+    }
+    Ok(())
+}
+
+/// Whether `task` carries every custom tag in `names`.
+pub fn has_all(task: &AVTask, names: &[String]) -> bool {
+    names.iter().all(|wanted| {
+        task.tags
+            .iter()
+            .any(|tag| matches!(tag, Tag::Custom { name, .. } if name == wanted))
+    })
+}
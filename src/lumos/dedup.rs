@@ -0,0 +1,276 @@
+//! Tasks that turn up more than once, e.g. a homework copied into the local tasks that Firefly
+//! also has, or the same task arriving from Firefly and Teams.
+//!
+//! [`candidates`] suggests likely duplicates from how alike their titles are, how close their due
+//! dates are, and whether they were set by the same teacher or for the same class. Nothing is
+//! merged until the user confirms it with [`merge`]; a merge only records the [task keys](task_key)
+//! of its tasks, so each keeps living at its origin and [`unmerge`] gives them back as they were.
+//!
+//! Merged tasks are listed as the first of them, which [collapses](collapse) the rest into it.
+use super::task::{parse_task_key, task_key, AVTask, ORIGINS};
+use super::user::utils::{cached_tasks, find_task};
+use crate::models::{NewTaskMergePG, TaskMergePG};
+
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use std::collections::{HashMap, HashSet};
+
+/// How alike two tasks must be, from 0 to 1, to be suggested as duplicates.
+pub const THRESHOLD: f64 = 0.7;
+/// How far apart, in hours, the due dates of duplicates may be.
+pub const DUE_WINDOW_HOURS: i64 = 24;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MergeError {
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("{0}")]
+    NotFound(&'static str),
+
+    #[error(transparent)]
+    Database(#[from] DieselError),
+}
+
+/// Tasks that are likely to be the same, and how alike they are.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub task_keys: [String; 2],
+    pub score: f64,
+}
+
+/// The title lowercased, with anything but letters and digits dropped.
+fn normalise(title: &str) -> String {
+    title
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The Dice coefficient of the character bigrams of `a` and `b`.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (normalise(a), normalise(b));
+    if a == b {
+        return 1.0;
+    }
+    let bigrams = |s: &str| {
+        let chars = s.chars().collect::<Vec<_>>();
+        chars
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect::<Vec<_>>()
+    };
+    let (a, mut b) = (bigrams(&a), bigrams(&b));
+    let total = a.len() + b.len();
+    if total == 0 {
+        return 0.0;
+    }
+    let mut shared = 0;
+    for bigram in a {
+        if let Some(at) = b.iter().position(|other| *other == bigram) {
+            b.swap_remove(at);
+            shared += 1;
+        }
+    }
+    2.0 * shared as f64 / total as f64
+}
+
+/// How alike `a` and `b` are, if their due dates are close enough for them to be duplicates.
+pub fn score(a: &AVTask, b: &AVTask) -> Option<f64> {
+    let (due_a, due_b) = (a.due()?, b.due()?);
+    if (due_a - due_b).num_hours().abs() > DUE_WINDOW_HOURS {
+        return None;
+    }
+    let same_setter =
+        !a.setter_name.is_empty() && a.setter_name.eq_ignore_ascii_case(&b.setter_name);
+    let same_class = a.classes.iter().any(|class| b.classes.contains(class));
+    let context = if same_setter || same_class { 1.0 } else { 0.0 };
+    Some(0.75 * similarity(&a.title, &b.title) + 0.25 * context)
+}
+
+/// Suggests pairs of tasks of `email` that are likely duplicates, most alike first, leaving out
+/// those already merged together.
+pub fn candidates(db_conn: &mut PgConnection, email: &str) -> QueryResult<Vec<Candidate>> {
+    let (local, firefly) = cached_tasks(db_conn, email)?;
+    let merged = merges(db_conn, email)?
+        .into_iter()
+        .flat_map(|merge| merge.task_keys)
+        .collect::<HashSet<_>>();
+
+    let tasks = ORIGINS
+        .into_iter()
+        .zip([local, firefly])
+        .flat_map(|(origin, tasks)| {
+            tasks
+                .into_iter()
+                .filter(|task| !task.is_done)
+                .map(move |task| (task_key(origin, task.id), task))
+        })
+        .filter(|(key, _)| !merged.contains(key))
+        .collect::<Vec<_>>();
+
+    let mut found = vec![];
+    for (i, (key_a, a)) in tasks.iter().enumerate() {
+        for (key_b, b) in &tasks[i + 1..] {
+            match score(a, b) {
+                Some(score) if score >= THRESHOLD => found.push(Candidate {
+                    task_keys: [key_a.clone(), key_b.clone()],
+                    score,
+                }),
+                _ => {}
+            }
+        }
+    }
+    found.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(found)
+}
+
+/// Gets the merges of `email`, oldest first.
+pub fn merges(db_conn: &mut PgConnection, email: &str) -> QueryResult<Vec<TaskMergePG>> {
+    use crate::schema::task_merges::dsl::*;
+
+    task_merges
+        .filter(user_email.eq(email))
+        .order(id)
+        .load(db_conn)
+}
+
+/// Merges the tasks `keys` of `email` into the first of them.
+///
+/// Every task must exist and not be part of another merge already.
+pub fn merge(
+    db_conn: &mut PgConnection,
+    email: &str,
+    keys: &[String],
+) -> Result<TaskMergePG, MergeError> {
+    use crate::schema::task_merges::dsl::*;
+
+    let unique = keys.iter().collect::<HashSet<_>>();
+    if keys.len() < 2 || unique.len() != keys.len() {
+        return Err(MergeError::Invalid(String::from(
+            "a merge needs at least two different tasks",
+        )));
+    }
+    for key in keys {
+        let (origin, task_id) = parse_task_key(key)
+            .filter(|(origin, _)| ORIGINS.contains(origin))
+            .ok_or_else(|| {
+                MergeError::Invalid(format!(
+                    "{} is not a task key, e.g. `local:1` or `firefly:2`",
+                    key
+                ))
+            })?;
+        if find_task(db_conn, email, origin, task_id)?.is_none() {
+            return Err(MergeError::NotFound("no such task"));
+        }
+    }
+
+    db_conn.transaction(|db_conn| {
+        let taken = merges(db_conn, email)?
+            .into_iter()
+            .flat_map(|merge| merge.task_keys)
+            .any(|key| unique.contains(&key));
+        if taken {
+            return Err(MergeError::Invalid(String::from(
+                "a task is already merged with others; unmerge it first",
+            )));
+        }
+        Ok(diesel::insert_into(task_merges)
+            .values(&NewTaskMergePG {
+                user_email: email,
+                task_keys: keys,
+            })
+            .get_result(db_conn)?)
+    })
+}
+
+/// Undoes the merge `merge` of `email`, so its tasks are listed apart again.
+pub fn unmerge(db_conn: &mut PgConnection, email: &str, merge: i32) -> Result<(), MergeError> {
+    use crate::schema::task_merges::dsl::*;
+
+    let deleted = diesel::delete(
+        task_merges
+            .filter(id.eq(merge))
+            .filter(user_email.eq(email)),
+    )
+    .execute(db_conn)?;
+    if deleted == 0 {
+        return Err(MergeError::NotFound("no such merge"));
+    }
+    Ok(())
+}
+
+/// Takes the tasks `keys` of `email` out of their merges, as they are gone for good, undoing the
+/// merges that are left with a single task.
+pub fn forget(db_conn: &mut PgConnection, email: &str, keys: &[String]) -> QueryResult<()> {
+    use crate::schema::task_merges::dsl::*;
+
+    for merge in merges(db_conn, email)? {
+        let left = merge
+            .task_keys
+            .iter()
+            .filter(|key| !keys.contains(key))
+            .cloned()
+            .collect::<Vec<_>>();
+        if left.len() == merge.task_keys.len() {
+            continue;
+        }
+        let target = task_merges.find(merge.id);
+        if left.len() < 2 {
+            diesel::delete(target).execute(db_conn)?;
+        } else {
+            diesel::update(target)
+                .set(task_keys.eq(left))
+                .execute(db_conn)?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the tasks of each origin together, with the tasks of every merge collapsed into the first
+/// of them that is listed.
+///
+/// That task carries the keys of all of the merge in [`merged`](AVTask::merged), and is done if any
+/// of the listed ones is.
+pub fn collapse(merges: &[TaskMergePG], by_origin: Vec<(&str, Vec<AVTask>)>) -> Vec<AVTask> {
+    let mut tasks = by_origin
+        .into_iter()
+        .flat_map(|(origin, tasks)| {
+            tasks
+                .into_iter()
+                .map(move |task| (task_key(origin, task.id), task))
+        })
+        .collect::<Vec<_>>();
+
+    let mut primary_of = HashMap::new();
+    for merge in merges {
+        let listed = merge
+            .task_keys
+            .iter()
+            .filter(|key| tasks.iter().any(|(listed, _)| listed == *key))
+            .collect::<Vec<_>>();
+        if let Some((primary, _)) = listed.split_first() {
+            for key in &listed {
+                primary_of.insert((*key).clone(), (*primary).clone());
+            }
+        }
+    }
+
+    let mut done = HashSet::new();
+    for (key, task) in &tasks {
+        if task.is_done {
+            if let Some(primary) = primary_of.get(key) {
+                done.insert(primary.clone());
+            }
+        }
+    }
+    tasks.retain(|(key, _)| primary_of.get(key).is_none_or(|primary| primary == key));
+    for (key, task) in &mut tasks {
+        if let Some(merge) = merges.iter().find(|merge| merge.task_keys.contains(key)) {
+            task.merged = merge.task_keys.clone();
+            task.is_done |= done.contains(key);
+        }
+    }
+    tasks.into_iter().map(|(_, task)| task).collect()
+}
@@ -0,0 +1,166 @@
+//! What Firefly has on a task beyond what it lists: the description, attachments, responses and
+//! history.
+//!
+//! Firefly writes descriptions and comments as HTML, which lantern [sanitises](to_markdown) and
+//! hands out as markdown, so clients never render markup from Firefly. Fetching the details takes
+//! a request per task, so they are cached with the task for `LANTERN_TASK_DETAIL_TTL` seconds (an
+//! hour by default), and the cache is served when Firefly can't be reached.
+use super::task::{
+    AVTaskDetail, Attachment, RawFFAttachment, RawFFEvent, RawFFTaskDetail, TaskEvent,
+};
+use crate::models::{NewTaskDetailPG, TaskDetailPG};
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use std::collections::HashSet;
+
+/// How long details are cached, unless `LANTERN_TASK_DETAIL_TTL` says otherwise.
+const DEFAULT_TTL_SECS: i64 = 60 * 60;
+
+/// What links in descriptions and comments may point at; links to anything else are dropped.
+const URL_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+/// The kinds of event that respond to the task, rather than change its state.
+const RESPONSE_KINDS: [&str; 4] = [
+    "comment",
+    "add-file",
+    "mark-and-grade",
+    "request-resubmission",
+];
+
+/// How long details are cached before they are fetched again.
+pub fn ttl() -> Duration {
+    let secs = std::env::var("LANTERN_TASK_DETAIL_TTL")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_TTL_SECS);
+    Duration::seconds(secs)
+}
+
+/// Converts the HTML `html` to markdown, dropping scripts, styles, event handlers, links to
+/// anything but the web or mail, and whatever other markup isn't safe first.
+pub fn to_markdown(html: &str) -> String {
+    let clean = ammonia::Builder::default()
+        .url_schemes(HashSet::from(URL_SCHEMES))
+        .clean(html)
+        .to_string();
+    html2md::parse_html(&clean).trim().to_string()
+}
+
+fn to_attachment(raw: RawFFAttachment) -> Attachment {
+    Attachment {
+        name: raw.file_name.unwrap_or_default(),
+        url: raw.resource_url.unwrap_or_default(),
+        mime_type: raw.mime_type.unwrap_or_default(),
+        size: raw.file_size.unwrap_or(0),
+    }
+}
+
+/// Marks come as numbers or strings, with or without what they are out of.
+fn to_mark(mark: Option<serde_json::Value>, out_of: Option<serde_json::Value>) -> Option<String> {
+    let text = |value: serde_json::Value| match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(text) => Some(text),
+        value => Some(value.to_string()),
+    };
+    let mark = mark.and_then(text)?;
+    Some(match out_of.and_then(text) {
+        Some(out_of) => format!("{}/{}", mark, out_of),
+        None => mark,
+    })
+}
+
+fn to_event(raw: RawFFEvent) -> TaskEvent {
+    TaskEvent {
+        kind: raw.event_type.unwrap_or_default(),
+        author: raw
+            .author
+            .and_then(|author| author.name)
+            .unwrap_or_default(),
+        at: raw.sent.unwrap_or_default(),
+        message: raw.message.as_deref().map(to_markdown).unwrap_or_default(),
+        mark: to_mark(raw.mark, raw.out_of),
+        files: raw.files.into_iter().flatten().map(to_attachment).collect(),
+    }
+}
+
+/// Converts the details as Firefly gives them, splitting its events into responses and history.
+pub fn from_raw(raw: RawFFTaskDetail) -> AVTaskDetail {
+    let (responses, history) = raw
+        .responses
+        .into_iter()
+        .flatten()
+        .map(to_event)
+        .partition(|event: &TaskEvent| RESPONSE_KINDS.contains(&event.kind.as_str()));
+    AVTaskDetail {
+        description: raw
+            .description
+            .as_deref()
+            .map(to_markdown)
+            .unwrap_or_default(),
+        description_contains_questions: raw.description_contains_questions.unwrap_or(false),
+        attachments: raw
+            .file_attachments
+            .into_iter()
+            .flatten()
+            .map(to_attachment)
+            .collect(),
+        responses,
+        history,
+    }
+}
+
+/// Gets the cached details of the task `key` of `email`, however old they are.
+pub fn cached(
+    db_conn: &mut PgConnection,
+    email: &str,
+    key: &str,
+) -> QueryResult<Option<TaskDetailPG>> {
+    use crate::schema::task_details::dsl::*;
+
+    task_details
+        .filter(user_email.eq(email))
+        .filter(task_key.eq(key))
+        .first(db_conn)
+        .optional()
+}
+
+/// Whether `row` was fetched less than the [`ttl`] before `now`.
+pub fn is_fresh(row: &TaskDetailPG, now: DateTime<Utc>) -> bool {
+    now - row.fetched_at < ttl()
+}
+
+/// Caches `new_detail` as the details of the task `key` of `email`, fetched just now.
+pub fn store(
+    db_conn: &mut PgConnection,
+    email: &str,
+    key: &str,
+    new_detail: &AVTaskDetail,
+) -> QueryResult<TaskDetailPG> {
+    use crate::schema::task_details::dsl::*;
+
+    let value = serde_json::to_value(new_detail).unwrap();
+    diesel::insert_into(task_details)
+        .values(&NewTaskDetailPG {
+            user_email: email,
+            task_key: key,
+            detail: value.clone(),
+        })
+        .on_conflict((user_email, task_key))
+        .do_update()
+        .set((detail.eq(value), fetched_at.eq(Utc::now())))
+        .get_result(db_conn)
+}
+
+/// Drops the cached details of the task `key` of `email`, as they changed at the source.
+pub fn forget(db_conn: &mut PgConnection, email: &str, key: &str) -> QueryResult<()> {
+    use crate::schema::task_details::dsl::*;
+
+    diesel::delete(
+        task_details
+            .filter(user_email.eq(email))
+            .filter(task_key.eq(key)),
+    )
+    .execute(db_conn)?;
+    Ok(())
+}
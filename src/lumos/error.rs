@@ -1,3 +1,4 @@
+use super::redact::{redact, redact_error};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -5,9 +6,17 @@ pub enum FireflyError {
     #[error("firefly secret is invalid")]
     InvalidSecret,
 
-    #[error("http request failed with {0}")]
-    HTTP(#[from] reqwest::Error),
+    #[error("http request failed with {}", redact(&.0.to_string()))]
+    HTTP(reqwest::Error),
 
-    #[error("failed with: {0}")]
+    #[error("failed with: {}", redact(.0))]
     Misc(String),
 }
+
+impl From<reqwest::Error> for FireflyError {
+    /// Strips the secrets from the url of the error, so that neither the `Display` nor the
+    /// `Debug` output of the error leak them.
+    fn from(e: reqwest::Error) -> Self {
+        FireflyError::HTTP(redact_error(e))
+    }
+}
@@ -0,0 +1,106 @@
+//! Changes to tasks, worked out by diffing a list of tasks before and after it was written.
+//!
+//! Local tasks are diffed whenever they are [updated](super::user::utils::update_local_tasks) and
+//! Firefly tasks after every sync that sees all of them, so events come out of every path that
+//! changes a task.
+use super::task::{task_key, AVTask};
+
+use serde::Serialize;
+use std::collections::HashMap;
+use strum::EnumString;
+use strum_macros::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, Display, Serialize)]
+pub enum EventKind {
+    /// A task that wasn't there before.
+    #[strum(serialize = "task.created")]
+    #[serde(rename = "task.created")]
+    Created,
+
+    #[strum(serialize = "task.due_date_changed")]
+    #[serde(rename = "task.due_date_changed")]
+    DueDateChanged,
+
+    /// The teacher has marked the task.
+    #[strum(serialize = "task.marked")]
+    #[serde(rename = "task.marked")]
+    Marked,
+
+    #[strum(serialize = "task.completed")]
+    #[serde(rename = "task.completed")]
+    Completed,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 4] = [
+        EventKind::Created,
+        EventKind::DueDateChanged,
+        EventKind::Marked,
+        EventKind::Completed,
+    ];
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    #[serde(rename = "event")]
+    pub kind: EventKind,
+    /// `local:<id>` or `firefly:<id>`
+    pub task_key: String,
+    pub task: AVTask,
+    /// The due date before it moved, for [`EventKind::DueDateChanged`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_due_date: Option<String>,
+}
+
+/// The events that turn `before` into `after`, both lists of tasks from `origin` (`local` or
+/// `firefly`). Tasks are matched up by [task key](task_key); tasks that disappear, and Firefly
+/// tasks listed without an id (which are all given 0), don't produce an event.
+///
+/// Both lists must be complete: a task missing from `before` is taken to be new.
+pub fn diff(origin: &str, before: &[AVTask], after: &[AVTask]) -> Vec<Event> {
+    let before = before
+        .iter()
+        .filter_map(|task| Some((identity(origin, task)?, task)))
+        .collect::<HashMap<_, _>>();
+    let mut events = vec![];
+
+    for task in after {
+        let Some(key) = identity(origin, task) else {
+            continue;
+        };
+        let event = |kind, previous_due_date| Event {
+            kind,
+            task_key: key.clone(),
+            task: task.clone(),
+            previous_due_date,
+        };
+        let Some(old) = before.get(&key) else {
+            events.push(event(EventKind::Created, None));
+            continue;
+        };
+
+        let moved = match (old.due(), task.due()) {
+            (Some(old), Some(new)) => old != new,
+            _ => old.due_date != task.due_date,
+        };
+        if moved {
+            events.push(event(EventKind::DueDateChanged, Some(old.due_date.clone())));
+        }
+        if task.is_marked && !old.is_marked {
+            events.push(event(EventKind::Marked, None));
+        }
+        if task.is_done && !old.is_done {
+            events.push(event(EventKind::Completed, None));
+        }
+    }
+    events
+}
+
+/// The [task key](task_key) that tells `task` apart from the other tasks of `origin` across
+/// writes, if it has one. Firefly tasks listed without an id are all given 0, so they have none.
+fn identity(origin: &str, task: &AVTask) -> Option<String> {
+    if origin == "firefly" && task.id == 0 {
+        return None;
+    }
+    Some(task_key(origin, task.id))
+}
@@ -1,55 +1,142 @@
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
 use std::string::ToString;
 use strum::EnumString;
 use strum_macros::Display;
 
 use super::error::FireflyError;
-use super::task::Response;
+use super::task::{AVTask, Response};
+use super::user::utils::send;
 
-#[derive(Debug, PartialEq, EnumString, Display)]
+#[derive(Debug, PartialEq, Clone, Copy, EnumString, Display)]
 pub enum CompletionStatus {
     Todo,
     DoneOrArchived,
     AllIncludingArchived,
 }
 
-#[derive(Debug, PartialEq, EnumString, Display)]
+#[derive(Debug, PartialEq, Clone, Copy, EnumString, Display)]
 pub enum ReadStatus {
     All,
     OnlyRead,
     OnlyUnread,
 }
 
-#[derive(Debug, PartialEq, EnumString, Display)]
+#[derive(Debug, PartialEq, Clone, Copy, EnumString, Display)]
 pub enum SortOrder {
     Ascending,
     Descending,
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, EnumString, Display)]
+/// Where Firefly got a task from, as it aggregates tasks from other platforms alongside its own.
+///
+/// Parsing never fails, so that a source lantern doesn't know of yet can't break a sync.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, EnumString)]
+#[serde(from = "String", into = "String")]
 pub enum Source {
-    #[serde(rename = "FF")]
     #[strum(serialize = "FF")]
     Ff,
 
-    #[serde(rename = "GC")]
+    /// Google Classroom
     #[strum(serialize = "GC")]
     Gc,
+
+    /// Microsoft Teams
+    #[strum(serialize = "MS")]
+    Ms,
+
+    /// Any other source, by the name Firefly gives it.
+    #[strum(default)]
+    Other(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Ff => f.write_str("FF"),
+            Source::Gc => f.write_str("GC"),
+            Source::Ms => f.write_str("MS"),
+            Source::Other(source) => f.write_str(source),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, EnumString, Display)]
+impl From<String> for Source {
+    fn from(source: String) -> Self {
+        Source::from_str(&source).unwrap_or(Source::Other(source))
+    }
+}
+
+impl From<Source> for String {
+    fn from(source: Source) -> Self {
+        source.to_string()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, EnumString, Display)]
 pub enum SortBy {
     DueDate,
     SetDate,
+    /// By the score of [`priority`](super::priority); done by lantern, not Firefly.
+    Priority,
+}
+
+impl SortBy {
+    /// The column Firefly is asked to sort by; tasks are fetched by due date when they are to be
+    /// sorted by priority, as urgency mostly follows the due date anyway.
+    fn firefly_column(&self) -> &'static str {
+        match self {
+            SortBy::DueDate | SortBy::Priority => "DueDate",
+            SortBy::SetDate => "SetDate",
+        }
+    }
+}
+
+/// Sorts `tasks` by the date `by` names, with tasks that lack one (or have one lantern can't
+/// parse) last either way; [`SortBy::Priority`] falls back to the due date, as it needs the
+/// weights of the user (see [`priority::sort`](super::priority::sort)).
+///
+/// The sort is stable, so tasks that tie keep the order they were listed in and every listing of
+/// the same tasks comes out the same, which paging through them relies on.
+pub fn sort(tasks: &mut [AVTask], by: SortBy, order: SortOrder) {
+    let date = |task: &AVTask| match by {
+        SortBy::DueDate | SortBy::Priority => task.due(),
+        SortBy::SetDate => task.set(),
+    };
+    tasks.sort_by(|a, b| match (date(a), date(b)) {
+        (Some(a), Some(b)) => match order {
+            SortOrder::Ascending => a.cmp(&b),
+            SortOrder::Descending => b.cmp(&a),
+        },
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    });
+}
+
+/// Which tasks to fetch, in terms every [source](super::source) understands; each source turns it
+/// into what its own service asks for, e.g. Firefly into an [`FFTaskFilter`].
+///
+/// Tasks are [sorted](sort) once they are fetched, so the order a source lists them in is its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskFilter {
+    pub status: CompletionStatus,
+    pub read: ReadStatus,
+    /// Only tasks from one of these; every task (even without a source) if empty.
+    pub sources: Vec<Source>,
 }
 
 #[allow(dead_code)]
 pub struct FFTaskFilter {
     pub status: CompletionStatus,
     pub read: ReadStatus,
-    pub sorting: (SortBy, SortOrder), // String = DueDate or SetDate; bool is True or False
-    pub source: Option<Source>,       // Google Classroom or Firefly; sometimes not present -_-
+    /// What the tasks are sorted by, and in which order; [`SortBy::Priority`] is left to lantern.
+    pub sorting: (SortBy, SortOrder),
+    /// Only tasks from one of these; every task (even without a source) if empty.
+    pub sources: Vec<Source>,
 }
 
 #[derive(serde::Serialize, Deserialize, Debug)]
@@ -71,11 +158,36 @@ pub struct JSONFFTaskFilter {
     sortingCriteria: Vec<Sorting>,
 }
 
+impl From<&TaskFilter> for FFTaskFilter {
+    /// Asks for the tasks by due date, which is how lantern most often sorts them anyway.
+    fn from(filter: &TaskFilter) -> Self {
+        FFTaskFilter {
+            status: filter.status,
+            read: filter.read,
+            sorting: (SortBy::DueDate, SortOrder::Ascending),
+            sources: filter.sources.clone(),
+        }
+    }
+}
+
 impl FFTaskFilter {
+    /// Whether every task passes the filter, so that a sync with it sees all of them and may stand
+    /// in for what Firefly has; the order doesn't matter.
+    pub fn is_complete(&self) -> bool {
+        self.status == CompletionStatus::AllIncludingArchived
+            && self.read == ReadStatus::All
+            && self.sources.is_empty()
+    }
+
     /// Converts the more ergonomic [`FFTaskFilter`] to a `Vec<JSONTaskFilter>` a vector of filters
     ///
     /// The Firefly API allows you to get a maximum of 100 tasks per request; a vector of filters
     /// must be created when the number of tasks being requested exceeds 100.
+    ///
+    /// Sources are filtered on lantern's side, not by Firefly: its task listing only filters by
+    /// owner and by archive, completion, read and marking status, and has no field for the source
+    /// of a task. So every page is fetched, and [`get_ff_tasks`](super::user::User::get_ff_tasks)
+    /// narrows the tasks to [`sources`](FFTaskFilter::sources) afterwards.
     pub async fn to_json(
         &self,
         client: reqwest::Client,
@@ -90,20 +202,17 @@ impl FFTaskFilter {
             readStatus: self.read.to_string(),
             markingStatus: String::from("All"),
             sortingCriteria: vec![Sorting {
-                column: self.sorting.0.to_string(),
+                column: self.sorting.0.firefly_column().to_string(),
                 order: self.sorting.1.to_string(),
             }],
         };
-        let res = client
-            .post(url.clone())
-            .json(&pre_filter)
-            .send()
+        let res = send("taskListing", client.post(url.clone()).json(&pre_filter))
             .await?
             .text()
             .await?;
 
         if res == "Invalid token" {
-            return Err(FireflyError::InvalidSecret.into());
+            return Err(FireflyError::InvalidSecret);
         }
 
         let ser_res = serde_json::from_str::<Response>(&res);
@@ -135,7 +244,7 @@ impl FFTaskFilter {
                         readStatus: self.read.to_string(),
                         markingStatus: String::from("All"),
                         sortingCriteria: vec![Sorting {
-                            column: self.sorting.0.to_string(),
+                            column: self.sorting.0.firefly_column().to_string(),
                             order: self.sorting.1.to_string(),
                         }],
                     };
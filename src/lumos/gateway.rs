@@ -0,0 +1,232 @@
+//! REST/JSON gateway to the operations of the `Lantern` service.
+//!
+//! Handlers call into [`TaskService`](super::rpc::TaskService) directly, so behaviour is shared
+//! with the gRPC service rather than duplicated; only the encoding differs.
+use super::http::AppState;
+use super::rpc::{etag, light::Filter};
+use super::task::{AVTask, ChecklistItem, Occurrence, Tag};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tonic::Code;
+use tracing::Instrument;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "lantern",
+        description = "REST/JSON gateway to the Lantern gRPC service"
+    ),
+    paths(get_tasks, add_tasks),
+    components(schemas(AVTask, ChecklistItem, Occurrence, Tag, StatusCodeBody, ErrorBody))
+)]
+pub struct ApiDoc;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/v1/tasks", get(get_tasks).post(add_tasks))
+        .route("/v1/openapi.json", get(openapi))
+}
+
+/// Query parameters of `GET /v1/tasks`; mirrors the `Filter` message.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TasksQuery {
+    /// `Todo`, `DoneOrArchived` or `AllIncludingArchived`
+    #[serde(default = "default_status")]
+    status: String,
+    /// `All`, `OnlyRead` or `OnlyUnread`
+    #[serde(default = "default_read")]
+    read: String,
+    /// `DueDate`, `SetDate` or `Priority`
+    #[serde(default = "default_sort_by")]
+    sort_by: String,
+    /// `Ascending` or `Descending`
+    #[serde(default = "default_sort_order")]
+    sort_order: String,
+    /// Comma separated sources, e.g. `FF,MS`; empty for every source
+    #[serde(default)]
+    source: String,
+    /// Comma separated names of custom tags that tasks must all carry
+    tags: Option<String>,
+}
+
+fn default_status() -> String {
+    String::from("Todo")
+}
+
+fn default_read() -> String {
+    String::from("All")
+}
+
+fn default_sort_by() -> String {
+    String::from("DueDate")
+}
+
+fn default_sort_order() -> String {
+    String::from("Ascending")
+}
+
+impl From<TasksQuery> for Filter {
+    fn from(query: TasksQuery) -> Self {
+        Filter {
+            status: query.status,
+            read: query.read,
+            sort_by: query.sort_by,
+            sort_order: query.sort_order,
+            source: String::new(),
+            tags: split_list(query.tags.as_deref().unwrap_or_default()),
+            sources: split_list(&query.source),
+            page_size: 0,
+            page_token: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StatusCodeBody {
+    success: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// gRPC status code the error corresponds to
+    code: String,
+    message: String,
+}
+
+/// A [`tonic::Status`] turned into the closest matching HTTP response.
+pub struct ApiError(pub tonic::Status);
+
+impl From<tonic::Status> for ApiError {
+    fn from(status: tonic::Status) -> Self {
+        ApiError(status)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let code = match self.0.code() {
+            Code::InvalidArgument | Code::OutOfRange => StatusCode::BAD_REQUEST,
+            Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Code::PermissionDenied => StatusCode::FORBIDDEN,
+            Code::NotFound => StatusCode::NOT_FOUND,
+            Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+            Code::FailedPrecondition => StatusCode::PRECONDITION_FAILED,
+            Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+            Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+            Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = ErrorBody {
+            code: format!("{:?}", self.0.code()),
+            message: self.0.message().to_string(),
+        };
+        (code, Json(body)).into_response()
+    }
+}
+
+/// Lists local tasks along with the Firefly tasks matching the filter.
+///
+/// The `ETag` is the version of the local tasks, which `If-Match` makes writes conditional on.
+#[utoipa::path(
+    get,
+    path = "/v1/tasks",
+    params(TasksQuery),
+    responses(
+        (status = 200, description = "Local and Firefly tasks", body = [AVTask],
+            headers(("ETag" = String, description = "Version of the local tasks"))),
+        (status = 400, description = "Malformed filter", body = ErrorBody),
+    )
+)]
+async fn get_tasks(
+    State(state): State<AppState>,
+    Query(query): Query<TasksQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let span = tracing::info_span!("http", route = "GET /v1/tasks");
+    let (all_tasks, version) = state
+        .tasks
+        .list_tasks(&query.into())
+        .instrument(span)
+        .await?;
+    Ok(([(header::ETAG, etag(version))], Json(all_tasks)))
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Adds tasks to the user's local tasks.
+///
+/// With `If-Match`, the tasks are only added if the local tasks are still at that version; if they
+/// changed, the client should get them again, reapply its change and retry.
+#[utoipa::path(
+    post,
+    path = "/v1/tasks",
+    request_body = [AVTask],
+    params(("If-Match" = Option<String>, Header, description = "`ETag` of the tasks last read")),
+    responses(
+        (status = 201, description = "Tasks were added", body = StatusCodeBody,
+            headers(("ETag" = String, description = "Version of the local tasks"))),
+        (status = 400, description = "Malformed tasks", body = ErrorBody),
+        (status = 412, description = "The tasks changed since they were read", body = ErrorBody),
+    )
+)]
+async fn add_tasks(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(new_tasks): Json<Vec<AVTask>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let span = tracing::info_span!("http", route = "POST /v1/tasks");
+    let expected = if_match(&headers)?;
+    let version = state
+        .tasks
+        .store_tasks(new_tasks, expected)
+        .instrument(span)
+        .await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::ETAG, etag(version))],
+        Json(StatusCodeBody { success: true }),
+    ))
+}
+
+/// The version `If-Match` names, if it names one rather than `*`.
+#[allow(clippy::result_large_err)]
+fn if_match(headers: &HeaderMap) -> Result<Option<i64>, ApiError> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    let value = value.to_str().unwrap_or_default().trim();
+    if value == "*" {
+        return Ok(None);
+    }
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .and_then(|version| version.parse().ok())
+        .map(Some)
+        .ok_or_else(|| {
+            tonic::Status::new(
+                Code::InvalidArgument,
+                "If-Match must be an ETag of the tasks",
+            )
+            .into()
+        })
+}
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
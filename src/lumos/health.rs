@@ -0,0 +1,126 @@
+use super::rpc::{LanternServer, TaskService};
+use super::shutdown::Shutdown;
+
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tonic_health::server::HealthReporter;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// How long to wait for a connection from the pool before declaring the database unhealthy.
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the gRPC health status is brought in line with [`Readiness::check`].
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Tracks whether the server is in a state to usefully serve requests.
+///
+/// Cheap to clone; every clone shares the same state.
+#[derive(Clone)]
+pub struct Readiness {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    db_conn: Pool<ConnectionManager<PgConnection>>,
+    last_sync: Mutex<Option<SyncOutcome>>,
+    shutdown: Shutdown,
+}
+
+#[derive(Clone, Copy)]
+struct SyncOutcome {
+    at: Instant,
+    success: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub database: bool,
+    pub migrations: bool,
+    pub firefly: bool,
+    pub shutting_down: bool,
+    /// Seconds since the last sync with Firefly, if there has been one.
+    pub last_sync_secs: Option<u64>,
+}
+
+impl Report {
+    pub fn is_ready(&self) -> bool {
+        self.database && self.migrations && self.firefly && !self.shutting_down
+    }
+}
+
+impl Readiness {
+    pub fn new(db_conn: Pool<ConnectionManager<PgConnection>>, shutdown: Shutdown) -> Self {
+        Readiness {
+            inner: Arc::new(Inner {
+                db_conn,
+                last_sync: Mutex::new(None),
+                shutdown,
+            }),
+        }
+    }
+
+    /// Records the outcome of a sync with the task sources.
+    pub fn record_sync(&self, success: bool) {
+        *self.inner.last_sync.lock().unwrap() = Some(SyncOutcome {
+            at: Instant::now(),
+            success,
+        });
+    }
+
+    /// Checks the database pool, pending migrations and the most recent Firefly sync.
+    ///
+    /// Firefly is considered reachable until a sync fails, as it has to be reachable for the
+    /// user to be attached at startup in the first place.
+    pub async fn check(&self) -> Report {
+        let pool = self.inner.db_conn.clone();
+        let (database, migrations) =
+            tokio::task::spawn_blocking(move || match pool.get_timeout(DB_CHECK_TIMEOUT) {
+                Ok(mut conn) => (
+                    true,
+                    matches!(conn.has_pending_migration(MIGRATIONS), Ok(false)),
+                ),
+                Err(_) => (false, false),
+            })
+            .await
+            .unwrap_or((false, false));
+
+        let last_sync = *self.inner.last_sync.lock().unwrap();
+        Report {
+            database,
+            migrations,
+            firefly: last_sync.is_none_or(|sync| sync.success),
+            shutting_down: self.inner.shutdown.is_triggered(),
+            last_sync_secs: last_sync.map(|sync| sync.at.elapsed().as_secs()),
+        }
+    }
+}
+
+/// Keeps the status of `grpc.health.v1.Health` in line with `readiness` until shutdown, at which
+/// point every service is reported as `NOT_SERVING`.
+pub async fn report(mut reporter: HealthReporter, readiness: Readiness, shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if readiness.check().await.is_ready() {
+                    reporter.set_serving::<LanternServer<TaskService>>().await;
+                } else {
+                    reporter.set_not_serving::<LanternServer<TaskService>>().await;
+                }
+            }
+            _ = shutdown.triggered() => break,
+        }
+    }
+
+    reporter
+        .set_not_serving::<LanternServer<TaskService>>()
+        .await;
+    reporter
+        .set_service_status("", tonic_health::ServingStatus::NotServing)
+        .await;
+}
@@ -0,0 +1,50 @@
+use super::gateway;
+use super::health::Readiness;
+use super::ics;
+use super::metrics;
+use super::rpc::TaskService;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use std::sync::Arc;
+
+/// State shared by every handler on the HTTP side-listener.
+#[derive(Clone)]
+pub struct AppState {
+    pub readiness: Readiness,
+    pub db_conn: Pool<ConnectionManager<PgConnection>>,
+    pub tasks: Arc<TaskService>,
+}
+
+/// Routes served next to the gRPC server, for anything that is more convenient over plain HTTP.
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(render_metrics))
+        .merge(gateway::routes())
+        .merge(ics::routes())
+        .with_state(state)
+}
+
+/// Liveness: the process is up and able to answer.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness: the database is usable, migrations are applied and Firefly syncs are succeeding.
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let report = state.readiness.check().await;
+    let code = if report.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(report))
+}
+
+/// Prometheus scrape endpoint.
+async fn render_metrics(State(state): State<AppState>) -> String {
+    metrics::render(&state.db_conn)
+}
@@ -0,0 +1,260 @@
+//! iCalendar feed of a user's tasks, served at a per-user secret url that calendar apps can
+//! subscribe to.
+//!
+//! The feed is built from the tasks stored in the database, so serving it never hits Firefly;
+//! Firefly tasks are as fresh as the last unfiltered sync.
+//!
+//! Going the other way, [`import`] turns calendar files into local tasks.
+use super::custom_tag;
+use super::filter::CompletionStatus;
+use super::http::AppState;
+use super::priority;
+use super::rpc::light::Filter;
+use super::task::{AVTask, Tag};
+use super::user::utils::cached_tasks;
+use crate::models::{FeedTokenPG, NewFeedTokenPG};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use icalendar::{Calendar, Component, Event, EventLike, Todo, TodoStatus};
+use serde::Deserialize;
+use std::str::FromStr;
+use uuid::Uuid;
+
+pub mod import;
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/v1/calendar/:token/tasks.ics", get(serve_feed))
+}
+
+/// Which tasks end up in the feed, and as what.
+#[derive(Debug, Default, Deserialize)]
+pub struct FeedQuery {
+    /// `Todo`, `DoneOrArchived` or `AllIncludingArchived`; defaults to everything
+    pub status: Option<String>,
+    /// Only include tasks from these sources, comma separated (e.g. `FF,MS`)
+    pub source: Option<String>,
+    /// `todo`, `event` or `both` (the default); many calendar apps ignore `VTODO`s entirely
+    pub kind: Option<String>,
+}
+
+/// Creates a new feed token for `email`.
+pub fn create_token(db_conn: &mut PgConnection, email: &str) -> QueryResult<String> {
+    use crate::schema::feed_tokens;
+
+    let token = Uuid::new_v4().simple().to_string() + &Uuid::new_v4().simple().to_string();
+    diesel::insert_into(feed_tokens::table)
+        .values(&NewFeedTokenPG {
+            user_email: email,
+            token: &token,
+        })
+        .execute(db_conn)?;
+    Ok(token)
+}
+
+/// Revokes `revoked` (or every feed of `email`, if `None`). Returns the number of feeds revoked.
+pub fn revoke_token(
+    db_conn: &mut PgConnection,
+    email: &str,
+    revoked: Option<&str>,
+) -> QueryResult<usize> {
+    use crate::schema::feed_tokens::dsl::*;
+
+    let feeds = feed_tokens
+        .filter(user_email.eq(email))
+        .filter(revoked_at.is_null());
+    match revoked {
+        Some(revoked) => diesel::update(feeds.filter(token.eq(revoked)))
+            .set(revoked_at.eq(Utc::now()))
+            .execute(db_conn),
+        None => diesel::update(feeds)
+            .set(revoked_at.eq(Utc::now()))
+            .execute(db_conn),
+    }
+}
+
+/// The url a feed is served at, with the parts of `filter` that apply to feeds baked in.
+///
+/// The base is taken from `LANTERN_PUBLIC_URL`, as the server can't know how it is reached; fails
+/// if that isn't a url.
+pub fn feed_url(feed_token: &str, filter: &Filter) -> Result<String, url::ParseError> {
+    let base = std::env::var("LANTERN_PUBLIC_URL")
+        .unwrap_or_else(|_| String::from("http://localhost:8081"));
+    let url = format!(
+        "{}/v1/calendar/{}/tasks.ics",
+        base.trim_end_matches('/'),
+        feed_token
+    );
+
+    let sources = std::iter::once(&filter.source)
+        .chain(&filter.sources)
+        .filter(|source| !source.is_empty())
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(",");
+    let params = [("status", &filter.status), ("source", &sources)]
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty());
+    let mut url = url::Url::parse(&url)?;
+    url.query_pairs_mut().extend_pairs(params);
+    if url.query() == Some("") {
+        url.set_query(None);
+    }
+    Ok(url.into())
+}
+
+/// Renders `local` and `firefly` tasks that match `query` as a calendar.
+pub fn render(local: &[AVTask], firefly: &[AVTask], query: &FeedQuery) -> Result<String, String> {
+    let status = query
+        .status
+        .as_deref()
+        .map(CompletionStatus::from_str)
+        .transpose()
+        .map_err(|_| String::from("unknown status"))?
+        .unwrap_or(CompletionStatus::AllIncludingArchived);
+    let sources = query
+        .source
+        .iter()
+        .flat_map(|sources| sources.split(','))
+        .map(str::trim)
+        .filter(|source| !source.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let (todos, events) = match query.kind.as_deref() {
+        None | Some("both") => (true, true),
+        Some("todo") => (true, false),
+        Some("event") => (false, true),
+        Some(_) => return Err(String::from("unknown kind")),
+    };
+
+    let mut calendar = Calendar::new();
+    calendar
+        .name("Lantern")
+        .description("Tasks from Firefly and Lantern")
+        .ttl(&Duration::hours(1));
+
+    let tasks = local
+        .iter()
+        .map(|task| ("local", task))
+        .chain(firefly.iter().map(|task| ("firefly", task)));
+    for (origin, task) in tasks {
+        let matches_status = match status {
+            CompletionStatus::Todo => !task.is_done,
+            CompletionStatus::DoneOrArchived => task.is_done,
+            CompletionStatus::AllIncludingArchived => true,
+        };
+        let matches_source = sources.is_empty()
+            || task
+                .tags
+                .iter()
+                .any(|tag| matches!(tag, Tag::Source { source } if sources.contains(source)));
+        if !matches_status || !matches_source {
+            continue;
+        }
+
+        let uid = format!("{}@{}.lantern", task.id, origin);
+        let description = format!("Set by {}", task.setter_name);
+        let categories = categories(task);
+
+        if todos {
+            let mut todo = Todo::new();
+            todo.uid(&uid)
+                .summary(&task.title)
+                .description(&description);
+            if let Some(set) = task.set() {
+                todo.starts(set);
+            }
+            if let Some(due) = task.due() {
+                todo.due(due);
+            }
+            if !categories.is_empty() {
+                todo.add_property("CATEGORIES", categories.join(","));
+            }
+            if task.is_done {
+                todo.status(TodoStatus::Completed).percent_complete(100);
+            } else {
+                todo.status(TodoStatus::NeedsAction);
+            }
+            calendar.push(todo.done());
+        }
+
+        // an event only makes sense on a date, so tasks without one only get a todo
+        if let (true, Some(due)) = (events, task.due()) {
+            let mut event = Event::new();
+            event
+                .uid(&format!("event-{}", uid))
+                .summary(&if task.is_done {
+                    format!("✓ {}", task.title)
+                } else {
+                    task.title.clone()
+                })
+                .description(&description)
+                .starts(due.date_naive())
+                // the end of an all-day event is exclusive
+                .ends(due.date_naive() + Duration::days(1));
+            if !categories.is_empty() {
+                event.add_property("CATEGORIES", categories.join(","));
+            }
+            calendar.push(event.done());
+        }
+    }
+
+    Ok(calendar.done().to_string())
+}
+
+/// Tags of `task` that make sense as calendar categories.
+fn categories(task: &AVTask) -> Vec<String> {
+    task.tags
+        .iter()
+        .filter_map(|tag| match tag {
+            Tag::Source { source } => Some(source.clone()),
+            Tag::Priority { priority } => Some(format!("Priority {}", priority)),
+            Tag::Custom { name, .. } => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+async fn serve_feed(
+    State(state): State<AppState>,
+    Path(feed_token): Path<String>,
+    Query(query): Query<FeedQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    use crate::schema::feed_tokens::dsl::*;
+
+    let mut db_conn = state
+        .db_conn
+        .get()
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    let feed = feed_tokens
+        .filter(token.eq(&feed_token))
+        .filter(revoked_at.is_null())
+        .first::<FeedTokenPG>(&mut db_conn)
+        .optional()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let (mut local, mut firefly) = cached_tasks(&mut db_conn, &feed.user_email)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let weights = priority::weights(&mut db_conn, &feed.user_email)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let now = Utc::now();
+    priority::assign(&mut local, &weights, now);
+    priority::assign(&mut firefly, &weights, now);
+    custom_tag::apply(&mut db_conn, &feed.user_email, "local", &mut local)
+        .and_then(|_| custom_tag::apply(&mut db_conn, &feed.user_email, "firefly", &mut firefly))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let calendar = render(&local, &firefly, &query).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        calendar,
+    ))
+}
@@ -0,0 +1,207 @@
+//! Imports the `VTODO`s and `VEVENT`s of a calendar file as local tasks.
+use crate::lumos::audit::Actor;
+use crate::lumos::custom_tag;
+use crate::lumos::task::{AVTask, Tag};
+use crate::lumos::user::utils::{update_local_tasks, LocalTasks};
+
+use chrono::SecondsFormat;
+use diesel::prelude::*;
+use icalendar::{
+    Calendar, CalendarComponent, CalendarDateTime, Component, DatePerhapsTime, TodoStatus,
+};
+use std::collections::HashSet;
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub imported: usize,
+    /// Entries skipped because a task with the same UID was imported before.
+    pub duplicates: usize,
+    pub errors: Vec<ItemError>,
+}
+
+/// An entry of the calendar, as a task along with its `CATEGORIES`.
+#[derive(Debug)]
+pub struct Entry {
+    pub task: AVTask,
+    /// Become [custom tags](custom_tag) of the task once it is imported.
+    pub categories: Vec<String>,
+}
+
+/// An entry of the calendar that couldn't be turned into a task.
+#[derive(Debug)]
+pub struct ItemError {
+    /// Position of the entry among the `VTODO`s and `VEVENT`s of the file.
+    pub index: usize,
+    pub uid: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("calendar is malformed: {0}")]
+    Malformed(String),
+
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+/// Imports every `VTODO` and `VEVENT` in `contents` as a local task of `email`.
+///
+/// Entries whose UID matches a task that was already imported are skipped, so importing the same
+/// file twice doesn't duplicate anything. Entries without a UID can't be told apart, and are
+/// always imported. Categories are put on the tasks as custom tags, made as needed.
+pub fn import(
+    db_conn: &mut PgConnection,
+    email: &str,
+    contents: &str,
+) -> Result<Report, ImportError> {
+    let entries = parse(contents).map_err(ImportError::Malformed)?;
+
+    db_conn.transaction(|db_conn| {
+        let (report, tagged) = update_local_tasks(db_conn, email, Actor::User, |loc_tasks| {
+            add_entries(loc_tasks, entries)
+        })?;
+        custom_tag::attach_by_name(db_conn, email, "local", &tagged)?;
+        Ok(report)
+    })
+}
+
+/// Adds `entries` to `loc_tasks`, giving the report along with the categories of each task added.
+fn add_entries(
+    loc_tasks: &mut LocalTasks,
+    entries: Vec<Result<Entry, ItemError>>,
+) -> (Report, Vec<(usize, Vec<String>)>) {
+    let mut report = Report::default();
+    let mut tagged = vec![];
+    let mut seen = loc_tasks
+        .iter()
+        .filter_map(|task| task.import_uid.clone())
+        .collect::<HashSet<_>>();
+
+    for entry in entries {
+        let Entry {
+            mut task,
+            categories,
+        } = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                report.errors.push(e);
+                continue;
+            }
+        };
+
+        if let Some(ref uid) = task.import_uid {
+            if !seen.insert(uid.clone()) {
+                report.duplicates += 1;
+                continue;
+            }
+        }
+
+        task.id = loc_tasks.next_id();
+        if !categories.is_empty() {
+            tagged.push((task.id, categories));
+        }
+        loc_tasks.push(task);
+        report.imported += 1;
+    }
+    (report, tagged)
+}
+
+/// Parses the entries of a calendar into tasks, without assigning them ids.
+pub fn parse(contents: &str) -> Result<Vec<Result<Entry, ItemError>>, String> {
+    let calendar = contents.parse::<Calendar>()?;
+
+    let entries = calendar
+        .components
+        .iter()
+        .filter(|component| {
+            matches!(
+                component,
+                CalendarComponent::Todo(_) | CalendarComponent::Event(_)
+            )
+        })
+        .enumerate()
+        .map(|(index, component)| match component {
+            CalendarComponent::Todo(todo) => to_task(
+                index,
+                todo,
+                todo.get_due().or_else(|| todo.get_start()),
+                todo.get_status() == Some(TodoStatus::Completed) || todo.get_completed().is_some(),
+            ),
+            CalendarComponent::Event(event) => to_task(index, event, event.get_start(), false),
+            _ => unreachable!(),
+        })
+        .collect();
+    Ok(entries)
+}
+
+fn to_task(
+    index: usize,
+    component: &impl Component,
+    due: Option<DatePerhapsTime>,
+    is_done: bool,
+) -> Result<Entry, ItemError> {
+    let uid = component.get_uid().map(String::from);
+    let error = |message: &str| ItemError {
+        index,
+        uid: uid.clone(),
+        message: message.to_string(),
+    };
+
+    let title = component
+        .get_summary()
+        .filter(|summary| !summary.trim().is_empty())
+        .ok_or_else(|| error("missing SUMMARY"))?;
+    let due_date = due.ok_or_else(|| error("missing a due date or start"))?;
+    let set_date = component
+        .get_timestamp()
+        .map(|stamp| stamp.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default();
+
+    let categories = component
+        .multi_properties()
+        .get("CATEGORIES")
+        .into_iter()
+        .flatten()
+        .flat_map(|categories| categories.value().split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect();
+    let due_date = format_date(&due_date);
+    let tags = vec![Tag::DueDate {
+        date: due_date.clone(),
+    }];
+
+    let task = AVTask {
+        due_date,
+        is_done,
+        set_date,
+        title: title.to_string(),
+        setter_key: String::from("self"),
+        setter_name: String::from("Imported"),
+        id: 0,
+        tags,
+        import_uid: uid,
+        ..Default::default()
+    };
+    Ok(Entry { task, categories })
+}
+
+/// Formats a calendar date the way [`parse_date`](crate::lumos::task::parse_date) reads it back.
+fn format_date(date: &DatePerhapsTime) -> String {
+    match date {
+        DatePerhapsTime::Date(date) => date.format("%Y-%m-%d").to_string(),
+        DatePerhapsTime::DateTime(CalendarDateTime::Utc(date)) => {
+            date.to_rfc3339_opts(SecondsFormat::Secs, true)
+        }
+        DatePerhapsTime::DateTime(date @ CalendarDateTime::WithTimezone { date_time, .. }) => date
+            .try_into_utc()
+            .map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true))
+            // unknown time zone; the wall clock time is the best there is
+            .unwrap_or_else(|| date_time.format("%Y-%m-%dT%H:%M:%S").to_string()),
+        DatePerhapsTime::DateTime(CalendarDateTime::Floating(date)) => {
+            date.format("%Y-%m-%dT%H:%M:%S").to_string()
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Listings of tasks that `GetTasks` hands out a page at a time.
+//!
+//! Syncing again for every page would let tasks added, removed or re-dated at the source in
+//! between shift the rest along, so that a page skips or repeats tasks. Instead, the whole listing
+//! is kept when its first page is given out, and the pages after it are cut from the same tasks in
+//! the same order. Listings expire [`TTL_MINUTES`] after they were made.
+use super::task::AVTask;
+use crate::models::{NewTaskListingPG, TaskListingPG};
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+/// How long the pages of a listing can be asked for after its first page.
+pub const TTL_MINUTES: i64 = 10;
+
+/// Keeps `tasks`, listed at `version` of the local tasks of `email`, returning the id of the
+/// listing. Expired listings of `email` are dropped on the way.
+pub fn store(
+    db_conn: &mut PgConnection,
+    email: &str,
+    tasks: &[AVTask],
+    version: i64,
+) -> QueryResult<String> {
+    use crate::schema::task_listings;
+
+    let expired = Utc::now() - Duration::minutes(TTL_MINUTES);
+    diesel::delete(
+        task_listings::table
+            .filter(task_listings::user_email.eq(email))
+            .filter(task_listings::created_at.le(expired)),
+    )
+    .execute(db_conn)?;
+
+    let new_id = Uuid::new_v4().simple().to_string();
+    diesel::insert_into(task_listings::table)
+        .values(&NewTaskListingPG {
+            id: &new_id,
+            user_email: email,
+            tasks: serde_json::to_value(tasks).unwrap(),
+            version,
+        })
+        .execute(db_conn)?;
+    Ok(new_id)
+}
+
+/// Gets the tasks of the listing `listing` of `email` and the version they were listed at, unless
+/// it has expired.
+pub fn load(
+    db_conn: &mut PgConnection,
+    email: &str,
+    listing: &str,
+) -> QueryResult<Option<(Vec<AVTask>, i64)>> {
+    use crate::schema::task_listings::dsl::*;
+
+    let expired = Utc::now() - Duration::minutes(TTL_MINUTES);
+    let found = task_listings
+        .filter(id.eq(listing))
+        .filter(user_email.eq(email))
+        .filter(created_at.gt(expired))
+        .first::<TaskListingPG>(db_conn)
+        .optional()?;
+    Ok(found.map(|found| {
+        let listed = serde_json::from_value(found.tasks).unwrap_or_default();
+        (listed, found.version)
+    }))
+}
+
+/// The page token of the page of the listing `listing` that starts at `offset`.
+pub fn token(listing: &str, offset: usize) -> String {
+    format!("{}.{}", listing, offset)
+}
+
+/// Reads a token made by [`token`] back into the listing and the offset.
+pub fn parse_token(page_token: &str) -> Option<(&str, usize)> {
+    let (listing, offset) = page_token.split_once('.')?;
+    if listing.is_empty() {
+        return None;
+    }
+    Some((listing, offset.parse().ok()?))
+}
@@ -0,0 +1,207 @@
+//! Prometheus metrics, served on `/metrics` by the HTTP side-listener.
+//!
+//! None of the metrics are labelled per user; labels are limited to rpc methods, Firefly
+//! endpoints, status codes and task sources so that the number of series stays bounded.
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use prometheus::{
+    core::Collector, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+use tonic::Status;
+
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+pub static RPC_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "lantern_rpc_requests_total",
+                "RPCs handled, by method and code",
+            ),
+            &["method", "code"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static RPC_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "lantern_rpc_duration_seconds",
+                "Time taken to handle an RPC",
+            ),
+            &["method"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static FIREFLY_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "lantern_firefly_requests_total",
+                "HTTP requests made to Firefly, by endpoint and status",
+            ),
+            &["endpoint", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static FIREFLY_REAUTHS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "lantern_firefly_reauths_total",
+            "Times the Firefly secret was found to be invalid and refreshed",
+        )
+        .unwrap(),
+    )
+});
+
+pub static SYNC_PAGES: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new(
+                "lantern_sync_pages",
+                "Pages of tasks fetched from Firefly per sync",
+            )
+            .buckets(vec![1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0]),
+        )
+        .unwrap(),
+    )
+});
+
+pub static SYNC_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "lantern_sync_duration_seconds",
+                "Time taken to sync tasks with Firefly",
+            )
+            .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]),
+            &["outcome"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static TASKS_STORED: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "lantern_tasks_stored",
+                "Tasks stored as of the last sync or write, by source",
+            ),
+            &["source"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static REMINDERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "lantern_reminders_total",
+                "Reminders processed, by channel and outcome",
+            ),
+            &["channel", "outcome"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static DB_POOL_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "lantern_db_pool_connections",
+                "Connections in the database pool, by state",
+            ),
+            &["state"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static DB_POOL_MAX: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "lantern_db_pool_max_connections",
+            "Maximum size of the database pool",
+        )
+        .unwrap(),
+    )
+});
+
+fn register<M: Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+/// Runs `rpc`, recording its duration and the code it finished with under `method`.
+pub async fn track_rpc<T>(
+    method: &'static str,
+    rpc: impl Future<Output = Result<T, Status>>,
+) -> Result<T, Status> {
+    let start = Instant::now();
+    let res = rpc.await;
+    let code = match &res {
+        Ok(_) => tonic::Code::Ok,
+        Err(status) => status.code(),
+    };
+
+    RPC_REQUESTS
+        .with_label_values(&[method, &format!("{:?}", code)])
+        .inc();
+    RPC_DURATION
+        .with_label_values(&[method])
+        .observe(start.elapsed().as_secs_f64());
+    res
+}
+
+/// Records the outcome of a request to the Firefly `endpoint`.
+pub fn firefly_request<T>(endpoint: &'static str, res: &Result<reqwest::Response, T>) {
+    let status = match res {
+        Ok(res) => res.status().as_u16().to_string(),
+        Err(_) => String::from("error"),
+    };
+    FIREFLY_REQUESTS
+        .with_label_values(&[endpoint, &status])
+        .inc();
+}
+
+/// Renders every metric in the Prometheus text format, sampling the database pool first.
+pub fn render(db_conn: &Pool<ConnectionManager<PgConnection>>) -> String {
+    // make sure metrics that haven't been touched yet are still registered
+    LazyLock::force(&RPC_REQUESTS);
+    LazyLock::force(&RPC_DURATION);
+    LazyLock::force(&FIREFLY_REQUESTS);
+    LazyLock::force(&FIREFLY_REAUTHS);
+    LazyLock::force(&SYNC_PAGES);
+    LazyLock::force(&SYNC_DURATION);
+    LazyLock::force(&TASKS_STORED);
+    LazyLock::force(&REMINDERS);
+
+    let state = db_conn.state();
+    let idle = i64::from(state.idle_connections);
+    DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["in_use"])
+        .set(i64::from(state.connections) - idle);
+    DB_POOL_MAX.set(i64::from(db_conn.max_size()));
+
+    let mut buf = vec![];
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buf)
+        .expect("failed to encode metrics");
+    String::from_utf8(buf).unwrap()
+}
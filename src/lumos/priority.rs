@@ -0,0 +1,182 @@
+//! Urgency scores for tasks, attached as a [`Tag::Priority`] whenever tasks are read.
+//!
+//! A task's score adds up:
+//! - `due_soon`, scaled by how close the due date is (the full weight when due now, falling off
+//!   exponentially over `horizon_days`)
+//! - `overdue`, if the due date has passed
+//! - `file_submission`, if a file has to be handed in
+//! - the weight of each of its classes, and of its setter (looked up by key, then by name)
+//!
+//! Done tasks always score 0. The weights are per user, falling back to [`Weights::default`].
+use super::filter::SortOrder;
+use super::task::{AVTask, Tag};
+use crate::models::{NewPriorityWeightsPG, PriorityWeightsPG};
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use strum_macros::Display;
+
+/// Scores from which a task is [`Level::Medium`] and [`Level::High`].
+const MEDIUM: f64 = 3.0;
+const HIGH: f64 = 6.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Weights {
+    pub due_soon: f64,
+    pub overdue: f64,
+    pub file_submission: f64,
+    /// Days over which the weight of the due date drops to about a third; must be positive.
+    pub horizon_days: f64,
+    /// By class name.
+    pub classes: HashMap<String, f64>,
+    /// By setter key or name.
+    pub setters: HashMap<String, f64>,
+}
+
+impl Default for Weights {
+    /// With these a task is high priority once it is overdue, or due within a day with a file to
+    /// hand in; and medium priority when due within three and a half days.
+    fn default() -> Self {
+        Weights {
+            due_soon: 5.0,
+            overdue: 8.0,
+            file_submission: 2.0,
+            horizon_days: 7.0,
+            classes: HashMap::new(),
+            setters: HashMap::new(),
+        }
+    }
+}
+
+impl Weights {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.horizon_days.is_finite() && self.horizon_days > 0.0) {
+            return Err(String::from("horizon_days must be positive"));
+        }
+        let all_finite = [self.due_soon, self.overdue, self.file_submission]
+            .iter()
+            .chain(self.classes.values())
+            .chain(self.setters.values())
+            .all(|weight| weight.is_finite());
+        if !all_finite {
+            return Err(String::from("weights must be finite"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+pub enum Level {
+    Low,
+    Medium,
+    High,
+}
+
+impl Level {
+    pub fn of(score: f64) -> Level {
+        if score >= HIGH {
+            Level::High
+        } else if score >= MEDIUM {
+            Level::Medium
+        } else {
+            Level::Low
+        }
+    }
+}
+
+/// The urgency of `task` as of `now`; higher is more urgent.
+pub fn score(task: &AVTask, weights: &Weights, now: DateTime<Utc>) -> f64 {
+    if task.is_done {
+        return 0.0;
+    }
+
+    let mut score = 0.0;
+    if let Some(due) = task.due() {
+        let days_left = (due - now).num_seconds() as f64 / 86_400.0;
+        score += weights.due_soon * (-days_left.max(0.0) / weights.horizon_days).exp();
+        if days_left < 0.0 {
+            score += weights.overdue;
+        }
+    }
+    if task.file_submission_required {
+        score += weights.file_submission;
+    }
+    score += task
+        .classes
+        .iter()
+        .filter_map(|class| weights.classes.get(class))
+        .sum::<f64>();
+    score += weights
+        .setters
+        .get(&task.setter_key)
+        .or_else(|| weights.setters.get(&task.setter_name))
+        .copied()
+        .unwrap_or(0.0);
+    score
+}
+
+/// Replaces any priority tag on `tasks` with the one they score.
+pub fn assign(tasks: &mut [AVTask], weights: &Weights, now: DateTime<Utc>) {
+    for task in tasks {
+        let level = Level::of(score(task, weights, now));
+        task.tags.retain(|tag| !matches!(tag, Tag::Priority { .. }));
+        task.tags.push(Tag::Priority {
+            priority: level.to_string(),
+        });
+    }
+}
+
+/// Sorts `tasks` by score, ties going to the one due first.
+pub fn sort(tasks: &mut [AVTask], weights: &Weights, now: DateTime<Utc>, order: SortOrder) {
+    tasks.sort_by(|a, b| {
+        let (a_score, b_score) = (score(a, weights, now), score(b, weights, now));
+        let by_score = match order {
+            SortOrder::Ascending => a_score.total_cmp(&b_score),
+            SortOrder::Descending => b_score.total_cmp(&a_score),
+        };
+        by_score.then_with(|| match (a.due(), b.due()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        })
+    });
+}
+
+/// Gets the weights of `email`, or the defaults if they never set any.
+pub fn weights(db_conn: &mut PgConnection, email: &str) -> QueryResult<Weights> {
+    use crate::schema::priority_weights::dsl::*;
+
+    let row = priority_weights
+        .filter(user_email.eq(email))
+        .first::<PriorityWeightsPG>(db_conn)
+        .optional()?;
+    Ok(row
+        .and_then(|row| {
+            serde_json::from_value(row.weights)
+                .map_err(|e| tracing::warn!(error = %e, "stored priority weights are malformed"))
+                .ok()
+        })
+        .unwrap_or_default())
+}
+
+/// Sets the weights of `email`; they should have been [validated](Weights::validate) first.
+pub fn set_weights(db_conn: &mut PgConnection, email: &str, new: &Weights) -> QueryResult<()> {
+    use crate::schema::priority_weights::dsl::*;
+
+    let value = serde_json::to_value(new).unwrap();
+    diesel::insert_into(priority_weights)
+        .values(&NewPriorityWeightsPG {
+            user_email: email,
+            weights: value.clone(),
+        })
+        .on_conflict(user_email)
+        .do_update()
+        .set((weights.eq(&value), updated_at.eq(Utc::now())))
+        .execute(db_conn)?;
+    Ok(())
+}
@@ -0,0 +1,614 @@
+//! Local tasks that repeat, like a weekly vocab test or daily reading.
+//!
+//! A series is a title and a [`Frequency`], anchored at the local time of its first occurrence in
+//! an IANA timezone, so an 8am task stays at 8am across changes in daylight saving. Occurrences are
+//! made into ordinary local tasks, carrying an [`Occurrence`] that ties them to their series:
+//! those due within [`HORIZON`] are [generated](generate) ahead of time, and there is always at
+//! least the next one, so completing the last open occurrence rolls the series on to the one after.
+//! Occurrences that are long overdue by the time they would be made (e.g. the server was down) are
+//! passed over.
+//!
+//! Editing a series changes its open occurrences, except those that were [edited](edit) by
+//! themselves. Changing when it falls replaces its future occurrences with ones on the new
+//! schedule.
+use super::audit::Actor;
+use super::shutdown::Shutdown;
+use super::task::{AVTask, Occurrence, Tag};
+use super::user::utils::{update_local_tasks, update_local_tasks_if, Precondition, WriteError};
+use crate::models::{NewTaskSeriesPG, TaskSeriesPG};
+
+use chrono::{
+    DateTime, Datelike, Duration as ChronoDuration, Months, NaiveDate, NaiveDateTime,
+    SecondsFormat, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::Instrument;
+
+/// How often occurrences are generated, unless `LANTERN_RECURRENCE_INTERVAL` says otherwise.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How far ahead occurrences are made into tasks.
+pub const HORIZON: ChronoDuration = ChronoDuration::days(7);
+
+/// How overdue an occurrence may be and still be made into a task, when a series is behind.
+pub const CATCH_UP: ChronoDuration = ChronoDuration::days(1);
+
+const MAX_TITLE_LEN: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "frequency", rename_all = "snake_case")]
+pub enum Frequency {
+    Daily,
+    /// On each of `weekdays`, at the time of day the series starts at.
+    Weekly {
+        weekdays: Vec<Weekday>,
+    },
+    EveryNDays {
+        days: u32,
+    },
+    /// On the day of the month the series starts on, or the last day of months that are too short.
+    Monthly,
+}
+
+/// A series as given by the user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub title: String,
+    pub frequency: Frequency,
+    pub timezone: Tz,
+    /// The local time of the first occurrence (if it falls on the schedule).
+    pub starts_at: NaiveDateTime,
+    /// The last local day an occurrence may fall on.
+    pub until: Option<NaiveDate>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SeriesError {
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("{0}")]
+    NotFound(&'static str),
+
+    /// The task changed since the client read it; see [`WriteError::Conflict`].
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+impl From<WriteError> for SeriesError {
+    fn from(e: WriteError) -> Self {
+        match e {
+            e @ WriteError::Conflict(_) => SeriesError::Conflict(e.to_string()),
+            WriteError::Database(e) => SeriesError::Database(e),
+        }
+    }
+}
+
+fn validate_title(title: &str) -> Result<&str, String> {
+    let title = title.trim();
+    if title.is_empty() || title.chars().count() > MAX_TITLE_LEN {
+        return Err(format!(
+            "titles must be between 1 and {} characters",
+            MAX_TITLE_LEN
+        ));
+    }
+    Ok(title)
+}
+
+impl Series {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_title(&self.title)?;
+        match &self.frequency {
+            Frequency::Weekly { weekdays } if weekdays.is_empty() => {
+                return Err(String::from("a weekly series needs at least one weekday"))
+            }
+            Frequency::EveryNDays { days } if !(1..=366).contains(days) => {
+                return Err(String::from(
+                    "days between occurrences must be from 1 to 366",
+                ))
+            }
+            _ => {}
+        }
+        if self
+            .until
+            .is_some_and(|until| until < self.starts_at.date())
+        {
+            return Err(String::from("a series can't end before it starts"));
+        }
+        Ok(())
+    }
+
+    /// Every occurrence of the series in order, in its local time.
+    pub fn occurrences(&self) -> impl Iterator<Item = NaiveDateTime> + '_ {
+        self.occurrences_from(0)
+    }
+
+    /// The occurrences of the series after the local time `after`, in order. Unlike skipping
+    /// through [`occurrences`](Series::occurrences), this doesn't walk the series from its start.
+    pub fn occurrences_after(
+        &self,
+        after: NaiveDateTime,
+    ) -> impl Iterator<Item = NaiveDateTime> + '_ {
+        let start = self.starts_at;
+        // the step of an occurrence at or before `after`, so none after it is passed over
+        let step = if after <= start {
+            0
+        } else {
+            let days = (after - start).num_days();
+            match &self.frequency {
+                Frequency::Daily | Frequency::Weekly { .. } => days,
+                Frequency::EveryNDays { days: every } => days / i64::from(*every),
+                Frequency::Monthly => {
+                    let months = (after.year() - start.year()) * 12 + after.month() as i32
+                        - start.month() as i32;
+                    i64::from(months - 1).max(0)
+                }
+            }
+        };
+        self.occurrences_from(step)
+            .skip_while(move |at| *at <= after)
+    }
+
+    /// The occurrences of the series from the `step`th day (or month, for a monthly series) on.
+    fn occurrences_from(&self, step: i64) -> impl Iterator<Item = NaiveDateTime> + '_ {
+        let start = self.starts_at;
+        let mut step = step;
+        std::iter::from_fn(move || loop {
+            let at = match &self.frequency {
+                Frequency::Daily => start.checked_add_signed(ChronoDuration::days(step))?,
+                Frequency::EveryNDays { days } => {
+                    start.checked_add_signed(ChronoDuration::days(step * i64::from(*days)))?
+                }
+                Frequency::Weekly { weekdays } => {
+                    let at = start.checked_add_signed(ChronoDuration::days(step))?;
+                    if !weekdays.contains(&at.weekday()) {
+                        step += 1;
+                        continue;
+                    }
+                    at
+                }
+                Frequency::Monthly => {
+                    let month = start
+                        .date()
+                        .with_day(1)?
+                        .checked_add_months(Months::new(step as u32))?;
+                    let day = (1..=start.day())
+                        .rev()
+                        .find_map(|day| month.with_day(day))?;
+                    day.and_time(start.time())
+                }
+            };
+            step += 1;
+            if self.until.is_some_and(|until| at.date() > until) {
+                return None;
+            }
+            return Some(at);
+        })
+    }
+
+    /// When the occurrence at the local time `at` is due. Times skipped by a change in daylight
+    /// saving are moved forward an hour, and those that happen twice are taken the first time.
+    pub fn due(&self, at: NaiveDateTime) -> DateTime<Tz> {
+        let local = |at| self.timezone.from_local_datetime(&at).earliest();
+        local(at)
+            .or_else(|| local(at + ChronoDuration::hours(1)))
+            .unwrap_or_else(|| self.timezone.from_utc_datetime(&at))
+    }
+
+    /// The occurrences to make into tasks at `now`, past `generated_until`, the last one that was
+    /// made: those due within [`HORIZON`], and the next one too if the series has no open
+    /// occurrence (`has_open`). Gives them along with the new `generated_until`.
+    ///
+    /// Occurrences that were due more than [`CATCH_UP`] before `now` are passed over rather than
+    /// made, so a series that fell behind (the server was down, or the series starts in the past)
+    /// doesn't flood the tasks, reminders and webhooks with overdue copies.
+    pub fn pending(
+        &self,
+        generated_until: Option<NaiveDateTime>,
+        has_open: bool,
+        now: DateTime<Utc>,
+    ) -> (Vec<NaiveDateTime>, Option<NaiveDateTime>) {
+        let oldest = now - CATCH_UP;
+        // a day early, as the local time of `oldest` may be off by a change in daylight saving
+        let mut after = self.local(oldest) - ChronoDuration::days(1);
+        if let Some(generated_until) = generated_until {
+            after = after.max(generated_until);
+        }
+
+        let (mut made, mut last, mut has_open) = (vec![], generated_until, has_open);
+        for at in self.occurrences_after(after) {
+            let due = self.due(at);
+            if due < oldest {
+                last = Some(at);
+                continue;
+            }
+            if has_open && due > now + HORIZON {
+                break;
+            }
+            made.push(at);
+            has_open = true;
+            last = Some(at);
+        }
+        (made, last)
+    }
+
+    /// The first `count` occurrences due after `now`.
+    pub fn upcoming(&self, now: DateTime<Utc>, count: usize) -> Vec<DateTime<Tz>> {
+        self.occurrences()
+            .map(|at| self.due(at))
+            .filter(|due| *due > now)
+            .take(count)
+            .collect()
+    }
+
+    /// The local time of the series at `now`.
+    fn local(&self, now: DateTime<Utc>) -> NaiveDateTime {
+        now.with_timezone(&self.timezone).naive_local()
+    }
+
+    /// Whether `other` falls on different days or times than this.
+    fn reschedules(&self, other: &Series) -> bool {
+        self.frequency != other.frequency
+            || self.timezone != other.timezone
+            || self.starts_at != other.starts_at
+            || self.until != other.until
+    }
+}
+
+impl TryFrom<&TaskSeriesPG> for Series {
+    type Error = String;
+
+    fn try_from(row: &TaskSeriesPG) -> Result<Self, Self::Error> {
+        let series = Series {
+            title: row.title.clone(),
+            frequency: serde_json::from_value(row.frequency.clone()).map_err(|e| e.to_string())?,
+            timezone: row
+                .timezone
+                .parse()
+                .map_err(|e: chrono_tz::ParseError| e.to_string())?,
+            starts_at: row.starts_at,
+            until: row.until,
+        };
+        // a weekly series without weekdays would look for its next occurrence forever
+        series.validate()?;
+        Ok(series)
+    }
+}
+
+/// Gets the series of `email`.
+pub fn list(db_conn: &mut PgConnection, email: &str) -> QueryResult<Vec<TaskSeriesPG>> {
+    use crate::schema::task_series::dsl::*;
+
+    task_series
+        .filter(user_email.eq(email))
+        .order(id)
+        .load(db_conn)
+}
+
+/// Creates `new` for `email`, and generates its first occurrences. Occurrences before `now` are
+/// skipped.
+pub fn create(
+    db_conn: &mut PgConnection,
+    email: &str,
+    new: &Series,
+    now: DateTime<Utc>,
+) -> Result<TaskSeriesPG, SeriesError> {
+    use crate::schema::task_series;
+
+    new.validate().map_err(SeriesError::Invalid)?;
+    let created = diesel::insert_into(task_series::table)
+        .values(&NewTaskSeriesPG {
+            user_email: email,
+            title: new.title.trim(),
+            frequency: serde_json::to_value(&new.frequency).unwrap(),
+            timezone: new.timezone.name(),
+            starts_at: new.starts_at,
+            until: new.until,
+            generated_until: Some(new.local(now)),
+        })
+        .get_result::<TaskSeriesPG>(db_conn)?;
+    generate(db_conn, email, now)?;
+    Ok(created)
+}
+
+/// Replaces the series `series` of `email` with `new`, along with its open occurrences.
+pub fn update(
+    db_conn: &mut PgConnection,
+    email: &str,
+    series: i32,
+    new: &Series,
+    now: DateTime<Utc>,
+) -> Result<TaskSeriesPG, SeriesError> {
+    use crate::schema::task_series::dsl::*;
+
+    new.validate().map_err(SeriesError::Invalid)?;
+    let updated = db_conn.transaction(|db_conn| {
+        let row = task_series
+            .filter(id.eq(series))
+            .filter(user_email.eq(email))
+            .for_update()
+            .first::<TaskSeriesPG>(db_conn)
+            .optional()?
+            .ok_or(SeriesError::NotFound("no such series"))?;
+        // a series that can't be read any more is rescheduled, which is what fixing it means
+        let rescheduled = Series::try_from(&row).map_or(true, |old| old.reschedules(new));
+        let new_title = new.title.trim().to_string();
+
+        update_local_tasks(db_conn, email, Actor::User, |loc_tasks| {
+            loc_tasks.retain(|task| {
+                !(rescheduled && is_open(task, series) && task.due().is_some_and(|due| due > now))
+            });
+            for task in loc_tasks.iter_mut().filter(|task| is_open(task, series)) {
+                task.title = new_title.clone();
+            }
+        })?;
+
+        let next = if rescheduled {
+            Some(new.local(now))
+        } else {
+            row.generated_until
+        };
+        Ok::<_, SeriesError>(
+            diesel::update(task_series.find(series))
+                .set((
+                    title.eq(&new_title),
+                    frequency.eq(serde_json::to_value(&new.frequency).unwrap()),
+                    timezone.eq(new.timezone.name()),
+                    starts_at.eq(new.starts_at),
+                    until.eq(new.until),
+                    generated_until.eq(next),
+                    updated_at.eq(Utc::now()),
+                ))
+                .get_result::<TaskSeriesPG>(db_conn)?,
+        )
+    })?;
+    generate(db_conn, email, now)?;
+    Ok(updated)
+}
+
+/// Deletes the series `series` of `email`, along with its open occurrences that aren't due yet.
+/// Its other occurrences are kept as one-off tasks.
+pub fn delete(
+    db_conn: &mut PgConnection,
+    email: &str,
+    series: i32,
+    now: DateTime<Utc>,
+) -> Result<(), SeriesError> {
+    use crate::schema::task_series::dsl::*;
+
+    db_conn.transaction(|db_conn| {
+        let deleted = diesel::delete(
+            task_series
+                .filter(id.eq(series))
+                .filter(user_email.eq(email)),
+        )
+        .execute(db_conn)?;
+        if deleted == 0 {
+            return Err(SeriesError::NotFound("no such series"));
+        }
+        update_local_tasks(db_conn, email, Actor::User, |loc_tasks| {
+            loc_tasks
+                .retain(|task| !(is_open(task, series) && task.due().is_some_and(|due| due > now)));
+            for task in loc_tasks.iter_mut() {
+                if task
+                    .occurrence
+                    .as_ref()
+                    .is_some_and(|occurrence| occurrence.series_id == series)
+                {
+                    task.occurrence = None;
+                }
+            }
+        })?;
+        Ok(())
+    })
+}
+
+/// Whether `task` is an occurrence of `series` that isn't done, and wasn't edited by itself.
+fn is_open(task: &AVTask, series: i32) -> bool {
+    !task.is_done
+        && task
+            .occurrence
+            .as_ref()
+            .is_some_and(|occurrence| occurrence.series_id == series && !occurrence.detached)
+}
+
+/// Makes the occurrences of every series of `email` that are due within [`HORIZON`] of `now` into
+/// tasks, as well as the next occurrence of any series that has none left open, as
+/// [`Series::pending`] lays out. Returns how many tasks were made.
+pub fn generate(db_conn: &mut PgConnection, email: &str, now: DateTime<Utc>) -> QueryResult<usize> {
+    use crate::schema::task_series::dsl::*;
+
+    db_conn.transaction(|db_conn| {
+        let all = task_series
+            .filter(user_email.eq(email))
+            .order(id)
+            .for_update()
+            .load::<TaskSeriesPG>(db_conn)?;
+        if all.is_empty() {
+            return Ok(0);
+        }
+
+        let (made, advanced) = update_local_tasks(db_conn, email, Actor::System, |loc_tasks| {
+            let mut made = 0;
+            let mut advanced = vec![];
+
+            for row in &all {
+                let series = match Series::try_from(row) {
+                    Ok(series) => series,
+                    Err(e) => {
+                        tracing::warn!(error = %e, series = row.id, "stored series is malformed");
+                        continue;
+                    }
+                };
+                let has_open = loc_tasks
+                    .iter()
+                    .any(|task| !task.is_done && is_occurrence(task, row.id));
+                let (pending, last) = series.pending(row.generated_until, has_open, now);
+
+                for at in pending {
+                    let task_id = loc_tasks.next_id();
+                    loc_tasks.push(occurrence_task(task_id, row.id, &series, at, now));
+                    made += 1;
+                }
+                if last != row.generated_until {
+                    advanced.push((row.id, last));
+                }
+            }
+            (made, advanced)
+        })?;
+
+        for (series, last) in advanced {
+            diesel::update(task_series.find(series))
+                .set(generated_until.eq(last))
+                .execute(db_conn)?;
+        }
+        if made > 0 {
+            tracing::debug!(count = made, "generated recurring tasks");
+        }
+        Ok(made)
+    })
+}
+
+fn is_occurrence(task: &AVTask, series: i32) -> bool {
+    task.occurrence
+        .as_ref()
+        .is_some_and(|occurrence| occurrence.series_id == series)
+}
+
+fn occurrence_task(
+    task_id: usize,
+    series_id: i32,
+    series: &Series,
+    at: NaiveDateTime,
+    now: DateTime<Utc>,
+) -> AVTask {
+    let due_date = series.due(at).to_rfc3339_opts(SecondsFormat::Secs, true);
+    AVTask {
+        due_date: due_date.clone(),
+        is_done: false,
+        set_date: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        title: series.title.trim().to_string(),
+        setter_key: String::from("self"),
+        setter_name: String::from("Recurring"),
+        id: task_id,
+        tags: vec![Tag::DueDate { date: due_date }],
+        occurrence: Some(Occurrence {
+            series_id,
+            at,
+            detached: false,
+        }),
+        ..Default::default()
+    }
+}
+
+/// Marks the local task `task_id` of `email` as done, or not, if it meets `expected`. Completing an
+/// occurrence of a series rolls the series on to its next occurrence, if it has none left open.
+pub fn complete(
+    db_conn: &mut PgConnection,
+    email: &str,
+    task_id: usize,
+    done: bool,
+    expected: Precondition,
+    now: DateTime<Utc>,
+) -> Result<(), SeriesError> {
+    let (recurs, _) = update_local_tasks_if(db_conn, email, Actor::User, expected, |loc_tasks| {
+        let task = loc_tasks.iter_mut().find(|task| task.id == task_id)?;
+        task.is_done = done;
+        Some(task.occurrence.is_some())
+    })?;
+    let recurs = recurs.ok_or(SeriesError::NotFound("no such task"))?;
+
+    if done && recurs {
+        generate(db_conn, email, now)?;
+    }
+    Ok(())
+}
+
+/// Changes the title and/or due date of the local task `task_id` of `email`, if it meets
+/// `expected`. An occurrence of a series edited this way is detached from it, so later edits to the
+/// series leave it alone.
+pub fn edit(
+    db_conn: &mut PgConnection,
+    email: &str,
+    task_id: usize,
+    new_title: Option<&str>,
+    new_due_date: Option<&str>,
+    expected: Precondition,
+) -> Result<(), SeriesError> {
+    let new_title = new_title
+        .map(validate_title)
+        .transpose()
+        .map_err(SeriesError::Invalid)?;
+    if new_due_date.is_some_and(|date| super::task::parse_date(date).is_none()) {
+        return Err(SeriesError::Invalid(String::from(
+            "due dates must be RFC 3339, or YYYY-MM-DD",
+        )));
+    }
+
+    update_local_tasks_if(db_conn, email, Actor::User, expected, |loc_tasks| {
+        let task = loc_tasks.iter_mut().find(|task| task.id == task_id)?;
+        if let Some(new_title) = new_title {
+            task.title = new_title.to_string();
+        }
+        if let Some(new_due_date) = new_due_date {
+            task.due_date = new_due_date.to_string();
+            for tag in &mut task.tags {
+                if let Tag::DueDate { date } = tag {
+                    *date = new_due_date.to_string();
+                }
+            }
+        }
+        if let Some(occurrence) = &mut task.occurrence {
+            occurrence.detached = true;
+        }
+        Some(())
+    })?
+    .0
+    .ok_or(SeriesError::NotFound("no such task"))
+}
+
+/// Generates occurrences for every user with a series, until shutdown.
+pub async fn run(db_conn: Pool<ConnectionManager<PgConnection>>, shutdown: Shutdown) {
+    let period = std::env::var("LANTERN_RECURRENCE_INTERVAL")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let span = tracing::info_span!("recurrence");
+                let res = async {
+                    use crate::schema::task_series::dsl::*;
+
+                    let mut db_conn = db_conn.get()?;
+                    let now = Utc::now();
+                    let emails = task_series
+                        .select(user_email)
+                        .distinct()
+                        .load::<String>(&mut db_conn)?;
+                    for email in emails {
+                        generate(&mut db_conn, &email, now)?;
+                    }
+                    color_eyre::Result::<()>::Ok(())
+                }
+                .instrument(span)
+                .await;
+                if let Err(e) = res {
+                    tracing::error!(error = %e, "failed to generate recurring tasks");
+                }
+            }
+            _ = shutdown.triggered() => break,
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Scrubs Firefly credentials out of anything that could end up in a log.
+//!
+//! The secret and device id are sent to Firefly as query parameters, so they are part of every
+//! request url, and in turn of every [`reqwest::Error`] and any message built from one.
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::sync::RwLock;
+use tracing_subscriber::fmt::MakeWriter;
+use url::Url;
+
+/// Query parameters whose values must never be logged.
+pub const SECRET_PARAMS: [&str; 3] = ["ffauth_secret", "ffauth_device_id", "device_id"];
+
+pub const REDACTED: &str = "REDACTED";
+
+/// Secrets that are known at runtime; scrubbed wherever they appear, not only in urls.
+static KNOWN_SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Registers `secret` so that [`redact`] scrubs it from any text it appears in.
+pub fn register(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    let mut secrets = KNOWN_SECRETS.write().unwrap();
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_owned());
+    }
+}
+
+/// Replaces the value of every secret query parameter in `url`.
+pub fn redact_url(url: &mut Url) {
+    if !url
+        .query_pairs()
+        .any(|(key, _)| SECRET_PARAMS.contains(&key.as_ref()))
+    {
+        return;
+    }
+
+    let pairs = url
+        .query_pairs()
+        .map(|(key, value)| {
+            let value = if SECRET_PARAMS.contains(&key.as_ref()) {
+                Cow::Borrowed(REDACTED)
+            } else {
+                value
+            };
+            (key.into_owned(), value.into_owned())
+        })
+        .collect::<Vec<_>>();
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+}
+
+/// Strips the secrets out of the url attached to `e`.
+pub fn redact_error(mut e: reqwest::Error) -> reqwest::Error {
+    if let Some(url) = e.url_mut() {
+        redact_url(url);
+    }
+    e
+}
+
+/// Scrubs secret query parameters and registered secrets from arbitrary text.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let mut text = Cow::Borrowed(text);
+
+    for param in SECRET_PARAMS {
+        if text.contains(param) {
+            text = Cow::Owned(redact_param(&text, param));
+        }
+    }
+
+    for secret in KNOWN_SECRETS.read().unwrap().iter() {
+        if text.contains(secret.as_str()) {
+            text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+        }
+    }
+    text
+}
+
+/// Replaces the value in every `param=value` in `text`.
+fn redact_param(text: &str, param: &str) -> String {
+    let needle = format!("{}=", param);
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(pos) = rest.find(&needle) {
+        let (before, after) = rest.split_at(pos);
+        out.push_str(before);
+        out.push_str(&needle);
+
+        let after = &after[needle.len()..];
+        // `device_id=` also appears inside `ffauth_device_id=`; only match whole names
+        let whole = !before
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        let end = after
+            .find(|c: char| c.is_whitespace() || "&#\"'<>),;\\".contains(c))
+            .unwrap_or(after.len());
+
+        if whole && end > 0 {
+            out.push_str(REDACTED);
+            rest = &after[end..];
+        } else {
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Replaces the panic hook with one that redacts the panic message before logging it.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.as_str()
+        } else {
+            "Box<dyn Any>"
+        };
+        let location = info.location().map(|l| l.to_string()).unwrap_or_default();
+
+        tracing::error!(
+            location,
+            backtrace = %std::backtrace::Backtrace::capture(),
+            "panicked: {}",
+            redact(msg)
+        );
+    }));
+}
+
+/// Wraps a [`MakeWriter`] so that everything written through it is passed through [`redact`].
+pub struct MakeRedacting<M>(pub M);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for MakeRedacting<M> {
+    type Writer = Redacting<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Redacting(self.0.make_writer())
+    }
+}
+
+pub struct Redacting<W>(W);
+
+impl<W: Write> Write for Redacting<W> {
+    // the fmt layer writes each event in one go, so there's no risk of a secret being split
+    // across two writes
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
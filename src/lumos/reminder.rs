@@ -0,0 +1,353 @@
+//! Reminders of tasks that are coming due.
+//!
+//! A user's reminder rules (e.g. "24h before, by webhook" and "2h before, by webhook") are
+//! expanded over their tasks into reminders, which are stored along with whether they were sent,
+//! so that a restart neither loses nor repeats any. The [dispatcher](run) does both on every tick:
+//! it brings the stored reminders in line with the tasks, then delivers those that are due.
+//!
+//! Like the calendar feed, reminders are worked out from the tasks stored in the database, so
+//! Firefly tasks are as fresh as the last unfiltered sync.
+use super::metrics;
+use super::shutdown::Shutdown;
+use super::task::AVTask;
+use super::user::utils::cached_tasks;
+use super::webhook;
+use crate::models::{NewReminderPG, NewReminderRulePG, ReminderPG, ReminderRulePG};
+use channel::{Channels, Reminder};
+
+use chrono::{DateTime, Duration as ChronoDuration, SubsecRound, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Instrument;
+
+pub mod channel;
+
+/// How often reminders are scheduled and delivered, unless `LANTERN_REMINDER_INTERVAL` says
+/// otherwise.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Deliveries of a reminder that may fail before it is given up on.
+const MAX_ATTEMPTS: i32 = 5;
+
+/// A reminder rule, as given by the user.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Rule {
+    pub minutes_before: i32,
+    pub channel: String,
+    pub target: Option<String>,
+}
+
+impl From<&ReminderRulePG> for Rule {
+    fn from(rule: &ReminderRulePG) -> Self {
+        Rule {
+            minutes_before: rule.minutes_before,
+            channel: rule.channel.clone(),
+            target: rule.target.clone(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RuleError {
+    #[error("invalid rule: {0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+/// Gets the reminder rules of `email`.
+pub fn rules(db_conn: &mut PgConnection, email: &str) -> QueryResult<Vec<ReminderRulePG>> {
+    use crate::schema::reminder_rules::dsl::*;
+
+    reminder_rules
+        .filter(user_email.eq(email))
+        .order((minutes_before.desc(), id))
+        .load(db_conn)
+}
+
+/// Replaces the reminder rules of `email` with `new_rules`.
+///
+/// Rules that are left unchanged keep the reminders that were already sent for them, so setting
+/// the same rules twice doesn't send anything twice.
+pub fn set_rules(
+    db_conn: &mut PgConnection,
+    email: &str,
+    new_rules: &[Rule],
+    channels: &Channels,
+) -> Result<Vec<ReminderRulePG>, RuleError> {
+    use crate::schema::reminder_rules::dsl::*;
+
+    for rule in new_rules {
+        if rule.minutes_before <= 0 {
+            return Err(RuleError::Invalid(String::from(
+                "reminders must be before the due date",
+            )));
+        }
+        let found = channels
+            .get(&rule.channel)
+            .ok_or_else(|| RuleError::Invalid(format!("unknown channel {}", rule.channel)))?;
+        found
+            .validate(rule.target.as_deref())
+            .map_err(RuleError::Invalid)?;
+    }
+
+    db_conn.transaction(|db_conn| {
+        let existing = rules(db_conn, email)?;
+        let removed = existing
+            .iter()
+            .filter(|old| !new_rules.contains(&Rule::from(*old)))
+            .map(|old| old.id)
+            .collect::<Vec<_>>();
+        diesel::delete(reminder_rules.filter(id.eq_any(removed))).execute(db_conn)?;
+
+        let kept = existing.iter().map(Rule::from).collect::<Vec<_>>();
+        let added = new_rules
+            .iter()
+            .filter(|rule| !kept.contains(rule))
+            .collect::<HashSet<_>>();
+        let added = added
+            .into_iter()
+            .map(|rule| NewReminderRulePG {
+                user_email: email,
+                minutes_before: rule.minutes_before,
+                channel: &rule.channel,
+                target: rule.target.as_deref(),
+            })
+            .collect::<Vec<_>>();
+        if !added.is_empty() {
+            diesel::insert_into(reminder_rules)
+                .values(&added)
+                .execute(db_conn)?;
+        }
+
+        Ok(rules(db_conn, email)?)
+    })
+}
+
+/// Brings the pending reminders of `email` in line with their rules and tasks.
+///
+/// Reminders are only scheduled for tasks that aren't done and are due after `now`. A reminder
+/// whose time has already passed by the time it is scheduled (e.g. for a task added an hour
+/// before it is due, under a "2h before" rule) goes out on the next delivery. Pending reminders
+/// of tasks that have since been done, removed or moved to another due date are dropped.
+pub fn schedule(db_conn: &mut PgConnection, email: &str, now: DateTime<Utc>) -> QueryResult<()> {
+    use crate::schema::reminders::dsl::*;
+
+    let user_rules = rules(db_conn, email)?;
+    let (local, firefly) = cached_tasks(db_conn, email)?;
+    let keyed = local
+        .iter()
+        .map(|task| (super::task::task_key("local", task.id), task))
+        .chain(
+            firefly
+                .iter()
+                .map(|task| (super::task::task_key("firefly", task.id), task)),
+        )
+        .filter_map(|(key, task)| Some((key, task, due_date(task, now)?)))
+        .collect::<Vec<_>>();
+
+    let wanted = user_rules
+        .iter()
+        .flat_map(|rule| {
+            keyed.iter().map(move |(key, task, due)| NewReminderPG {
+                rule_id: rule.id,
+                user_email: email,
+                task_key: key,
+                title: &task.title,
+                due_at: *due,
+                fire_at: *due - ChronoDuration::minutes(rule.minutes_before.into()),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    db_conn.transaction(|db_conn| {
+        if !wanted.is_empty() {
+            diesel::insert_into(reminders)
+                .values(&wanted)
+                .on_conflict((rule_id, task_key, due_at))
+                .do_nothing()
+                .execute(db_conn)?;
+        }
+
+        let wanted = wanted
+            .iter()
+            .map(|reminder| (reminder.rule_id, reminder.task_key, reminder.due_at))
+            .collect::<HashSet<_>>();
+        let stale = reminders
+            .filter(user_email.eq(email))
+            .filter(state.eq("pending"))
+            .load::<ReminderPG>(db_conn)?
+            .into_iter()
+            .filter(|reminder| {
+                !wanted.contains(&(
+                    reminder.rule_id,
+                    reminder.task_key.as_str(),
+                    reminder.due_at,
+                ))
+            })
+            .map(|reminder| reminder.id)
+            .collect::<Vec<_>>();
+        diesel::delete(reminders.filter(id.eq_any(stale))).execute(db_conn)?;
+        Ok(())
+    })
+}
+
+/// The due date of `task`, if it should be reminded of at all.
+///
+/// Truncated to the second, as postgres would otherwise round it and the stored reminder would no
+/// longer match its task.
+fn due_date(task: &AVTask, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if task.is_done {
+        return None;
+    }
+    task.due()
+        .map(|due| due.trunc_subsecs(0))
+        .filter(|due| *due > now)
+}
+
+/// Delivers every pending reminder whose time has come.
+///
+/// Reminders that only come up after their task was due (e.g. the server was down at the time)
+/// are skipped rather than sent late. No connection is held while a reminder is being delivered,
+/// and one that fails is retried with the same backoff as [webhooks](super::webhook).
+pub async fn dispatch(
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    channels: &Channels,
+    now: DateTime<Utc>,
+) -> color_eyre::Result<()> {
+    use crate::schema::reminder_rules;
+    use crate::schema::reminders::dsl::*;
+
+    let ready = {
+        let mut db_conn = db_pool.get()?;
+        let ready = reminders
+            .inner_join(reminder_rules::table)
+            .filter(state.eq("pending"))
+            .filter(fire_at.le(now))
+            .filter(next_attempt_at.le(now))
+            .order(fire_at)
+            .load::<(ReminderPG, ReminderRulePG)>(&mut db_conn)?;
+
+        let mut deliverable = vec![];
+        for (reminder, rule) in ready {
+            let target = reminders.find(reminder.id);
+            if reminder.due_at <= now {
+                diesel::update(target)
+                    .set(state.eq("skipped"))
+                    .execute(&mut db_conn)?;
+                metrics::REMINDERS
+                    .with_label_values(&[&rule.channel, "skipped"])
+                    .inc();
+                continue;
+            }
+            let Some(channel) = channels.get(&rule.channel) else {
+                tracing::warn!(channel = %rule.channel, "reminder rule has an unknown channel");
+                diesel::update(target)
+                    .set(state.eq("failed"))
+                    .execute(&mut db_conn)?;
+                continue;
+            };
+            deliverable.push((reminder, rule, channel));
+        }
+        deliverable
+    };
+
+    for (reminder, rule, channel) in ready {
+        let target = reminders.find(reminder.id);
+        let delivery = Reminder {
+            user_email: reminder.user_email,
+            task_key: reminder.task_key,
+            title: reminder.title,
+            due_at: reminder.due_at,
+            minutes_before: rule.minutes_before,
+            target: rule.target,
+        };
+        let delivered = channel.deliver(&delivery).await;
+
+        let mut db_conn = db_pool.get()?;
+        match delivered {
+            Ok(()) => {
+                diesel::update(target)
+                    .set((state.eq("sent"), sent_at.eq(Utc::now())))
+                    .execute(&mut db_conn)?;
+                metrics::REMINDERS
+                    .with_label_values(&[&rule.channel, "sent"])
+                    .inc();
+            }
+            Err(e) => {
+                let tried = reminder.attempts + 1;
+                let given_up = tried >= MAX_ATTEMPTS;
+                tracing::warn!(
+                    error = %e,
+                    channel = %rule.channel,
+                    task = %delivery.task_key,
+                    attempts = tried,
+                    given_up,
+                    "failed to deliver reminder"
+                );
+                diesel::update(target)
+                    .set((
+                        attempts.eq(tried),
+                        state.eq(if given_up { "failed" } else { "pending" }),
+                        next_attempt_at.eq(Utc::now() + webhook::backoff(tried)),
+                    ))
+                    .execute(&mut db_conn)?;
+                metrics::REMINDERS
+                    .with_label_values(&[&rule.channel, "failed"])
+                    .inc();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Schedules and delivers reminders for every user with rules, until shutdown.
+pub async fn run(
+    db_conn: Pool<ConnectionManager<PgConnection>>,
+    channels: Arc<Channels>,
+    shutdown: Shutdown,
+) {
+    let period = std::env::var("LANTERN_REMINDER_INTERVAL")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let span = tracing::info_span!("reminders");
+                if let Err(e) = tick(&db_conn, &channels).instrument(span).await {
+                    tracing::error!(error = %e, "failed to process reminders");
+                }
+            }
+            _ = shutdown.triggered() => break,
+        }
+    }
+}
+
+async fn tick(
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    channels: &Channels,
+) -> color_eyre::Result<()> {
+    use crate::schema::reminder_rules::dsl::*;
+
+    let now = Utc::now();
+    {
+        let mut db_conn = db_pool.get()?;
+        let emails = reminder_rules
+            .select(user_email)
+            .distinct()
+            .load::<String>(&mut db_conn)?;
+        for email in emails {
+            schedule(&mut db_conn, &email, now)?;
+        }
+    }
+    dispatch(db_pool, channels, now).await
+}
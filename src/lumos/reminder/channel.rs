@@ -0,0 +1,151 @@
+//! Ways of getting a reminder to the user.
+//!
+//! A [`Channel`] is looked up by the name a rule was created with, so adding one is a matter of
+//! implementing the trait and [registering](Channels::register) it.
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::future::BoxFuture;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How long a webhook gets to respond before the delivery is considered failed.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A reminder that is ready to be delivered.
+#[derive(Debug, Clone)]
+pub struct Reminder {
+    pub user_email: String,
+    /// `local:<id>` or `firefly:<id>`
+    pub task_key: String,
+    pub title: String,
+    pub due_at: DateTime<Utc>,
+    pub minutes_before: i32,
+    /// Where the rule said to deliver to, e.g. the url of a webhook.
+    pub target: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChannelError {
+    #[error("no target to deliver to")]
+    MissingTarget,
+
+    #[error(transparent)]
+    HTTP(#[from] reqwest::Error),
+}
+
+pub trait Channel: Send + Sync {
+    /// Checks that `target` is something this channel can deliver to, before a rule is stored.
+    fn validate(&self, target: Option<&str>) -> Result<(), String>;
+
+    fn deliver<'a>(&'a self, reminder: &'a Reminder) -> BoxFuture<'a, Result<(), ChannelError>>;
+}
+
+/// The channels reminders can be delivered through, by name.
+pub struct Channels {
+    channels: HashMap<&'static str, Box<dyn Channel>>,
+}
+
+impl Channels {
+    /// No channels at all; see [`Default`] for the built in ones.
+    pub fn empty() -> Self {
+        Channels {
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn register(mut self, name: &'static str, channel: impl Channel + 'static) -> Self {
+        self.channels.insert(name, Box::new(channel));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Channel> {
+        self.channels.get(name).map(|channel| channel.as_ref())
+    }
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Channels::empty()
+            .register("log", Log)
+            .register("webhook", Webhook::new())
+    }
+}
+
+/// Writes reminders to the log; mostly useful for trying rules out.
+pub struct Log;
+
+impl Channel for Log {
+    fn validate(&self, _target: Option<&str>) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn deliver<'a>(&'a self, reminder: &'a Reminder) -> BoxFuture<'a, Result<(), ChannelError>> {
+        tracing::info!(
+            user = %reminder.user_email,
+            task = %reminder.task_key,
+            minutes_before = reminder.minutes_before,
+            "reminder: {} is due at {}",
+            reminder.title,
+            reminder.due_at
+        );
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// POSTs reminders as JSON to the url the rule was created with. Any response other than a 2xx
+/// counts as a failed delivery, and is retried.
+pub struct Webhook {
+    http_client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new() -> Self {
+        Webhook {
+            http_client: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .unwrap(),
+        }
+    }
+}
+
+impl Default for Webhook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Channel for Webhook {
+    fn validate(&self, target: Option<&str>) -> Result<(), String> {
+        let target = target.ok_or_else(|| String::from("a webhook needs a url"))?;
+        match reqwest::Url::parse(target) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+            _ => Err(String::from("the webhook url must be an http(s) url")),
+        }
+    }
+
+    fn deliver<'a>(&'a self, reminder: &'a Reminder) -> BoxFuture<'a, Result<(), ChannelError>> {
+        Box::pin(async move {
+            let target = reminder
+                .target
+                .as_deref()
+                .ok_or(ChannelError::MissingTarget)?;
+            let body = json!({
+                "task": reminder.task_key,
+                "title": reminder.title,
+                "due_at": reminder.due_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "minutes_before": reminder.minutes_before,
+            });
+
+            // the url of a webhook may well carry a token of its own, so keep it out of errors
+            self.http_client
+                .post(target)
+                .json(&body)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|e| e.without_url())?;
+            Ok(())
+        })
+    }
+}
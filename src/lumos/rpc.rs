@@ -1,105 +1,1740 @@
 pub mod light {
     tonic::include_proto!("light");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("light_descriptor");
 }
-use super::task::AVTask;
-use super::user::User;
-use crate::models::TasksPG;
+use super::audit::{self, Actor};
+use super::changelog;
+use super::checklist::{self, ChecklistError};
+use super::custom_tag::{self, TagError};
+use super::dedup::{self, MergeError};
+use super::detail;
+use super::filter::{self, TaskFilter};
+use super::health::Readiness;
+use super::ics::{
+    self,
+    import::{self, ImportError},
+};
+use super::listing;
+use super::metrics;
+use super::priority;
+use super::recurrence::{self, Frequency, SeriesError};
+use super::reminder::{self, channel::Channels, RuleError};
+use super::shutdown::Shutdown;
+use super::source::{SourceError, Sources, Upload};
+use super::task::{parse_task_key, task_key, AVTask, AVTaskDetail, ORIGINS};
+use super::trash::{self, TrashError};
+use super::user::utils::{
+    find_task, from_client, update_firefly_task, update_local_tasks_if, Precondition, WriteError,
+};
+use super::webhook::{self, SubscriptionError};
+use crate::models::{
+    CustomTagPG, ReminderRulePG, TaskAuditPG, TaskChangePG, TaskDetailPG, TaskMergePG,
+    TaskSeriesPG, TasksPG, TrashedTaskPG, WebhookSubscriptionPG,
+};
 use crate::prelude::*;
 
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
 use color_eyre::Result;
 use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use futures_core::Stream;
+use futures_util::stream;
 use light::lantern_server::Lantern;
 pub use light::lantern_server::LanternServer;
-use light::{Filter, PTasks, StatusCode};
+use light::{
+    Attachment, AuditEntry, CalendarFeed, CalendarFile, ChangeBatch, ChangeResult, ChangeSet,
+    Checklist, ChecklistItem, ChecklistItemUpdate, ChecklistOrder, ClientChange, CustomTag,
+    CustomTags, DuplicateCandidate, DuplicateCandidates, FileChunk, Filter, ImportReport,
+    NewChecklistItem, PTasks, PriorityWeights, PushResult, ReminderRule, ReminderRules, StatusCode,
+    Submission, SubmitProgress, SyncRequest, TaskChange, TaskCompletion, TaskDetail,
+    TaskDetailRequest, TaskEdit, TaskEvent, TaskHistory, TaskHistoryRequest, TaskMerge, TaskMerges,
+    TaskRef, TaskSeries, TaskSeriesList, TaskTag, Trash, TrashedTask, Webhook, WebhookDeliveries,
+    WebhookDeliveriesRequest, WebhookDelivery, Webhooks,
+};
+use std::pin::Pin;
 use std::str::FromStr;
-use tokio::sync::Mutex;
-use tonic::{Code, Request, Response, Status};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::{Instrument, Span};
+use uuid::Uuid;
+
+/// Deliveries listed when the client doesn't say how many, and the most it may ask for.
+const DEFAULT_DELIVERIES: i64 = 50;
+const MAX_DELIVERIES: i64 = 500;
 
+#[derive(Clone)]
 pub struct TaskService {
-    inner: Mutex<User>,
+    sources: Sources,
+    email: String,
+    db_conn: Pool<ConnectionManager<PgConnection>>,
+    readiness: Readiness,
+    shutdown: Shutdown,
+    channels: Arc<Channels>,
 }
 
 #[tonic::async_trait]
 impl Lantern for TaskService {
     async fn get_tasks(&self, request: Request<Filter>) -> Result<Response<PTasks>, Status> {
+        let span = self.rpc_span("GetTasks", &request);
+        metrics::track_rpc("GetTasks", async {
+            let filter = request.get_ref();
+            let (listed, version, start, listing_id) = match filter.page_token.as_str() {
+                "" => {
+                    let (all_tasks, version) = self.list_tasks(filter).await?;
+                    (all_tasks, version, 0, None)
+                }
+                token => {
+                    let (listing_id, start) = listing::parse_token(token).ok_or_else(|| {
+                        Status::new(Code::InvalidArgument, "page token is malformed")
+                    })?;
+                    let mut db_conn = self.conn()?;
+                    let (listed, version) = listing::load(&mut db_conn, &self.email, listing_id)
+                        .map_err(db_error)?
+                        .ok_or_else(|| {
+                            Status::new(
+                                Code::InvalidArgument,
+                                "page token has expired; get the first page again",
+                            )
+                        })?;
+                    (listed, version, start, Some(listing_id.to_string()))
+                }
+            };
+            let total_count = listed.len() as u32;
+            let end = page_end(listed.len(), start, filter.page_size);
+
+            let next_page_token = if end < listed.len() {
+                let listing_id = match listing_id {
+                    Some(listing_id) => listing_id,
+                    None => {
+                        let mut db_conn = self.conn()?;
+                        listing::store(&mut db_conn, &self.email, &listed, version)
+                            .map_err(db_error)?
+                    }
+                };
+                listing::token(&listing_id, end)
+            } else {
+                String::new()
+            };
+            let page = &listed[start.min(end)..end];
+            Ok(Response::new(PTasks {
+                body: serde_json::to_string(page).unwrap(),
+                total_count,
+                next_page_token,
+                version,
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn add_tasks(&self, request: Request<PTasks>) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("AddTasks", &request);
+        metrics::track_rpc("AddTasks", async {
+            let new_tasks = serde_json::from_str::<Vec<AVTask>>(&request.get_ref().body)
+                .map_err(|_| Status::new(Code::InvalidArgument, "tasks are malformed"))?;
+            let expected = Some(request.get_ref().version).filter(|version| *version != 0);
+            let version = self.store_tasks(new_tasks, expected).await?;
+            let mut response = Response::new(StatusCode { success: true });
+            response
+                .metadata_mut()
+                .insert("etag", etag(version).parse().unwrap());
+            Ok(response)
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn create_calendar_feed(
+        &self,
+        request: Request<Filter>,
+    ) -> Result<Response<CalendarFeed>, Status> {
+        let span = self.rpc_span("CreateCalendarFeed", &request);
+        metrics::track_rpc("CreateCalendarFeed", async {
+            let mut db_conn = self.conn()?;
+            let token = ics::create_token(&mut db_conn, &self.email).map_err(db_error)?;
+            let url = ics::feed_url(&token, request.get_ref()).map_err(|e| {
+                tracing::error!(error = %e, "LANTERN_PUBLIC_URL is not a url");
+                Status::new(Code::Internal, "the feed url can't be made")
+            })?;
+            Ok(Response::new(CalendarFeed { url, token }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn revoke_calendar_feed(
+        &self,
+        request: Request<CalendarFeed>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("RevokeCalendarFeed", &request);
+        metrics::track_rpc("RevokeCalendarFeed", async {
+            let feed = request.get_ref();
+            let revoked = (!feed.token.is_empty()).then_some(feed.token.as_str());
+            let mut db_conn = self.conn()?;
+            let count = ics::revoke_token(&mut db_conn, &self.email, revoked).map_err(db_error)?;
+            if revoked.is_some() && count == 0 {
+                return Err(Status::new(Code::NotFound, "no such calendar feed"));
+            }
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn import_calendar(
+        &self,
+        request: Request<CalendarFile>,
+    ) -> Result<Response<ImportReport>, Status> {
+        let span = self.rpc_span("ImportCalendar", &request);
+        metrics::track_rpc("ImportCalendar", async {
+            let file = request.get_ref();
+            let contents = std::str::from_utf8(&file.contents)
+                .map_err(|_| Status::new(Code::InvalidArgument, "calendar is not utf-8"))?;
+
+            let mut db_conn = self.conn()?;
+            let report = match import::import(&mut db_conn, &self.email, contents) {
+                Ok(report) => report,
+                Err(ImportError::Malformed(e)) => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        format!("{} is malformed: {}", file.filename, e),
+                    ))
+                }
+                Err(ImportError::Database(e)) => return Err(db_error(e)),
+            };
+            tracing::info!(
+                imported = report.imported,
+                duplicates = report.duplicates,
+                errors = report.errors.len(),
+                "imported calendar"
+            );
+
+            Ok(Response::new(light::ImportReport {
+                imported: report.imported as u32,
+                duplicates: report.duplicates as u32,
+                errors: report
+                    .errors
+                    .into_iter()
+                    .map(|e| light::ImportError {
+                        index: e.index as u32,
+                        uid: e.uid.unwrap_or_default(),
+                        message: e.message,
+                    })
+                    .collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn get_reminder_rules(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<ReminderRules>, Status> {
+        let span = self.rpc_span("GetReminderRules", &request);
+        metrics::track_rpc("GetReminderRules", async {
+            let mut db_conn = self.conn()?;
+            let rules = reminder::rules(&mut db_conn, &self.email).map_err(db_error)?;
+            Ok(Response::new(to_reminder_rules(&rules)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn set_reminder_rules(
+        &self,
+        request: Request<ReminderRules>,
+    ) -> Result<Response<ReminderRules>, Status> {
+        let span = self.rpc_span("SetReminderRules", &request);
+        metrics::track_rpc("SetReminderRules", async {
+            #[allow(clippy::result_large_err)]
+            let new_rules = request
+                .get_ref()
+                .rules
+                .iter()
+                .map(|rule| {
+                    Ok(reminder::Rule {
+                        minutes_before: i32::try_from(rule.minutes_before).map_err(|_| {
+                            Status::new(Code::InvalidArgument, "minutes_before is too large")
+                        })?,
+                        channel: rule.channel.clone(),
+                        target: Some(rule.target.clone()).filter(|target| !target.is_empty()),
+                    })
+                })
+                .collect::<Result<Vec<_>, Status>>()?;
+
+            let mut db_conn = self.conn()?;
+            let rules =
+                match reminder::set_rules(&mut db_conn, &self.email, &new_rules, &self.channels) {
+                    Ok(rules) => rules,
+                    Err(RuleError::Invalid(e)) => {
+                        return Err(Status::new(Code::InvalidArgument, e))
+                    }
+                    Err(RuleError::Database(e)) => return Err(db_error(e)),
+                };
+            tracing::info!(rules = rules.len(), "set reminder rules");
+            Ok(Response::new(to_reminder_rules(&rules)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn create_webhook(&self, request: Request<Webhook>) -> Result<Response<Webhook>, Status> {
+        let span = self.rpc_span("CreateWebhook", &request);
+        metrics::track_rpc("CreateWebhook", async {
+            let hook = request.get_ref();
+            let secret = (!hook.secret.is_empty()).then_some(hook.secret.as_str());
+            let mut db_conn = self.conn()?;
+            let sub = match webhook::subscribe(
+                &mut db_conn,
+                &self.email,
+                &hook.url,
+                &hook.event_types,
+                secret,
+            ) {
+                Ok(sub) => sub,
+                Err(SubscriptionError::Invalid(e)) => {
+                    return Err(Status::new(Code::InvalidArgument, e))
+                }
+                Err(SubscriptionError::Database(e)) => return Err(db_error(e)),
+            };
+            tracing::info!(webhook = sub.id, "created webhook");
+
+            Ok(Response::new(Webhook {
+                secret: sub.secret.clone(),
+                ..to_webhook(&sub)
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn list_webhooks(&self, request: Request<()>) -> Result<Response<Webhooks>, Status> {
+        let span = self.rpc_span("ListWebhooks", &request);
+        metrics::track_rpc("ListWebhooks", async {
+            let mut db_conn = self.conn()?;
+            let subs = webhook::subscriptions(&mut db_conn, &self.email).map_err(db_error)?;
+            Ok(Response::new(Webhooks {
+                webhooks: subs.iter().map(to_webhook).collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn delete_webhook(
+        &self,
+        request: Request<Webhook>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("DeleteWebhook", &request);
+        metrics::track_rpc("DeleteWebhook", async {
+            let mut db_conn = self.conn()?;
+            let removed = webhook::unsubscribe(&mut db_conn, &self.email, request.get_ref().id)
+                .map_err(db_error)?;
+            if !removed {
+                return Err(Status::new(Code::NotFound, "no such webhook"));
+            }
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn list_webhook_deliveries(
+        &self,
+        request: Request<WebhookDeliveriesRequest>,
+    ) -> Result<Response<WebhookDeliveries>, Status> {
+        let span = self.rpc_span("ListWebhookDeliveries", &request);
+        metrics::track_rpc("ListWebhookDeliveries", async {
+            let req = request.get_ref();
+            let webhook_id = (req.webhook_id != 0).then_some(req.webhook_id);
+            let limit = match req.limit {
+                0 => DEFAULT_DELIVERIES,
+                limit => i64::from(limit).min(MAX_DELIVERIES),
+            };
+            let mut db_conn = self.conn()?;
+            let deliveries = webhook::deliveries(&mut db_conn, &self.email, webhook_id, limit)
+                .map_err(db_error)?;
+
+            let rfc3339 = |date: DateTime<Utc>| date.to_rfc3339_opts(SecondsFormat::Secs, true);
+            Ok(Response::new(WebhookDeliveries {
+                deliveries: deliveries
+                    .into_iter()
+                    .map(|delivery| WebhookDelivery {
+                        id: delivery.id,
+                        webhook_id: delivery.subscription_id,
+                        next_attempt_at: if delivery.state == "pending" {
+                            rfc3339(delivery.next_attempt_at)
+                        } else {
+                            String::new()
+                        },
+                        event_type: delivery.event_type,
+                        state: delivery.state,
+                        attempts: delivery.attempts as u32,
+                        last_status: delivery.last_status.unwrap_or(0) as u32,
+                        last_error: delivery.last_error.unwrap_or_default(),
+                        created_at: rfc3339(delivery.created_at),
+                        delivered_at: delivery.delivered_at.map(rfc3339).unwrap_or_default(),
+                        payload: delivery.payload.to_string(),
+                    })
+                    .collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn get_priority_weights(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<PriorityWeights>, Status> {
+        let span = self.rpc_span("GetPriorityWeights", &request);
+        metrics::track_rpc("GetPriorityWeights", async {
+            let mut db_conn = self.conn()?;
+            let weights = priority::weights(&mut db_conn, &self.email).map_err(db_error)?;
+            Ok(Response::new(weights.into()))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn set_priority_weights(
+        &self,
+        request: Request<PriorityWeights>,
+    ) -> Result<Response<PriorityWeights>, Status> {
+        let span = self.rpc_span("SetPriorityWeights", &request);
+        metrics::track_rpc("SetPriorityWeights", async {
+            let weights = priority::Weights::from(request.into_inner());
+            weights
+                .validate()
+                .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+            let mut db_conn = self.conn()?;
+            priority::set_weights(&mut db_conn, &self.email, &weights).map_err(db_error)?;
+            Ok(Response::new(weights.into()))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn create_tag(&self, request: Request<CustomTag>) -> Result<Response<CustomTag>, Status> {
+        let span = self.rpc_span("CreateTag", &request);
+        metrics::track_rpc("CreateTag", async {
+            let tag = request.get_ref();
+            let colour = (!tag.colour.is_empty()).then_some(tag.colour.as_str());
+            let mut db_conn = self.conn()?;
+            let created = custom_tag::create(&mut db_conn, &self.email, &tag.name, colour)
+                .map_err(tag_error)?;
+            Ok(Response::new(to_custom_tag(created)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn list_tags(&self, request: Request<()>) -> Result<Response<CustomTags>, Status> {
+        let span = self.rpc_span("ListTags", &request);
+        metrics::track_rpc("ListTags", async {
+            let mut db_conn = self.conn()?;
+            let tags = custom_tag::list(&mut db_conn, &self.email).map_err(db_error)?;
+            Ok(Response::new(CustomTags {
+                tags: tags.into_iter().map(to_custom_tag).collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn update_tag(&self, request: Request<CustomTag>) -> Result<Response<CustomTag>, Status> {
+        let span = self.rpc_span("UpdateTag", &request);
+        metrics::track_rpc("UpdateTag", async {
+            let tag = request.get_ref();
+            let name = (!tag.name.is_empty()).then_some(tag.name.as_str());
+            let colour = (!tag.colour.is_empty()).then_some(tag.colour.as_str());
+            let mut db_conn = self.conn()?;
+            let updated = custom_tag::update(&mut db_conn, &self.email, tag.id, name, colour)
+                .map_err(tag_error)?;
+            Ok(Response::new(to_custom_tag(updated)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn delete_tag(
+        &self,
+        request: Request<CustomTag>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("DeleteTag", &request);
+        metrics::track_rpc("DeleteTag", async {
+            let mut db_conn = self.conn()?;
+            custom_tag::delete(&mut db_conn, &self.email, request.get_ref().id)
+                .map_err(tag_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn tag_task(&self, request: Request<TaskTag>) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("TagTask", &request);
+        metrics::track_rpc("TagTask", async {
+            let tagging = request.get_ref();
+            let mut db_conn = self.conn()?;
+            custom_tag::attach(
+                &mut db_conn,
+                &self.email,
+                tagging.tag_id,
+                &tagging.origin,
+                tagging.task_id as usize,
+            )
+            .map_err(tag_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn untag_task(&self, request: Request<TaskTag>) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("UntagTask", &request);
+        metrics::track_rpc("UntagTask", async {
+            let tagging = request.get_ref();
+            let mut db_conn = self.conn()?;
+            custom_tag::detach(
+                &mut db_conn,
+                &self.email,
+                tagging.tag_id,
+                &tagging.origin,
+                tagging.task_id as usize,
+            )
+            .map_err(tag_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn add_checklist_item(
+        &self,
+        request: Request<NewChecklistItem>,
+    ) -> Result<Response<Checklist>, Status> {
+        let span = self.rpc_span("AddChecklistItem", &request);
+        metrics::track_rpc("AddChecklistItem", async {
+            let item = request.get_ref();
+            let at = item.position.checked_sub(1).map(|at| at as usize);
+            let mut db_conn = self.conn()?;
+            let list = checklist::add(
+                &mut db_conn,
+                &self.email,
+                &item.origin,
+                item.task_id as usize,
+                &item.title,
+                at,
+            )
+            .map_err(checklist_error)?;
+            Ok(Response::new(to_checklist(list)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn reorder_checklist(
+        &self,
+        request: Request<ChecklistOrder>,
+    ) -> Result<Response<Checklist>, Status> {
+        let span = self.rpc_span("ReorderChecklist", &request);
+        metrics::track_rpc("ReorderChecklist", async {
+            let order = request.get_ref();
+            let mut db_conn = self.conn()?;
+            let list = checklist::reorder(
+                &mut db_conn,
+                &self.email,
+                &order.origin,
+                order.task_id as usize,
+                &order.item_ids,
+            )
+            .map_err(checklist_error)?;
+            Ok(Response::new(to_checklist(list)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn complete_checklist_item(
+        &self,
+        request: Request<ChecklistItemUpdate>,
+    ) -> Result<Response<Checklist>, Status> {
+        let span = self.rpc_span("CompleteChecklistItem", &request);
+        metrics::track_rpc("CompleteChecklistItem", async {
+            let update = request.get_ref();
+            let mut db_conn = self.conn()?;
+            let list = checklist::set_done(&mut db_conn, &self.email, update.item_id, update.done)
+                .map_err(checklist_error)?;
+            Ok(Response::new(to_checklist(list)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn delete_checklist_item(
+        &self,
+        request: Request<ChecklistItemUpdate>,
+    ) -> Result<Response<Checklist>, Status> {
+        let span = self.rpc_span("DeleteChecklistItem", &request);
+        metrics::track_rpc("DeleteChecklistItem", async {
+            let mut db_conn = self.conn()?;
+            let list = checklist::delete(&mut db_conn, &self.email, request.get_ref().item_id)
+                .map_err(checklist_error)?;
+            Ok(Response::new(to_checklist(list)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn create_series(
+        &self,
+        request: Request<TaskSeries>,
+    ) -> Result<Response<TaskSeries>, Status> {
+        let span = self.rpc_span("CreateSeries", &request);
+        metrics::track_rpc("CreateSeries", async {
+            let series = recurrence::Series::try_from(request.get_ref())
+                .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+            let now = Utc::now();
+            let mut db_conn = self.conn()?;
+            let created = recurrence::create(&mut db_conn, &self.email, &series, now)
+                .map_err(series_error)?;
+            Ok(Response::new(to_task_series(&created, now)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn list_series(&self, request: Request<()>) -> Result<Response<TaskSeriesList>, Status> {
+        let span = self.rpc_span("ListSeries", &request);
+        metrics::track_rpc("ListSeries", async {
+            let now = Utc::now();
+            let mut db_conn = self.conn()?;
+            let all = recurrence::list(&mut db_conn, &self.email).map_err(db_error)?;
+            Ok(Response::new(TaskSeriesList {
+                series: all.iter().map(|row| to_task_series(row, now)).collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn update_series(
+        &self,
+        request: Request<TaskSeries>,
+    ) -> Result<Response<TaskSeries>, Status> {
+        let span = self.rpc_span("UpdateSeries", &request);
+        metrics::track_rpc("UpdateSeries", async {
+            let series = recurrence::Series::try_from(request.get_ref())
+                .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+            let now = Utc::now();
+            let mut db_conn = self.conn()?;
+            let updated = recurrence::update(
+                &mut db_conn,
+                &self.email,
+                request.get_ref().id,
+                &series,
+                now,
+            )
+            .map_err(series_error)?;
+            Ok(Response::new(to_task_series(&updated, now)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn delete_series(
+        &self,
+        request: Request<TaskSeries>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("DeleteSeries", &request);
+        metrics::track_rpc("DeleteSeries", async {
+            let mut db_conn = self.conn()?;
+            recurrence::delete(&mut db_conn, &self.email, request.get_ref().id, Utc::now())
+                .map_err(series_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn edit_task(&self, request: Request<TaskEdit>) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("EditTask", &request);
+        metrics::track_rpc("EditTask", async {
+            let edit = request.get_ref();
+            let mut db_conn = self.conn()?;
+            recurrence::edit(
+                &mut db_conn,
+                &self.email,
+                edit.task_id as usize,
+                Some(edit.title.as_str()).filter(|title| !title.is_empty()),
+                Some(edit.due_date.as_str()).filter(|date| !date.is_empty()),
+                task_precondition(edit.task_id, edit.version),
+            )
+            .map_err(series_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn complete_task(
+        &self,
+        request: Request<TaskCompletion>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("CompleteTask", &request);
+        metrics::track_rpc("CompleteTask", async {
+            let completion = request.get_ref();
+            if completion.origin != "local" {
+                let source = self.sources.get(&completion.origin).ok_or_else(|| {
+                    Status::new(
+                        Code::InvalidArgument,
+                        format!("no source named {}", completion.origin),
+                    )
+                })?;
+                let mut source = source.lock().await;
+                source
+                    .push_state(completion.task_id as usize, completion.done)
+                    .await
+                    .map_err(source_error)?;
+                return Ok(Response::new(StatusCode { success: true }));
+            }
+            let mut db_conn = self.conn()?;
+            recurrence::complete(
+                &mut db_conn,
+                &self.email,
+                completion.task_id as usize,
+                completion.done,
+                task_precondition(completion.task_id, completion.version),
+                Utc::now(),
+            )
+            .map_err(series_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn find_duplicates(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<DuplicateCandidates>, Status> {
+        let span = self.rpc_span("FindDuplicates", &request);
+        metrics::track_rpc("FindDuplicates", async {
+            let mut db_conn = self.conn()?;
+            let found = dedup::candidates(&mut db_conn, &self.email).map_err(db_error)?;
+            Ok(Response::new(DuplicateCandidates {
+                candidates: found
+                    .into_iter()
+                    .map(|candidate| DuplicateCandidate {
+                        task_keys: candidate.task_keys.into(),
+                        score: candidate.score,
+                    })
+                    .collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn merge_tasks(
+        &self,
+        request: Request<TaskMerge>,
+    ) -> Result<Response<TaskMerge>, Status> {
+        let span = self.rpc_span("MergeTasks", &request);
+        metrics::track_rpc("MergeTasks", async {
+            let mut db_conn = self.conn()?;
+            let merged = dedup::merge(&mut db_conn, &self.email, &request.get_ref().task_keys)
+                .map_err(merge_error)?;
+            Ok(Response::new(to_task_merge(merged)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn list_merges(&self, request: Request<()>) -> Result<Response<TaskMerges>, Status> {
+        let span = self.rpc_span("ListMerges", &request);
+        metrics::track_rpc("ListMerges", async {
+            let mut db_conn = self.conn()?;
+            let all = dedup::merges(&mut db_conn, &self.email).map_err(db_error)?;
+            Ok(Response::new(TaskMerges {
+                merges: all.into_iter().map(to_task_merge).collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn unmerge_tasks(
+        &self,
+        request: Request<TaskMerge>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("UnmergeTasks", &request);
+        metrics::track_rpc("UnmergeTasks", async {
+            let mut db_conn = self.conn()?;
+            dedup::unmerge(&mut db_conn, &self.email, request.get_ref().id).map_err(merge_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn sync_changes(
+        &self,
+        request: Request<SyncRequest>,
+    ) -> Result<Response<ChangeSet>, Status> {
+        let span = self.rpc_span("SyncChanges", &request);
+        metrics::track_rpc("SyncChanges", async {
+            let sync = request.get_ref();
+            if sync.since_seq < 0 {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "since_seq must not be negative",
+                ));
+            }
+            let limit = match sync.limit as usize {
+                0 => changelog::MAX_CHANGES,
+                limit => limit.min(changelog::MAX_CHANGES),
+            };
+            let mut db_conn = self.conn()?;
+            // one more than asked for, to tell whether there are more
+            let mut changes =
+                changelog::since(&mut db_conn, &self.email, sync.since_seq, limit as i64 + 1)
+                    .map_err(db_error)?;
+            let has_more = changes.len() > limit;
+            changes.truncate(limit);
+            Ok(Response::new(ChangeSet {
+                latest_seq: changes.last().map_or(sync.since_seq, |change| change.seq),
+                changes: changes.into_iter().map(to_task_change).collect(),
+                has_more,
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn push_changes(
+        &self,
+        request: Request<ChangeBatch>,
+    ) -> Result<Response<PushResult>, Status> {
+        let span = self.rpc_span("PushChanges", &request);
+        metrics::track_rpc("PushChanges", async {
+            let changes = &request.get_ref().changes;
+            if changes.len() > changelog::MAX_CHANGES {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!(
+                        "at most {} changes can be pushed at once",
+                        changelog::MAX_CHANGES
+                    ),
+                ));
+            }
+            let mutations = changes
+                .iter()
+                .map(to_mutation)
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut db_conn = self.conn()?;
+            let outcomes =
+                changelog::push(&mut db_conn, &self.email, mutations).map_err(db_error)?;
+            let task_json = |task: Option<AVTask>| {
+                task.map(|task| serde_json::to_string(&task).unwrap())
+                    .unwrap_or_default()
+            };
+            Ok(Response::new(PushResult {
+                results: outcomes
+                    .into_iter()
+                    .map(|outcome| match outcome {
+                        changelog::Outcome::Applied(task) => ChangeResult {
+                            applied: true,
+                            conflict: String::new(),
+                            task: task_json(task),
+                        },
+                        changelog::Outcome::Conflict { reason, task } => ChangeResult {
+                            applied: false,
+                            conflict: reason.to_string(),
+                            task: task_json(task),
+                        },
+                    })
+                    .collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn get_task_history(
+        &self,
+        request: Request<TaskHistoryRequest>,
+    ) -> Result<Response<TaskHistory>, Status> {
+        let span = self.rpc_span("GetTaskHistory", &request);
+        metrics::track_rpc("GetTaskHistory", async {
+            let query = request.get_ref();
+            if !ORIGINS.contains(&query.origin.as_str()) {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!("origin must be one of {}", ORIGINS.join(", ")),
+                ));
+            }
+            let limit = match query.limit {
+                0 => DEFAULT_HISTORY,
+                limit => limit.min(MAX_HISTORY),
+            };
+            let mut db_conn = self.conn()?;
+            let entries = audit::history(
+                &mut db_conn,
+                &self.email,
+                &task_key(&query.origin, query.task_id as usize),
+                Some(query.before_id).filter(|id| *id > 0),
+                limit as i64,
+            )
+            .map_err(db_error)?;
+            Ok(Response::new(TaskHistory {
+                entries: entries.into_iter().map(to_audit_entry).collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn delete_task(&self, request: Request<TaskRef>) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("DeleteTask", &request);
+        metrics::track_rpc("DeleteTask", async {
+            let task = request.get_ref();
+            let mut db_conn = self.conn()?;
+            trash::delete(
+                &mut db_conn,
+                &self.email,
+                &task.origin,
+                task.task_id as usize,
+            )
+            .map_err(trash_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn list_trash(&self, request: Request<()>) -> Result<Response<Trash>, Status> {
+        let span = self.rpc_span("ListTrash", &request);
+        metrics::track_rpc("ListTrash", async {
+            let mut db_conn = self.conn()?;
+            let rows = trash::list(&mut db_conn, &self.email).map_err(db_error)?;
+            let retention = trash::retention();
+            Ok(Response::new(Trash {
+                tasks: rows
+                    .into_iter()
+                    .filter_map(|row| to_trashed_task(row, retention))
+                    .collect(),
+            }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn restore_task(
+        &self,
+        request: Request<TaskRef>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("RestoreTask", &request);
+        metrics::track_rpc("RestoreTask", async {
+            let task = request.get_ref();
+            let mut db_conn = self.conn()?;
+            trash::restore(
+                &mut db_conn,
+                &self.email,
+                &task.origin,
+                task.task_id as usize,
+            )
+            .map_err(trash_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn empty_trash(&self, request: Request<()>) -> Result<Response<StatusCode>, Status> {
+        let span = self.rpc_span("EmptyTrash", &request);
+        metrics::track_rpc("EmptyTrash", async {
+            let mut db_conn = self.conn()?;
+            trash::purge(&mut db_conn, Some(&self.email), Utc::now()).map_err(db_error)?;
+            Ok(Response::new(StatusCode { success: true }))
+        })
+        .instrument(span)
+        .await
+    }
+
+    async fn get_task_detail(
+        &self,
+        request: Request<TaskDetailRequest>,
+    ) -> Result<Response<TaskDetail>, Status> {
+        let span = self.rpc_span("GetTaskDetail", &request);
+        metrics::track_rpc("GetTaskDetail", async {
+            let wanted = request.get_ref();
+            let source = self.sources.get(&wanted.origin).ok_or_else(|| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("no source named {}", wanted.origin),
+                )
+            })?;
+            let task_id = wanted.task_id as usize;
+            let key = task_key(&wanted.origin, task_id);
+
+            let cached = {
+                let mut db_conn = self.conn()?;
+                if find_task(&mut db_conn, &self.email, &wanted.origin, task_id)
+                    .map_err(db_error)?
+                    .is_none()
+                {
+                    return Err(Status::new(Code::NotFound, "no such task"));
+                }
+                detail::cached(&mut db_conn, &self.email, &key).map_err(db_error)?
+            };
+            if let Some(row) = cached
+                .as_ref()
+                .filter(|row| !wanted.refresh && detail::is_fresh(row, Utc::now()))
+            {
+                return Ok(Response::new(to_task_detail(&wanted.origin, task_id, row)));
+            }
+
+            let fetched = {
+                let mut source = source.lock().await;
+                match source.fetch_detail(task_id).await {
+                    Err(SourceError::Unauthenticated) => match source.authenticate().await {
+                        Ok(()) => source.fetch_detail(task_id).await,
+                        Err(e) => Err(e),
+                    },
+                    res => res,
+                }
+            };
+            let row = match (fetched, cached) {
+                (Ok(new_detail), _) => {
+                    let mut db_conn = self.conn()?;
+                    detail::store(&mut db_conn, &self.email, &key, &new_detail).map_err(db_error)?
+                }
+                (Err(e), Some(row)) => {
+                    tracing::warn!(error = %e, "serving cached task details");
+                    row
+                }
+                (Err(e), None) => return Err(source_error(e)),
+            };
+            Ok(Response::new(to_task_detail(&wanted.origin, task_id, &row)))
+        })
+        .instrument(span)
+        .await
+    }
+
+    type SubmitFileStream = Pin<Box<dyn Stream<Item = Result<SubmitProgress, Status>> + Send>>;
+
+    async fn submit_file(
+        &self,
+        request: Request<Streaming<FileChunk>>,
+    ) -> Result<Response<Self::SubmitFileStream>, Status> {
+        let span = self.rpc_span("SubmitFile", &request);
+        let chunks = request.into_inner();
+        let (progress, received) = mpsc::channel(PROGRESS_BUFFER);
+        let service = self.clone();
+
+        // the file is handed in by a task of its own, which reports on it through `progress`
+        self.shutdown.spawn(
+            async move {
+                let submitted = metrics::track_rpc("SubmitFile", async {
+                    service.hand_in(chunks, &progress).await
+                })
+                .await;
+                // the client may have gone, with nobody left to tell
+                let _ = progress.send(submitted).await;
+            }
+            .instrument(span),
+        );
+        let received = stream::unfold(received, |mut received| async move {
+            let next = received.recv().await?;
+            Some((next, received))
+        });
+        Ok(Response::new(Box::pin(received)))
+    }
+}
+
+impl TaskService {
+    /// Serves the user `email`, whose tasks come from `sources`.
+    pub fn new(
+        email: String,
+        sources: Sources,
+        db_conn: Pool<ConnectionManager<PgConnection>>,
+        shutdown: Shutdown,
+    ) -> Self {
+        TaskService {
+            email,
+            readiness: Readiness::new(db_conn.clone(), shutdown.clone()),
+            db_conn,
+            sources,
+            shutdown,
+            channels: Arc::new(Channels::default()),
+        }
+    }
+
+    /// Handle to the readiness of this service, for health checks.
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    /// Span that an rpc runs in, carrying the user, the method and a request id. The id is taken
+    /// from the `x-request-id` metadata if the client sent one.
+    fn rpc_span<T>(&self, method: &'static str, request: &Request<T>) -> Span {
+        let request_id = request
+            .metadata()
+            .get("x-request-id")
+            .and_then(|id| id.to_str().ok())
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        tracing::info_span!("rpc", method, user = %self.email, request_id = %request_id)
+    }
+
+    /// Gets a connection from the pool.
+    #[allow(clippy::result_large_err)]
+    fn conn(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>, Status> {
+        self.db_conn.get().map_err(|e| {
+            tracing::error!(error = %e, "failed to get a database connection");
+            Status::new(Code::Unavailable, "database is unavailable")
+        })
+    }
+
+    /// The channels reminder rules may deliver through, shared with the dispatcher.
+    pub fn reminder_channels(&self) -> Arc<Channels> {
+        self.channels.clone()
+    }
+
+    /// Receives the file `chunks` make up, reporting on `progress` as each chunk comes in, then
+    /// hands it in to the source of its task and marks the task as having a submission.
+    async fn hand_in(
+        &self,
+        mut chunks: Streaming<FileChunk>,
+        progress: &mpsc::Sender<Result<SubmitProgress, Status>>,
+    ) -> Result<SubmitProgress, Status> {
+        let first = chunks
+            .message()
+            .await?
+            .ok_or_else(|| Status::new(Code::InvalidArgument, "no file was sent"))?;
+        let source = self.sources.get(&first.origin).ok_or_else(|| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("no source named {}", first.origin),
+            )
+        })?;
+        if first.file_name.is_empty() {
+            return Err(Status::new(Code::InvalidArgument, "the file needs a name"));
+        }
+        if !first.mime_type.contains('/') {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "the mime type must look like `type/subtype`",
+            ));
+        }
+        let task_id = first.task_id as usize;
+        {
+            // checked before the rest of the file is received, so a wrong id fails fast
+            let mut db_conn = self.conn()?;
+            if find_task(&mut db_conn, &self.email, &first.origin, task_id)
+                .map_err(db_error)?
+                .is_none()
+            {
+                return Err(Status::new(Code::NotFound, "no such task"));
+            }
+        }
+
+        let mut data = first.data;
+        let mut received = 1;
+        loop {
+            // a client that falls behind misses reports rather than holding up the upload, as each
+            // supersedes the last
+            let _ = progress.try_send(Ok(SubmitProgress {
+                received: data.len() as u64,
+                chunks: received,
+                submission: None,
+            }));
+            let Some(chunk) = chunks.message().await? else {
+                break;
+            };
+            if data.len() + chunk.data.len() > MAX_SUBMISSION_BYTES {
+                return Err(Status::new(
+                    Code::ResourceExhausted,
+                    format!("files are limited to {} bytes", MAX_SUBMISSION_BYTES),
+                ));
+            }
+            data.extend(chunk.data);
+            received += 1;
+        }
+        if data.is_empty() {
+            return Err(Status::new(Code::InvalidArgument, "the file is empty"));
+        }
+
+        let upload = Upload {
+            file_name: first.file_name,
+            mime_type: first.mime_type,
+            data: data.into(),
+        };
+        let size = upload.data.len() as u64;
+        tracing::info!(bytes = size, chunks = received, "handing in the file");
+        // the source is only locked to set the submission up, and to authenticate again
+        let submission = source.lock().await.submit_file(task_id, upload.clone());
+        match submission.await {
+            Err(SourceError::Unauthenticated) => {
+                let retry = {
+                    let mut source = source.lock().await;
+                    source.authenticate().await.map_err(source_error)?;
+                    source.submit_file(task_id, upload.clone())
+                };
+                retry.await
+            }
+            res => res,
+        }
+        .map_err(source_error)?;
+
+        let mut db_conn = self.conn()?;
+        update_firefly_task(&mut db_conn, &self.email, Actor::User, task_id, |task| {
+            task.has_file_submission = true;
+        })
+        .map_err(db_error)?;
+        detail::forget(&mut db_conn, &self.email, &task_key(&first.origin, task_id))
+            .map_err(db_error)?;
+        Ok(SubmitProgress {
+            received: size,
+            chunks: received,
+            submission: Some(Submission {
+                origin: first.origin,
+                task_id: first.task_id,
+                file_name: upload.file_name,
+                size,
+                chunks: received,
+            }),
+        })
+    }
+
+    /// Gets every local task along with the tasks of each source that match `filter`, syncing
+    /// the latter with their sources in the process. Gives the version the local tasks are at with
+    /// them, for writes to be made conditional on.
+    pub async fn list_tasks(&self, filter: &Filter) -> Result<(Vec<AVTask>, i64), Status> {
         use crate::schema::tasks::dsl::*;
 
-        let filter = construct_filter(request.get_ref());
-        let mut user = self.inner.lock().await;
-        let mut db_conn = user.db_conn.get().unwrap();
+        let wanted_tags = filter.tags.clone();
+        let filter = construct_filter(filter);
+        let mut db_conn = self.conn()?;
         let mut all_tasks = vec![];
 
-        let loc_tasks = &tasks
-            .filter(user_email.eq(user.connection.email.clone()))
-            .load::<TasksPG>(&mut db_conn)
-            .expect("failed to get local tasks")[0];
-        let loc_tasks = serde_json::from_value::<Vec<AVTask>>(loc_tasks.local_tasks.clone());
+        let loc_tasks = tasks
+            .filter(user_email.eq(&self.email))
+            .first::<TasksPG>(&mut db_conn)
+            .optional()
+            .map_err(db_error)?
+            .ok_or_else(|| Status::new(Code::NotFound, "the user has no tasks"))?;
+        let local_version = loc_tasks.version;
+        let loc_tasks = serde_json::from_value::<Vec<AVTask>>(loc_tasks.local_tasks);
 
         match loc_tasks {
-            Ok(t) => all_tasks.extend(t),
+            Ok(t) => {
+                metrics::TASKS_STORED
+                    .with_label_values(&["local"])
+                    .set(t.len() as i64);
+                all_tasks.extend(t)
+            }
             Err(e) => {
-                eprintln!("failed while retrieving local tasks with {}", e);
+                tracing::error!(error = %e, "failed while retrieving local tasks");
                 return Err(Status::new(Code::Unknown, "failed to retrieve local tasks"));
             }
         }
-        match filter {
-            Ok(f) => user.get_ff_tasks(f).await.unwrap(),
-            _ => return Err(Status::new(Code::Unknown, "filter is malformed")),
+        let (filter, sorting) = match filter {
+            Ok(f) => f,
+            _ => return Err(Status::new(Code::InvalidArgument, "filter is malformed")),
+        };
+        drop(db_conn);
+
+        let started = Instant::now();
+        let synced = self
+            .sources
+            .fetch_all(Arc::new(filter), &self.shutdown)
+            .await;
+        let success = synced.iter().all(|(_, res)| res.is_ok());
+        metrics::SYNC_DURATION
+            .with_label_values(&[if success { "success" } else { "failure" }])
+            .observe(started.elapsed().as_secs_f64());
+        self.readiness.record_sync(success);
+
+        let mut by_origin = vec![("local", all_tasks)];
+        for (origin, res) in synced {
+            match res {
+                Ok(fetched) => by_origin.push((origin, fetched)),
+                Err(e) => {
+                    tracing::error!(error = %e, source = origin, "failed while syncing tasks");
+                    return Err(Status::new(
+                        Code::Unknown,
+                        format!("failed to retrieve {} tasks", origin),
+                    ));
+                }
+            }
+        }
+
+        let mut db_conn = self.conn()?;
+        let hidden = trash::hidden(&mut db_conn, &self.email).map_err(db_error)?;
+        for (origin, origin_tasks) in &mut by_origin {
+            origin_tasks.retain(|task| !hidden.contains(&task_key(origin, task.id)));
+            custom_tag::apply(&mut db_conn, &self.email, origin, origin_tasks)
+                .and_then(|_| checklist::apply(&mut db_conn, &self.email, origin, origin_tasks))
+                .map_err(db_error)?;
+        }
+        let merges = dedup::merges(&mut db_conn, &self.email).map_err(db_error)?;
+        let mut all_tasks = dedup::collapse(&merges, by_origin);
+        all_tasks.retain(|task| custom_tag::has_all(task, &wanted_tags));
+        let now = Utc::now();
+        let weights = priority::weights(&mut db_conn, &self.email).map_err(db_error)?;
+        priority::assign(&mut all_tasks, &weights, now);
+        match sorting {
+            (SortBy::Priority, order) => priority::sort(&mut all_tasks, &weights, now, order),
+            (by, order) => filter::sort(&mut all_tasks, by, order),
         }
+        tracing::debug!(count = all_tasks.len(), "retrieved tasks");
+        Ok((all_tasks, local_version))
+    }
 
-        all_tasks.extend(user.tasks.clone());
-        println!("{}", serde_json::to_string_pretty(&all_tasks).unwrap());
-        Ok(Response::new(PTasks {
-            body: serde_json::to_string(&all_tasks).unwrap(),
-        }))
+    /// Adds `new_tasks` to the user's local tasks, if they are still at the version `expected`
+    /// (when given). Gives the version they are at afterwards.
+    ///
+    /// Tasks with id 0 are numbered by lantern; any other id must be one no task has had.
+    pub async fn store_tasks(
+        &self,
+        new_tasks: Vec<AVTask>,
+        expected: Option<i64>,
+    ) -> Result<i64, Status> {
+        let mut db_conn = self.conn()?;
+        let expected = expected.map_or(Precondition::None, Precondition::Collection);
+        let (stored, version) = update_local_tasks_if(
+            &mut db_conn,
+            &self.email,
+            Actor::User,
+            expected,
+            |loc_tasks| {
+                let mut added = Vec::with_capacity(new_tasks.len());
+                for mut new_task in new_tasks {
+                    from_client(&mut new_task, None);
+                    if new_task.id == 0 {
+                        new_task.id = loc_tasks.next_id();
+                    } else if !loc_tasks.claim(new_task.id) {
+                        return Err(new_task.id);
+                    }
+                    added.push(new_task);
+                }
+                loc_tasks.splice(0..0, added);
+                Ok(loc_tasks.len())
+            },
+        )
+        .map_err(write_error)?;
+        let stored = stored.map_err(|taken| {
+            Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "a task has had the id {}; leave it 0 for one to be assigned",
+                    taken
+                ),
+            )
+        })?;
+
+        metrics::TASKS_STORED
+            .with_label_values(&["local"])
+            .set(stored as i64);
+        Ok(version)
     }
+}
 
-    async fn add_tasks(&self, request: Request<PTasks>) -> Result<Response<StatusCode>, Status> {
-        use crate::schema::tasks::dsl::*;
+/// Logs `e` and hides it from the client behind an internal error.
+fn db_error(e: diesel::result::Error) -> Status {
+    tracing::error!(error = %e, "database query failed");
+    Status::new(Code::Internal, "database query failed")
+}
+
+fn to_reminder_rules(rules: &[ReminderRulePG]) -> ReminderRules {
+    ReminderRules {
+        rules: rules
+            .iter()
+            .map(|rule| ReminderRule {
+                minutes_before: rule.minutes_before as u32,
+                channel: rule.channel.clone(),
+                target: rule.target.clone().unwrap_or_default(),
+            })
+            .collect(),
+    }
+}
+
+impl From<PriorityWeights> for priority::Weights {
+    fn from(weights: PriorityWeights) -> Self {
+        priority::Weights {
+            due_soon: weights.due_soon,
+            overdue: weights.overdue,
+            file_submission: weights.file_submission,
+            horizon_days: weights.horizon_days,
+            classes: weights.classes,
+            setters: weights.setters,
+        }
+    }
+}
 
-        let user = self.inner.lock().await;
-        let mut db_conn = user.db_conn.get().unwrap();
-        let loc_tasks = &tasks
-            .filter(user_email.eq(user.connection.email.clone()))
-            .load::<TasksPG>(&mut db_conn)
-            .expect("failed to get local tasks")[0];
-        let loc_tasks =
-            serde_json::from_value::<Vec<AVTask>>(loc_tasks.local_tasks.clone()).unwrap();
-        let mut all_tasks = serde_json::from_str::<Vec<AVTask>>(&request.get_ref().body).unwrap();
+impl From<priority::Weights> for PriorityWeights {
+    fn from(weights: priority::Weights) -> Self {
+        PriorityWeights {
+            due_soon: weights.due_soon,
+            overdue: weights.overdue,
+            file_submission: weights.file_submission,
+            horizon_days: weights.horizon_days,
+            classes: weights.classes,
+            setters: weights.setters,
+        }
+    }
+}
+
+impl TryFrom<&TaskSeries> for recurrence::Series {
+    type Error = String;
 
-        all_tasks.extend(loc_tasks);
+    fn try_from(series: &TaskSeries) -> Result<Self, Self::Error> {
+        let frequency = match series.frequency.as_str() {
+            "daily" => Frequency::Daily,
+            "weekly" => Frequency::Weekly {
+                weekdays: series
+                    .weekdays
+                    .iter()
+                    .map(|day| day.parse().map_err(|_| format!("{} is not a weekday", day)))
+                    .collect::<Result<_, _>>()?,
+            },
+            "every_n_days" => Frequency::EveryNDays {
+                days: series.interval_days,
+            },
+            "monthly" => Frequency::Monthly,
+            _ => {
+                return Err(String::from(
+                    "frequency must be one of daily, weekly, every_n_days, monthly",
+                ))
+            }
+        };
+        Ok(recurrence::Series {
+            title: series.title.clone(),
+            frequency,
+            timezone: series
+                .timezone
+                .parse()
+                .map_err(|_| format!("{} is not an IANA timezone", series.timezone))?,
+            starts_at: NaiveDateTime::parse_from_str(&series.starts_at, "%Y-%m-%dT%H:%M:%S")
+                .map_err(|_| String::from("starts_at must be of the form YYYY-MM-DDTHH:MM:SS"))?,
+            until: match series.until.as_str() {
+                "" => None,
+                until => Some(
+                    NaiveDate::parse_from_str(until, "%Y-%m-%d")
+                        .map_err(|_| String::from("until must be of the form YYYY-MM-DD"))?,
+                ),
+            },
+        })
+    }
+}
 
-        diesel::update(tasks)
-            .filter(user_email.eq(&user.connection.email))
-            .set(local_tasks.eq(serde_json::to_value(all_tasks).unwrap()))
-            .execute(&mut (user.db_conn.clone().get().unwrap()))
-            .unwrap();
+/// How many of the upcoming occurrences of a series are returned with it.
+const UPCOMING: usize = 5;
 
-        Ok(Response::new(StatusCode { success: true }))
+fn to_task_series(row: &TaskSeriesPG, now: DateTime<Utc>) -> TaskSeries {
+    let series = recurrence::Series::try_from(row);
+    let (frequency, weekdays, interval_days) = match series.as_ref().map(|series| &series.frequency)
+    {
+        Ok(Frequency::Daily) => ("daily", vec![], 0),
+        Ok(Frequency::Weekly { weekdays }) => (
+            "weekly",
+            weekdays.iter().map(|day| day.to_string()).collect(),
+            0,
+        ),
+        Ok(Frequency::EveryNDays { days }) => ("every_n_days", vec![], *days),
+        Ok(Frequency::Monthly) => ("monthly", vec![], 0),
+        Err(_) => ("", vec![], 0),
+    };
+    TaskSeries {
+        id: row.id,
+        title: row.title.clone(),
+        frequency: frequency.to_string(),
+        weekdays,
+        interval_days,
+        timezone: row.timezone.clone(),
+        starts_at: row.starts_at.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        until: row
+            .until
+            .map(|until| until.format("%Y-%m-%d").to_string())
+            .unwrap_or_default(),
+        upcoming: series
+            .map(|series| {
+                series
+                    .upcoming(now, UPCOMING)
+                    .iter()
+                    .map(|due| due.to_rfc3339_opts(SecondsFormat::Secs, true))
+                    .collect()
+            })
+            .unwrap_or_default(),
     }
 }
 
-impl TaskService {
-    pub async fn new() -> Self {
-        let user = User::attach("nlcssingapore", "avagarde", "sample@email.com")
-            .await
-            .unwrap();
-        TaskService {
-            inner: Mutex::new(user),
+fn source_error(e: SourceError) -> Status {
+    match e {
+        e @ SourceError::Unsupported(_) => Status::new(Code::Unimplemented, e.to_string()),
+        e => {
+            tracing::error!(error = %e, "task source failed");
+            Status::new(Code::Unavailable, "task source failed")
         }
     }
 }
 
+fn series_error(e: SeriesError) -> Status {
+    match e {
+        SeriesError::Invalid(e) => Status::new(Code::InvalidArgument, e),
+        SeriesError::NotFound(e) => Status::new(Code::NotFound, e),
+        SeriesError::Conflict(e) => Status::new(Code::FailedPrecondition, e),
+        SeriesError::Database(e) => db_error(e),
+    }
+}
+
+/// The version of the local tasks as an (HTTP) entity tag.
+pub fn etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+fn write_error(e: WriteError) -> Status {
+    match e {
+        e @ WriteError::Conflict(_) => Status::new(Code::FailedPrecondition, e.to_string()),
+        WriteError::Database(e) => db_error(e),
+    }
+}
+
+/// The precondition of a write to the local task `task_id`, which clients make conditional by
+/// giving the version of it they last read.
+fn task_precondition(task_id: u64, version: u64) -> Precondition {
+    match version {
+        0 => Precondition::None,
+        version => Precondition::Task {
+            id: task_id as usize,
+            version,
+        },
+    }
+}
+
+fn merge_error(e: MergeError) -> Status {
+    match e {
+        MergeError::Invalid(e) => Status::new(Code::InvalidArgument, e),
+        MergeError::NotFound(e) => Status::new(Code::NotFound, e),
+        MergeError::Database(e) => db_error(e),
+    }
+}
+
+fn to_task_merge(merge: TaskMergePG) -> TaskMerge {
+    TaskMerge {
+        id: merge.id,
+        task_keys: merge.task_keys,
+    }
+}
+
+fn tag_error(e: TagError) -> Status {
+    match e {
+        TagError::Invalid(e) => Status::new(Code::InvalidArgument, e),
+        e @ TagError::Exists(_) => Status::new(Code::AlreadyExists, e.to_string()),
+        TagError::NotFound(e) => Status::new(Code::NotFound, e),
+        TagError::Database(e) => db_error(e),
+    }
+}
+
+fn trash_error(e: TrashError) -> Status {
+    match e {
+        TrashError::Invalid(e) => Status::new(Code::InvalidArgument, e),
+        TrashError::NotFound(e) => Status::new(Code::NotFound, e),
+        e @ TrashError::Taken(_) => Status::new(Code::AlreadyExists, e.to_string()),
+        TrashError::Database(e) => db_error(e),
+    }
+}
+
+/// The cached details `row` of the task `task_id` of `origin`, as returned to clients.
+fn to_task_detail(origin: &str, task_id: usize, row: &TaskDetailPG) -> TaskDetail {
+    // details are only ever cached by lantern, so they are read leniently
+    let cached = serde_json::from_value::<AVTaskDetail>(row.detail.clone()).unwrap_or_default();
+    let to_attachment = |attachment: super::task::Attachment| Attachment {
+        name: attachment.name,
+        url: attachment.url,
+        mime_type: attachment.mime_type,
+        size: attachment.size,
+    };
+    let to_event = |event: super::task::TaskEvent| TaskEvent {
+        kind: event.kind,
+        author: event.author,
+        at: event.at,
+        message: event.message,
+        mark: event.mark.unwrap_or_default(),
+        files: event.files.into_iter().map(to_attachment).collect(),
+    };
+    TaskDetail {
+        origin: origin.to_string(),
+        task_id: task_id as u64,
+        description: cached.description,
+        description_contains_questions: cached.description_contains_questions,
+        attachments: cached.attachments.into_iter().map(to_attachment).collect(),
+        responses: cached.responses.into_iter().map(to_event).collect(),
+        history: cached.history.into_iter().map(to_event).collect(),
+        fetched_at: row.fetched_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+    }
+}
+
+/// `row` as returned to clients, unless its key can't be read.
+fn to_trashed_task(row: TrashedTaskPG, retention: chrono::Duration) -> Option<TrashedTask> {
+    let (origin, task_id) = parse_task_key(&row.task_key)?;
+    Some(TrashedTask {
+        origin: origin.to_string(),
+        task_id: task_id as u64,
+        task: row.task.map(|task| task.to_string()).unwrap_or_default(),
+        deleted_at: row.deleted_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        purged_at: (row.deleted_at + retention).to_rfc3339_opts(SecondsFormat::Secs, true),
+    })
+}
+
+/// The largest file that may be handed in. Each chunk must also fit in a gRPC message, 4 MiB by
+/// default.
+const MAX_SUBMISSION_BYTES: usize = 50 * 1024 * 1024;
+
+/// Progress reports of a submission that may wait for the client to read them, beyond which they
+/// are dropped.
+const PROGRESS_BUFFER: usize = 16;
+
+/// How many entries of a task's history are returned, unless asked for a number of them.
+const DEFAULT_HISTORY: u32 = 100;
+const MAX_HISTORY: u32 = 500;
+
+fn to_audit_entry(entry: TaskAuditPG) -> AuditEntry {
+    let json =
+        |value: Option<serde_json::Value>| value.map(|value| value.to_string()).unwrap_or_default();
+    AuditEntry {
+        id: entry.id,
+        action: entry.action,
+        actor: entry.actor,
+        fields: entry.fields,
+        old_value: json(entry.old_value),
+        new_value: json(entry.new_value),
+        recorded_at: entry.recorded_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+    }
+}
+
+fn to_task_change(change: TaskChangePG) -> TaskChange {
+    TaskChange {
+        seq: change.seq,
+        task_key: change.task_key,
+        kind: change.kind,
+        task: change.task.map(|task| task.to_string()).unwrap_or_default(),
+        changed_at: change.changed_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn to_mutation(change: &ClientChange) -> Result<changelog::Mutation, Status> {
+    let task = || {
+        serde_json::from_str::<AVTask>(&change.task)
+            .map_err(|_| Status::new(Code::InvalidArgument, "a pushed task is malformed"))
+    };
+    match change.kind.as_str() {
+        "create" => Ok(changelog::Mutation::Create(task()?)),
+        "update" => {
+            let mut task = task()?;
+            task.id = change.task_id as usize;
+            Ok(changelog::Mutation::Update {
+                task,
+                base_version: change.base_version,
+            })
+        }
+        "delete" => Ok(changelog::Mutation::Delete {
+            task_id: change.task_id as usize,
+            base_version: change.base_version,
+        }),
+        kind => Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "{} is not a kind of change; use create, update or delete",
+                kind
+            ),
+        )),
+    }
+}
+
+fn checklist_error(e: ChecklistError) -> Status {
+    match e {
+        ChecklistError::Invalid(e) => Status::new(Code::InvalidArgument, e),
+        ChecklistError::NotFound(e) => Status::new(Code::NotFound, e),
+        ChecklistError::Database(e) => db_error(e),
+    }
+}
+
+fn to_checklist(list: checklist::Checklist) -> Checklist {
+    let progress = list.progress().unwrap_or(0.0);
+    let (origin, task_id) = parse_task_key(&list.task_key).unwrap_or_default();
+    Checklist {
+        origin: origin.to_string(),
+        task_id: task_id as u64,
+        items: list
+            .items
+            .into_iter()
+            .map(|item| ChecklistItem {
+                id: item.id,
+                title: item.title,
+                is_done: item.is_done,
+            })
+            .collect(),
+        progress,
+    }
+}
+
+fn to_custom_tag(tag: CustomTagPG) -> CustomTag {
+    CustomTag {
+        id: tag.id,
+        name: tag.name,
+        colour: tag.colour,
+    }
+}
+
+/// A subscription as returned to the client, without its secret.
+fn to_webhook(sub: &WebhookSubscriptionPG) -> Webhook {
+    Webhook {
+        id: sub.id,
+        url: sub.url.clone(),
+        event_types: sub.event_types.clone(),
+        secret: String::new(),
+    }
+}
+
+/// The most tasks a page of `GetTasks` holds, however many are asked for.
+const MAX_PAGE_SIZE: u32 = 500;
+
+/// Where the page of `page_size` tasks starting at `start` ends, out of `count` tasks.
+///
+/// A `page_size` of 0 gives every task, as older clients expect.
+fn page_end(count: usize, start: usize, page_size: u32) -> usize {
+    let size = match page_size {
+        0 => count,
+        size => size.min(MAX_PAGE_SIZE) as usize,
+    };
+    start.saturating_add(size).min(count)
+}
+
+/// Reads the tasks `filter` asks for, and how it asks for them to be sorted.
 fn construct_filter(
     filter: &Filter,
-) -> Result<FFTaskFilter, Box<dyn std::error::Error + Send + Sync>> {
-    Ok(FFTaskFilter {
-        source: Some(Source::from_str(filter.source.as_str())?),
+) -> Result<(TaskFilter, (SortBy, SortOrder)), Box<dyn std::error::Error + Send + Sync>> {
+    let wanted = TaskFilter {
+        sources: std::iter::once(&filter.source)
+            .chain(&filter.sources)
+            .filter(|source| !source.is_empty())
+            .map(|source| Source::from(source.clone()))
+            .collect(),
         status: CompletionStatus::from_str(filter.status.as_str())?,
         read: ReadStatus::from_str(filter.read.as_str())?,
-        sorting: (
-            SortBy::from_str(filter.sort_by.as_str())?,
-            SortOrder::from_str(filter.sort_order.as_str())?,
-        ),
-    })
+    };
+    let sorting = (
+        SortBy::from_str(filter.sort_by.as_str())?,
+        SortOrder::from_str(filter.sort_order.as_str())?,
+    );
+    Ok((wanted, sorting))
 }
@@ -0,0 +1,81 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Coordinates graceful shutdown between the server and the work it spawns.
+///
+/// Anything spawned through [`Shutdown::spawn`] is tracked, so that when a signal arrives the
+/// process can wait for it to finish (within a deadline) instead of dropping it halfway through a
+/// Firefly sync or a write to the database.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawns a task that will be waited on by [`drain`](Shutdown::drain) before exiting.
+    ///
+    /// Unlike the future of an RPC, the task keeps running if the client hangs up; use this for
+    /// anything that must not be cut off midway (e.g. persisting tasks).
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tracker.spawn(task)
+    }
+
+    /// Starts the shutdown; everything waiting on [`triggered`](Shutdown::triggered) is woken.
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves once the shutdown has been started.
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Waits for all tracked tasks to finish. Returns `false` if the deadline passed first.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        self.tracker.close();
+        tokio::time::timeout(deadline, self.tracker.wait())
+            .await
+            .is_ok()
+    }
+}
+
+/// Resolves with the name of the signal once either Ctrl-C or SIGTERM is received.
+pub async fn signal() -> &'static str {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => "SIGINT",
+        _ = terminate => "SIGTERM",
+    }
+}
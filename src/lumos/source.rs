@@ -0,0 +1,180 @@
+//! Places tasks are pulled from, such as Firefly.
+//!
+//! A [`TaskSource`] knows how to authenticate with its service, fetch the user's tasks from it as
+//! [`AVTask`]s, and push changes to a task back. The service holds a [`Sources`] for the user,
+//! which syncs all of them at once, so adding an integration is a matter of implementing the trait
+//! and [registering](Sources::register) it, then [connecting](Sources::connect) to it from the
+//! user's [`Config`].
+use super::filter::TaskFilter;
+use super::shutdown::Shutdown;
+use super::task::{AVTask, AVTaskDetail};
+use firefly::Firefly;
+
+use bytes::Bytes;
+use color_eyre::eyre::Context;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use futures_util::future::{self, BoxFuture};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+pub mod firefly;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SourceError {
+    /// The credentials of the source were rejected; it should
+    /// [authenticate](TaskSource::authenticate) and try again.
+    #[error("the credentials were rejected")]
+    Unauthenticated,
+
+    #[error("{0} does not support this")]
+    Unsupported(&'static str),
+
+    #[error(transparent)]
+    Other(#[from] color_eyre::Report),
+}
+
+pub trait TaskSource: Send + Sync {
+    /// The origin tasks from this source are filed under, e.g. `firefly`.
+    fn origin(&self) -> &'static str;
+
+    /// Gets (new) credentials for the source.
+    fn authenticate(&mut self) -> BoxFuture<'_, Result<(), SourceError>>;
+
+    /// Gets the tasks that match `filter`.
+    fn fetch<'a>(
+        &'a mut self,
+        filter: &'a TaskFilter,
+    ) -> BoxFuture<'a, Result<Vec<AVTask>, SourceError>>;
+
+    /// Marks the task `task_id` as done, or not, at the source.
+    fn push_state(&mut self, task_id: usize, done: bool) -> BoxFuture<'_, Result<(), SourceError>>;
+
+    /// Gets what the source has on the task `task_id` beyond what it lists.
+    fn fetch_detail(&mut self, task_id: usize) -> BoxFuture<'_, Result<AVTaskDetail, SourceError>>;
+
+    /// Hands `upload` in for the task `task_id` at the source.
+    ///
+    /// The source is only borrowed to set the submission up, not while the file is being sent, so
+    /// that a large file doesn't hold up every other request to the source.
+    fn submit_file(
+        &mut self,
+        task_id: usize,
+        upload: Upload,
+    ) -> BoxFuture<'static, Result<(), SourceError>>;
+}
+
+/// A file to hand in for a task.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub file_name: String,
+    pub mime_type: String,
+    /// Cheap to clone, as a submission may have to be retried.
+    pub data: Bytes,
+}
+
+/// Where the tasks of a user come from, and what it takes to connect to each source.
+///
+/// lantern serves a single user, configured through the environment:
+/// - `LANTERN_USER_EMAIL`: the email of the user
+/// - `LANTERN_FIREFLY_SCHOOL`: the code Firefly knows the user's school by
+/// - `LANTERN_FIREFLY_APP_ID`: the app lantern signs in to Firefly as, `avagarde` unless set
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub email: String,
+    pub firefly: firefly::Config,
+}
+
+impl Config {
+    pub fn from_env() -> color_eyre::Result<Self> {
+        let var =
+            |name: &str| std::env::var(name).wrap_err_with(|| format!("{} must be set", name));
+        Ok(Config {
+            email: var("LANTERN_USER_EMAIL")?,
+            firefly: firefly::Config {
+                school_code: var("LANTERN_FIREFLY_SCHOOL")?,
+                app_id: var("LANTERN_FIREFLY_APP_ID")
+                    .unwrap_or_else(|_| String::from(firefly::DEFAULT_APP_ID)),
+            },
+        })
+    }
+}
+
+type Shared = Arc<Mutex<Box<dyn TaskSource>>>;
+
+/// The sources of a user, in the order their tasks are listed.
+#[derive(Clone, Default)]
+pub struct Sources {
+    sources: Vec<(&'static str, Shared)>,
+}
+
+impl Sources {
+    pub fn empty() -> Self {
+        Sources::default()
+    }
+
+    /// Connects to each source of the user `config` is for, storing what is synced through
+    /// `db_conn`.
+    pub async fn connect(
+        config: &Config,
+        db_conn: Pool<ConnectionManager<PgConnection>>,
+    ) -> color_eyre::Result<Self> {
+        let firefly = Firefly::connect(&config.firefly, &config.email, db_conn).await?;
+        Ok(Sources::empty().register(firefly))
+    }
+
+    pub fn register(mut self, source: impl TaskSource + 'static) -> Self {
+        let origin = source.origin();
+        self.sources
+            .push((origin, Arc::new(Mutex::new(Box::new(source)))));
+        self
+    }
+
+    pub fn origins(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.sources.iter().map(|(origin, _)| *origin)
+    }
+
+    pub fn get(&self, origin: &str) -> Option<Shared> {
+        self.sources
+            .iter()
+            .find(|(name, _)| *name == origin)
+            .map(|(_, source)| source.clone())
+    }
+
+    /// Fetches the tasks that match `filter` from every source at once, authenticating again
+    /// with any that rejects its credentials.
+    ///
+    /// Each fetch runs as a tracked task, so that it (and whatever the source writes at the end of
+    /// it) is finished even if the caller goes away or the server is shutting down.
+    pub async fn fetch_all(
+        &self,
+        filter: Arc<TaskFilter>,
+        shutdown: &Shutdown,
+    ) -> Vec<(&'static str, Result<Vec<AVTask>, SourceError>)> {
+        let syncs = self.sources.iter().map(|(origin, source)| {
+            let (source, filter) = (source.clone(), filter.clone());
+            let span = tracing::info_span!("sync", source = origin);
+            let sync = shutdown.spawn(
+                async move {
+                    let mut source = source.lock().await;
+                    match source.fetch(&filter).await {
+                        Err(SourceError::Unauthenticated) => {
+                            source.authenticate().await?;
+                            source.fetch(&filter).await
+                        }
+                        res => res,
+                    }
+                }
+                .instrument(span),
+            );
+            async move {
+                let res = sync.await.unwrap_or_else(|e| {
+                    Err(color_eyre::eyre::eyre!("sync task failed: {}", e).into())
+                });
+                (*origin, res)
+            }
+        });
+        future::join_all(syncs).await
+    }
+}
@@ -0,0 +1,110 @@
+//! Firefly, by way of the [`User`] that holds the connection to its API.
+use super::{SourceError, TaskSource, Upload};
+use crate::lumos::error::FireflyError;
+use crate::lumos::filter::{FFTaskFilter, TaskFilter};
+use crate::lumos::metrics;
+use crate::lumos::task::{AVTask, AVTaskDetail};
+use crate::lumos::user::{utils::auth, User};
+
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use futures_util::future::BoxFuture;
+
+/// The app lantern signs in to Firefly as, unless it is configured otherwise.
+pub const DEFAULT_APP_ID: &str = "avagarde";
+
+/// What it takes to connect to Firefly for a user.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The code Firefly knows the user's school by, which it finds the school's instance from.
+    pub school_code: String,
+    pub app_id: String,
+}
+
+pub struct Firefly {
+    user: User,
+}
+
+impl Firefly {
+    pub fn new(user: User) -> Self {
+        Firefly { user }
+    }
+
+    /// Signs in to Firefly as `email`, at the school `config` names.
+    pub async fn connect(
+        config: &Config,
+        email: &str,
+        db_conn: Pool<ConnectionManager<PgConnection>>,
+    ) -> color_eyre::Result<Self> {
+        let user = User::attach(db_conn, &config.school_code, &config.app_id, email).await?;
+        Ok(Firefly::new(user))
+    }
+}
+
+impl TaskSource for Firefly {
+    fn origin(&self) -> &'static str {
+        "firefly"
+    }
+
+    fn authenticate(&mut self) -> BoxFuture<'_, Result<(), SourceError>> {
+        Box::pin(async move {
+            metrics::FIREFLY_REAUTHS.inc();
+            auth(&mut self.user).await;
+            Ok(())
+        })
+    }
+
+    fn fetch<'a>(
+        &'a mut self,
+        filter: &'a TaskFilter,
+    ) -> BoxFuture<'a, Result<Vec<AVTask>, SourceError>> {
+        Box::pin(async move {
+            match self.user.get_ff_tasks(&FFTaskFilter::from(filter)).await {
+                Ok(()) => Ok(self.user.tasks.clone()),
+                Err(e) if matches!(e.downcast_ref(), Some(FireflyError::InvalidSecret)) => {
+                    Err(SourceError::Unauthenticated)
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    /// The Firefly API that lantern talks to has no way to mark a task as done or not; only
+    /// responses, such as [files](TaskSource::submit_file), can be sent to a task.
+    fn push_state(
+        &mut self,
+        _task_id: usize,
+        _done: bool,
+    ) -> BoxFuture<'_, Result<(), SourceError>> {
+        Box::pin(async { Err(SourceError::Unsupported("firefly")) })
+    }
+
+    fn fetch_detail(&mut self, task_id: usize) -> BoxFuture<'_, Result<AVTaskDetail, SourceError>> {
+        Box::pin(async move {
+            match self.user.get_ff_task_detail(task_id).await {
+                Ok(detail) => Ok(detail),
+                Err(e) if matches!(e.downcast_ref(), Some(FireflyError::InvalidSecret)) => {
+                    Err(SourceError::Unauthenticated)
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn submit_file(
+        &mut self,
+        task_id: usize,
+        upload: Upload,
+    ) -> BoxFuture<'static, Result<(), SourceError>> {
+        let submission = self.user.submit_ff_file(task_id, upload);
+        Box::pin(async move {
+            match submission.await {
+                Ok(()) => Ok(()),
+                Err(e) if matches!(e.downcast_ref(), Some(FireflyError::InvalidSecret)) => {
+                    Err(SourceError::Unauthenticated)
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+}
@@ -1,5 +1,7 @@
 use crate::lumos::filter::Source;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Response {
@@ -103,7 +105,7 @@ pub struct RawFFTask {
     pub title: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, ToSchema)]
 pub struct AVTask {
     pub due_date: String,
     pub is_done: bool,
@@ -113,9 +115,139 @@ pub struct AVTask {
     pub setter_name: String,
     pub id: usize,
     pub tags: Vec<Tag>,
+    /// Names of the classes the task was set for.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub classes: Vec<String>,
+    #[serde(default)]
+    pub file_submission_required: bool,
+    /// Whether a file has been handed in for the task; only ever set for Firefly tasks.
+    #[serde(default)]
+    pub has_file_submission: bool,
+    /// Whether the teacher has marked the task; only ever set for Firefly tasks.
+    #[serde(default)]
+    pub is_marked: bool,
+    /// Steps the task is broken into; see [`checklist`](super::checklist).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checklist: Vec<ChecklistItem>,
+    /// The fraction of the checklist that is done, if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<f64>,
+    /// The series the task is an occurrence of, if it recurs; see
+    /// [`recurrence`](super::recurrence).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub occurrence: Option<Occurrence>,
+    /// Keys of the tasks that were merged into this one as duplicates, its own first; see
+    /// [`dedup`](super::dedup).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged: Vec<String>,
+    /// Bumped whenever a local task changes, starting at 1; always 0 for Firefly tasks. Set by
+    /// lantern, so it is ignored when tasks are added.
+    #[serde(default)]
+    pub version: u64,
+    /// UID of the calendar entry the task was imported from, if it was imported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import_uid: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+impl AVTask {
+    /// The due date, if it is in a format that [`parse_date`] understands.
+    pub fn due(&self) -> Option<DateTime<Utc>> {
+        parse_date(&self.due_date)
+    }
+
+    /// The date the task was set, if it is in a format that [`parse_date`] understands.
+    pub fn set(&self) -> Option<DateTime<Utc>> {
+        parse_date(&self.set_date)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct ChecklistItem {
+    pub id: i32,
+    pub title: String,
+    pub is_done: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct Occurrence {
+    pub series_id: i32,
+    /// When the occurrence falls, in the local time of the series.
+    #[schema(value_type = String)]
+    pub at: NaiveDateTime,
+    /// Whether the task was edited by itself, which leaves it out of edits to the series.
+    #[serde(default)]
+    pub detached: bool,
+}
+
+/// What Firefly has on a task beyond what it lists; see [`detail`](super::detail).
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, ToSchema)]
+pub struct AVTaskDetail {
+    /// The description as markdown, stripped of anything unsafe.
+    pub description: String,
+    /// Whether the description asks questions to answer; they are part of the description.
+    pub description_contains_questions: bool,
+    pub attachments: Vec<Attachment>,
+    /// Comments, files, marks and requests to resubmit, from the student or the teacher, oldest
+    /// first.
+    pub responses: Vec<TaskEvent>,
+    /// Everything else that happened to the task, e.g. being set or marked as done, oldest first.
+    pub history: Vec<TaskEvent>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, ToSchema)]
+pub struct Attachment {
+    pub name: String,
+    pub url: String,
+    pub mime_type: String,
+    /// In bytes, 0 if Firefly didn't say.
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, ToSchema)]
+pub struct TaskEvent {
+    /// As Firefly names it, e.g. `comment` or `mark-as-done`.
+    pub kind: String,
+    pub author: String,
+    pub at: String,
+    /// Markdown, like the description.
+    pub message: String,
+    /// The mark out of the most it could be, if the event gave one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mark: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<Attachment>,
+}
+
+/// Where a task lives; local and Firefly tasks are numbered separately, so an id alone doesn't say
+/// which task it is.
+pub const ORIGINS: [&str; 2] = ["local", "firefly"];
+
+/// Identifies the task `id` of `origin` (`local` or `firefly`) among all of a user's tasks.
+pub fn task_key(origin: &str, id: usize) -> String {
+    format!("{}:{}", origin, id)
+}
+
+/// Splits a [`task_key`] back into its origin and id.
+pub fn parse_task_key(key: &str) -> Option<(&str, usize)> {
+    let (origin, id) = key.split_once(':')?;
+    Some((origin, id.parse().ok()?))
+}
+
+/// Parses the dates found in tasks: RFC 3339 from Firefly, or a plain date or naive date-time
+/// (taken to be UTC) for tasks added by the user.
+pub fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Some(date.and_utc());
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, ToSchema)]
 pub enum Tag {
     Source {
         source: String,
@@ -126,6 +258,12 @@ pub enum Tag {
     Priority {
         priority: String,
     },
+    /// A tag the user made; see [`custom_tag`](super::custom_tag).
+    Custom {
+        id: i32,
+        name: String,
+        colour: String,
+    },
     #[default]
     Error,
 }
@@ -189,3 +327,58 @@ pub struct Setter {
     #[serde(rename = "sortKey")]
     pub sort_key: Option<String>,
 }
+
+/// A task as Firefly gives it by itself, rather than in a listing.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawFFTaskDetail {
+    #[serde(rename = "description")]
+    pub description: Option<String>,
+
+    #[serde(rename = "descriptionContainsQuestions")]
+    pub description_contains_questions: Option<bool>,
+
+    #[serde(rename = "fileAttachments")]
+    pub file_attachments: Option<Vec<RawFFAttachment>>,
+
+    #[serde(rename = "responses")]
+    pub responses: Option<Vec<RawFFEvent>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawFFAttachment {
+    #[serde(rename = "fileName")]
+    pub file_name: Option<String>,
+
+    #[serde(rename = "fileSize")]
+    pub file_size: Option<u64>,
+
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
+
+    #[serde(rename = "resourceUrl")]
+    pub resource_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawFFEvent {
+    #[serde(rename = "author")]
+    pub author: Option<Setter>,
+
+    #[serde(rename = "eventType")]
+    pub event_type: Option<String>,
+
+    #[serde(rename = "files")]
+    pub files: Option<Vec<RawFFAttachment>>,
+
+    #[serde(rename = "mark")]
+    pub mark: Option<serde_json::Value>,
+
+    #[serde(rename = "message")]
+    pub message: Option<String>,
+
+    #[serde(rename = "outOf")]
+    pub out_of: Option<serde_json::Value>,
+
+    #[serde(rename = "sent")]
+    pub sent: Option<String>,
+}
@@ -0,0 +1,124 @@
+use super::redact::MakeRedacting;
+
+use color_eyre::Result;
+use diesel::connection::{Connection, Instrumentation, InstrumentationEvent};
+use diesel::r2d2::{self, CustomizeConnection};
+use diesel::PgConnection;
+use tracing::{field, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+/// Sets up the global tracing subscriber.
+///
+/// Logs are written to stdout, with secrets redacted, as JSON when `LANTERN_LOG_FORMAT=json`,
+/// filtered by `RUST_LOG` (defaults to `info`). With the `otlp` feature enabled, spans are also
+/// exported to the collector at `OTEL_EXPORTER_OTLP_ENDPOINT` when it is set.
+///
+/// The returned [`Guard`] flushes any spans that haven't been exported yet when dropped.
+pub fn init() -> Result<Guard> {
+    let fmt = if std::env::var("LANTERN_LOG_FORMAT").is_ok_and(|f| f == "json") {
+        tracing_subscriber::fmt::layer()
+            .with_writer(MakeRedacting(std::io::stdout))
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer()
+            .with_writer(MakeRedacting(std::io::stdout))
+            .boxed()
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let registry = tracing_subscriber::registry().with(fmt).with(filter);
+
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(otlp::layer()?);
+
+    registry.try_init()?;
+    Ok(Guard)
+}
+
+pub struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use color_eyre::Result;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    /// Exports spans to `OTEL_EXPORTER_OTLP_ENDPOINT` over gRPC, if set.
+    pub fn layer<S>() -> Result<Option<impl Layer<S>>>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let Ok(endpoint) = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") else {
+            return Ok(None);
+        };
+
+        let tracer =
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", "lantern"),
+                ])))
+                .install_batch(runtime::Tokio)?;
+
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+}
+
+/// Emits a span for every query made on a connection, as a child of whatever span is current
+/// when the query is started.
+///
+/// Only the SQL is recorded; bind parameters are left out as they can contain secrets.
+#[derive(Default)]
+pub struct QuerySpans {
+    current: Option<Span>,
+}
+
+impl Instrumentation for QuerySpans {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        match event {
+            InstrumentationEvent::StartQuery { query, .. } => {
+                let sql = query.to_string();
+                let sql = sql.split(" -- binds:").next().unwrap_or_default();
+                self.current = Some(tracing::info_span!(
+                    "db.query",
+                    db.statement = sql,
+                    error = field::Empty
+                ));
+            }
+            InstrumentationEvent::FinishQuery { error, .. } => {
+                if let (Some(span), Some(e)) = (self.current.take(), error) {
+                    span.record("error", field::display(e));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Installs [`QuerySpans`] on every connection handed out by a pool.
+#[derive(Debug)]
+pub struct TraceQueries;
+
+impl CustomizeConnection<PgConnection, r2d2::Error> for TraceQueries {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        conn.set_instrumentation(QuerySpans::default());
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+//! Deleted tasks, kept for a while so that they can be restored.
+//!
+//! Deleting a local task moves it out of the local tasks and into the trash. Firefly tasks can't
+//! be deleted from Firefly, so they are hidden instead: they stay in the cache, but are left out
+//! of everything lantern lists. Either way the trash keeps the task as it was, and restoring it
+//! puts it back (or unhides it). Hiding and unhiding are logged in the [`changelog`] and the
+//! [`audit`] log as the task being deleted and created, as that is how it looks to clients.
+//!
+//! Tasks are purged from the trash once they have been in it for the retention period
+//! (`LANTERN_TRASH_RETENTION_DAYS`, 30 days by default), or when the user empties it. Purged local
+//! tasks are gone for good; purged Firefly tasks stay hidden for good, as they can't be restored
+//! either.
+use super::audit::{self, Actor};
+use super::changelog;
+use super::shutdown::Shutdown;
+use super::task::{parse_task_key, AVTask, ORIGINS};
+use super::user::utils::{find_task, forget, update_local_tasks};
+use crate::models::{NewTrashedTaskPG, TrashedTaskPG};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::result::Error as DieselError;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tracing::Instrument;
+
+/// How often the trash is purged, unless `LANTERN_TRASH_PURGE_INTERVAL` says otherwise.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long tasks stay in the trash, unless `LANTERN_TRASH_RETENTION_DAYS` says otherwise.
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+/// The longest retention period `LANTERN_TRASH_RETENTION_DAYS` may set, about ten years.
+const MAX_RETENTION_DAYS: i64 = 3650;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrashError {
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("{0}")]
+    NotFound(&'static str),
+
+    /// A local task was added with the id of the one being restored.
+    #[error("a task with id {0} exists; delete it or restore this one later")]
+    Taken(usize),
+
+    #[error(transparent)]
+    Database(#[from] DieselError),
+}
+
+/// How long tasks stay in the trash before they are purged.
+///
+/// `LANTERN_TRASH_RETENTION_DAYS` must be between 1 and 3650 (about ten years); any other value
+/// is warned about and the default used instead, rather than purging everything straight away.
+pub fn retention() -> ChronoDuration {
+    let Ok(days) = std::env::var("LANTERN_TRASH_RETENTION_DAYS") else {
+        return ChronoDuration::days(DEFAULT_RETENTION_DAYS);
+    };
+    match days.parse::<i64>() {
+        Ok(days) if (1..=MAX_RETENTION_DAYS).contains(&days) => ChronoDuration::days(days),
+        _ => {
+            tracing::warn!(
+                value = %days,
+                default = DEFAULT_RETENTION_DAYS,
+                "LANTERN_TRASH_RETENTION_DAYS must be between 1 and {}; using the default",
+                MAX_RETENTION_DAYS
+            );
+            ChronoDuration::days(DEFAULT_RETENTION_DAYS)
+        }
+    }
+}
+
+fn check_origin(origin: &str) -> Result<(), TrashError> {
+    if !ORIGINS.contains(&origin) {
+        return Err(TrashError::Invalid(format!(
+            "origin must be one of {}",
+            ORIGINS.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Moves the task `task_id` of `origin` to the trash of `email`: local tasks are removed from the
+/// local tasks, Firefly tasks are hidden.
+pub fn delete(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    task_id: usize,
+) -> Result<TrashedTaskPG, TrashError> {
+    use crate::schema::trashed_tasks::dsl::*;
+
+    check_origin(origin)?;
+    let key = super::task::task_key(origin, task_id);
+    db_conn.transaction(|db_conn| {
+        let deleted = find_task(db_conn, email, origin, task_id)?
+            .ok_or(TrashError::NotFound("no such task"))?;
+
+        // in the trash before it leaves the local tasks, so what is kept by its key stays with it
+        let value = serde_json::to_value(&deleted).unwrap();
+        let trashed = diesel::insert_into(trashed_tasks)
+            .values(&NewTrashedTaskPG {
+                user_email: email,
+                task_key: &key,
+                task: value.clone(),
+            })
+            .on_conflict((user_email, task_key))
+            .do_update()
+            .set((
+                task.eq(value),
+                deleted_at.eq(Utc::now()),
+                purged_at.eq(None::<DateTime<Utc>>),
+            ))
+            .get_result(db_conn)?;
+        if origin == "local" {
+            update_local_tasks(db_conn, email, Actor::User, |loc_tasks| {
+                loc_tasks.retain(|loc_task| loc_task.id != task_id)
+            })?;
+        } else {
+            // hidden rather than removed, which the local tasks would have logged themselves
+            let hidden = [deleted];
+            changelog::record(db_conn, email, origin, &hidden, &[])?;
+            audit::record(db_conn, email, origin, Actor::User, &hidden, &[])?;
+        }
+        Ok(trashed)
+    })
+}
+
+/// Gets the tasks in the trash of `email`, most recently deleted first.
+pub fn list(db_conn: &mut PgConnection, email: &str) -> QueryResult<Vec<TrashedTaskPG>> {
+    use crate::schema::trashed_tasks::dsl::*;
+
+    trashed_tasks
+        .filter(user_email.eq(email))
+        .filter(purged_at.is_null())
+        .order((deleted_at.desc(), id.desc()))
+        .load(db_conn)
+}
+
+/// Takes the task `task_id` of `origin` out of the trash of `email`, putting a local task back
+/// among the local tasks (unless its id was taken since) and unhiding a Firefly one.
+pub fn restore(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    task_id: usize,
+) -> Result<(), TrashError> {
+    use crate::schema::trashed_tasks::dsl::*;
+
+    check_origin(origin)?;
+    let key = super::task::task_key(origin, task_id);
+    db_conn.transaction(|db_conn| {
+        let row = trashed_tasks
+            .filter(user_email.eq(email))
+            .filter(task_key.eq(&key))
+            .filter(purged_at.is_null())
+            .for_update()
+            .first::<TrashedTaskPG>(db_conn)
+            .optional()?
+            .ok_or(TrashError::NotFound("no such task in the trash"))?;
+
+        if origin == "local" {
+            let restored = row
+                .task
+                .and_then(|value| serde_json::from_value::<AVTask>(value).ok())
+                .ok_or(TrashError::NotFound("the task in the trash can't be read"))?;
+            update_local_tasks(db_conn, email, Actor::User, |loc_tasks| {
+                if loc_tasks.iter().any(|loc_task| loc_task.id == task_id) {
+                    return Err(TrashError::Taken(task_id));
+                }
+                loc_tasks.insert(0, restored);
+                Ok(())
+            })??;
+        }
+        diesel::delete(trashed_tasks.find(row.id)).execute(db_conn)?;
+        if origin != "local" {
+            // listed again only if the last sync still found it
+            if let Some(unhidden) = find_task(db_conn, email, origin, task_id)? {
+                let unhidden = [unhidden];
+                changelog::record(db_conn, email, origin, &[], &unhidden)?;
+                audit::record(db_conn, email, origin, Actor::User, &[], &unhidden)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Purges the trash of `email`, or of every user, of tasks deleted before `before`. Gives how many
+/// tasks were purged.
+///
+/// This can't be undone: purged local tasks are gone, and purged Firefly tasks stay hidden with no
+/// way to unhide them.
+pub fn purge(
+    db_conn: &mut PgConnection,
+    email: Option<&str>,
+    before: DateTime<Utc>,
+) -> QueryResult<usize> {
+    use crate::schema::trashed_tasks::dsl::*;
+
+    let due = |email: Option<&str>| {
+        let mut query = trashed_tasks
+            .filter(purged_at.is_null())
+            .filter(deleted_at.lt(before))
+            .into_boxed();
+        if let Some(email) = email {
+            query = query.filter(user_email.eq(email.to_string()));
+        }
+        query
+    };
+
+    db_conn.transaction(|db_conn| {
+        let rows = due(email).load::<TrashedTaskPG>(db_conn)?;
+        let (local, firefly): (Vec<_>, Vec<_>) = rows.iter().partition(|row| {
+            parse_task_key(&row.task_key).is_some_and(|(origin, _)| origin == "local")
+        });
+        diesel::delete(trashed_tasks.filter(id.eq_any(local.iter().map(|row| row.id))))
+            .execute(db_conn)?;
+        let mut by_user = HashMap::<&str, Vec<String>>::new();
+        for row in &local {
+            by_user
+                .entry(&row.user_email)
+                .or_default()
+                .push(row.task_key.clone());
+        }
+        for (owner, keys) in by_user {
+            forget(db_conn, owner, &keys)?;
+        }
+        diesel::update(trashed_tasks.filter(id.eq_any(firefly.iter().map(|row| row.id))))
+            .set((task.eq(None::<serde_json::Value>), purged_at.eq(Utc::now())))
+            .execute(db_conn)?;
+        Ok(rows.len())
+    })
+}
+
+/// Gets which of the tasks `keys` of `email` are in the trash, not counting those purged from it.
+pub fn in_trash(
+    db_conn: &mut PgConnection,
+    email: &str,
+    keys: &[String],
+) -> QueryResult<HashSet<String>> {
+    use crate::schema::trashed_tasks::dsl::*;
+
+    Ok(trashed_tasks
+        .filter(user_email.eq(email))
+        .filter(task_key.eq_any(keys))
+        .filter(purged_at.is_null())
+        .select(task_key)
+        .load::<String>(db_conn)?
+        .into_iter()
+        .collect())
+}
+
+/// Gets the keys of the Firefly tasks of `email` that are hidden, in the trash or purged from it.
+pub fn hidden(db_conn: &mut PgConnection, email: &str) -> QueryResult<HashSet<String>> {
+    use crate::schema::trashed_tasks::dsl::*;
+
+    Ok(trashed_tasks
+        .filter(user_email.eq(email))
+        .filter(task_key.like("firefly:%"))
+        .select(task_key)
+        .load::<String>(db_conn)?
+        .into_iter()
+        .collect())
+}
+
+/// Purges tasks that have been in the trash for longer than the [`retention`] period, until
+/// shutdown.
+pub async fn run(db_conn: Pool<ConnectionManager<PgConnection>>, shutdown: Shutdown) {
+    let period = std::env::var("LANTERN_TRASH_PURGE_INTERVAL")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let span = tracing::info_span!("trash");
+                let res = async {
+                    let mut db_conn = db_conn.get()?;
+                    let purged = purge(&mut db_conn, None, Utc::now() - retention())?;
+                    tracing::debug!(count = purged, "purged the trash");
+                    color_eyre::Result::<()>::Ok(())
+                }
+                .instrument(span)
+                .await;
+                if let Err(e) = res {
+                    tracing::error!(error = %e, "failed to purge the trash");
+                }
+            }
+            _ = shutdown.triggered() => break,
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use crate::lumos::{
+    detail,
     error::FireflyError,
-    filter::FFTaskFilter,
-    task::{AVTask, RawFFTask, Response},
+    filter::{FFTaskFilter, Source},
+    metrics, redact,
+    source::Upload,
+    task::{AVTask, AVTaskDetail, RawFFTask, RawFFTaskDetail, Response},
 };
 use crate::models::UserPG;
 use utils::*;
@@ -9,8 +12,9 @@ use utils::*;
 use color_eyre::{eyre::Context, Result};
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool};
-use dotenvy::dotenv;
-use reqwest::Client;
+use reqwest::{multipart, Client};
+use std::future::Future;
+use tracing::Instrument;
 use uuid::Uuid;
 
 pub mod utils;
@@ -33,17 +37,15 @@ pub struct Info {
 }
 
 impl User {
-    /// Instansiates a [`User`] that is the channel for commucation with Firefly.
-    pub async fn attach(school_code: &str, app_id: &str, user_email: &str) -> Result<User> {
+    /// Instansiates a [`User`] that is the channel for commucation with Firefly, storing what it
+    /// syncs through `pool`.
+    pub async fn attach(
+        pool: Pool<ConnectionManager<PgConnection>>,
+        school_code: &str,
+        app_id: &str,
+        user_email: &str,
+    ) -> Result<User> {
         use crate::schema::users::dsl::*; // imports useful aliases for diesel
-        dotenv().ok();
-
-        let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set!");
-        let manager = ConnectionManager::<PgConnection>::new(db_url);
-        let pool = Pool::builder()
-            .test_on_check_out(true)
-            .build(manager)
-            .wrap_err("Could not build connection pool")?;
 
         let mut user = User {
             connection: Info {
@@ -57,8 +59,8 @@ impl User {
 
         let portal = String::from("https://appgateway.fireflysolutions.co.uk/appgateway/school/");
         let url = reqwest::Url::parse(&(portal + school_code))?;
-        let res = user.http_client.get(url).send().await?.text().await?;
-        let res = parse_xml(res);
+        let res = send("school", user.http_client.get(url)).await?;
+        let res = parse_xml(res.text().await?);
 
         user.connection.http_endpoint = String::from("https://") + &res[1] + "/";
         user.connection.school_code = school_code.to_string();
@@ -77,6 +79,8 @@ impl User {
             user.connection.secret = data.firefly_secret.to_owned();
             user.connection.device_id = data.device_id.to_owned();
         }
+        redact::register(&user.connection.secret);
+        redact::register(&user.connection.device_id);
         user.connection.email = user_email.to_string();
         Ok(user)
     }
@@ -87,6 +91,10 @@ impl User {
     /// the tasks; sent in the body of the POST request. [`FFTaskFilter`] functions as an
     /// abstraction over this filter (which itself constructed in this function).
     ///
+    /// Fails with [`InvalidSecret`](crate::lumos::error::FireflyError::InvalidSecret) if Firefly
+    /// rejects the secret, which [`auth`] refreshes; the [source](crate::lumos::source::firefly)
+    /// takes care of that.
+    ///
     /// Multiple filters are needed (when more than 100 tasks are being requested) due to technical
     /// limiations with the API. See [`to_json`](FFTaskFilter::to_json) for more details.
     ///
@@ -94,7 +102,7 @@ impl User {
     ///
     /// # Examples
     /// DO THIS
-    pub async fn get_ff_tasks(&mut self, filter: FFTaskFilter) -> Result<()> {
+    pub async fn get_ff_tasks(&mut self, filter: &FFTaskFilter) -> Result<()> {
         // TODO: check last time since retrieved tasks:
         // if it has been shorter than a day, then just get the x most recent tasks (determined by
         // user setting) if it has be a month since the last fresh 'install', get it all in the background and
@@ -108,7 +116,7 @@ impl User {
             if let Some(tasks) = rawtask_to_task(items) {
                 tasks
             } else {
-                eprintln!("Error converting RawTask -> Task");
+                tracing::error!("failed converting RawTask -> Task");
                 vec![AVTask {
                     title: String::from("ERROR 102: RawTask -> Task failed!"),
                     ..Default::default()
@@ -126,59 +134,36 @@ impl User {
             params,
         )?;
         let mut items = vec![];
-        let res = filter.to_json(self.http_client.clone(), url.clone()).await;
+        let res = filter
+            .to_json(self.http_client.clone(), url.clone())
+            .await?;
         let mut handles;
 
-        let res = match res {
-            Ok(tuple) => tuple,
-            Err(e) => match e {
-                FireflyError::InvalidSecret => {
-                    auth(self).await;
-                    let params = [
-                        ("ffauth_device_id", &self.connection.device_id),
-                        ("ffauth_secret", &self.connection.secret),
-                    ];
-                    let url = reqwest::Url::parse_with_params(
-                        &(self.connection.http_endpoint.to_string()
-                            + "api/v2/taskListing/view/student/tasks/all/filterBy"),
-                        params,
-                    )?;
-
-                    filter
-                        .to_json(self.http_client.clone(), url)
-                        .await
-                        .wrap_err("failed while getting filter after refreshing secret")
-                        .unwrap() // HACK: can still crash :(
-                }
-                FireflyError::HTTP(e) => panic!("{e}"),
-                FireflyError::Misc(e) => panic!("{e}"), // HACK: remove panics
-            },
-        };
-
         match res {
             (Some(filters), Some(res)) => {
                 items.extend(res.items.unwrap());
                 handles = Vec::with_capacity(filters.len() + 1);
+                metrics::SYNC_PAGES.observe((filters.len() + 1) as f64);
 
                 for filter in filters {
                     let url = url.clone();
                     let client = self.http_client.clone();
-                    handles.push(tokio::spawn(async move {
-                        let res = client
-                            .post(url.clone())
-                            .json(&filter)
-                            .send()
-                            .await
-                            .unwrap()
-                            .text()
-                            .await
-                            .unwrap();
-
-                        serde_json::from_str::<Response>(&res)
-                            .unwrap()
-                            .items
-                            .unwrap()
-                    }));
+                    handles.push(tokio::spawn(
+                        async move {
+                            let res = send("taskListing", client.post(url.clone()).json(&filter))
+                                .await
+                                .unwrap()
+                                .text()
+                                .await
+                                .unwrap();
+
+                            serde_json::from_str::<Response>(&res)
+                                .unwrap()
+                                .items
+                                .unwrap()
+                        }
+                        .in_current_span(),
+                    ));
                 }
 
                 for handle in handles {
@@ -186,29 +171,125 @@ impl User {
                 }
             }
             (None, Some(res)) => {
+                metrics::SYNC_PAGES.observe(1.0);
                 items = res.items.unwrap();
             }
             _ => {}
         };
 
-        if let Some(ref source) = filter.source {
-            let parsed_items = items
-                .into_iter()
-                .filter(|item| {
-                    let curr_source = item.task_source.as_ref().unwrap();
-                    if source == curr_source {
-                        return true;
-                    }
-                    false
-                })
-                .collect::<Vec<RawFFTask>>();
-
-            self.tasks = standardise_ff_tasks(parsed_items);
-        } else {
-            self.tasks = standardise_ff_tasks(items);
+        // Firefly can't be asked for some sources alone (see `to_json`), so they are narrowed down
+        // here; tasks that came without a source can't be told to match one
+        if !filter.sources.is_empty() {
+            items.retain(|item| {
+                item.task_source
+                    .as_ref()
+                    .is_some_and(|source| filter.sources.contains(source))
+            });
         }
 
-        update_tasks_db(self);
+        // only a sync that saw every task may replace the cache, which webhooks, the changelog and
+        // the audit log are diffed against; a filtered one would make the tasks it left out look
+        // deleted, and new again once they are back
+        let complete = filter.is_complete();
+        if complete {
+            // sources lantern doesn't know of are counted together, to keep the labels few
+            let label = |item: &RawFFTask| match item.task_source {
+                Some(Source::Ff) => "FF",
+                Some(Source::Gc) => "GC",
+                Some(Source::Ms) => "MS",
+                Some(Source::Other(_)) | None => "other",
+            };
+            for source in ["FF", "GC", "MS", "other"] {
+                let stored = items.iter().filter(|item| label(item) == source).count();
+                metrics::TASKS_STORED
+                    .with_label_values(&[source])
+                    .set(stored as i64);
+            }
+        }
+
+        self.tasks = standardise_ff_tasks(items);
+        if complete {
+            update_tasks_db(self)?;
+        }
         Ok(())
     }
+
+    /// Gets the details of the task `task_id` from Firefly, which only come a task at a time.
+    ///
+    /// Fails with [`InvalidSecret`](crate::lumos::error::FireflyError::InvalidSecret) if Firefly
+    /// rejects the secret, like [`get_ff_tasks`](User::get_ff_tasks).
+    pub async fn get_ff_task_detail(&self, task_id: usize) -> Result<AVTaskDetail> {
+        let params = [
+            ("ffauth_device_id", &self.connection.device_id),
+            ("ffauth_secret", &self.connection.secret),
+        ];
+        let url = reqwest::Url::parse_with_params(
+            &format!(
+                "{}api/v2/apps/tasks/{}",
+                self.connection.http_endpoint, task_id
+            ),
+            params,
+        )?;
+        let res = send("taskDetail", self.http_client.get(url))
+            .await?
+            .text()
+            .await?;
+
+        if res == "Invalid token" {
+            return Err(FireflyError::InvalidSecret.into());
+        }
+        let raw = serde_json::from_str::<RawFFTaskDetail>(&res).map_err(|_| {
+            FireflyError::Misc(format!("malformed response, failed to parse: {}", res))
+        })?;
+        Ok(detail::from_raw(raw))
+    }
+
+    /// Hands `upload` in to Firefly as a response to the task `task_id`.
+    ///
+    /// The credentials are taken when this is called, so the user needn't be borrowed while the
+    /// file is sent. Fails with [`InvalidSecret`](crate::lumos::error::FireflyError::InvalidSecret)
+    /// if Firefly rejects the secret, like [`get_ff_tasks`](User::get_ff_tasks).
+    pub fn submit_ff_file(
+        &self,
+        task_id: usize,
+        upload: Upload,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let params = [
+            ("ffauth_device_id", &self.connection.device_id),
+            ("ffauth_secret", &self.connection.secret),
+        ];
+        let url = reqwest::Url::parse_with_params(
+            &format!(
+                "{}api/v2/apps/tasks/{}/responses",
+                self.connection.http_endpoint, task_id
+            ),
+            params,
+        );
+        let http_client = self.http_client.clone();
+
+        async move {
+            let size = upload.data.len() as u64;
+            let file = multipart::Part::stream_with_length(upload.data, size)
+                .file_name(upload.file_name)
+                .mime_str(&upload.mime_type)?;
+            let form = multipart::Form::new()
+                .text("eventType", "add-file")
+                .part("file", file);
+            let res = send("submitFile", http_client.post(url?).multipart(form)).await?;
+            let status = res.status();
+            let res = res.text().await?;
+
+            if res == "Invalid token" {
+                return Err(FireflyError::InvalidSecret.into());
+            }
+            if !status.is_success() {
+                return Err(FireflyError::Misc(format!(
+                    "the submission was refused with {}: {}",
+                    status, res
+                ))
+                .into());
+            }
+            Ok(())
+        }
+    }
 }
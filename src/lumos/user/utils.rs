@@ -1,12 +1,18 @@
 use super::{AVTask, RawFFTask, User};
-use crate::lumos::task::Tag;
-use crate::models::{NewTasksPG, NewUserPG};
+use crate::lumos::audit::{self, Actor};
+use crate::lumos::redact::{self, redact_error};
+use crate::lumos::task::{task_key, Tag};
+use crate::lumos::{changelog, checklist, custom_tag, dedup, event, metrics, trash, webhook};
+use crate::models::{NewTasksPG, NewUserPG, TasksPG};
 
 use diesel::prelude::*;
 use dotenvy::dotenv;
 use quick_xml::{events::Event, reader::Reader};
 use reqwest::header;
 use serde_json::json;
+use std::collections::HashSet;
+use std::ops::{Deref, DerefMut};
+use tracing::{field, Instrument};
 
 pub fn parse_xml(response: String) -> Vec<String> {
     let mut reader = Reader::from_str(response.as_str());
@@ -25,6 +31,33 @@ pub fn parse_xml(response: String) -> Vec<String> {
     txt
 }
 
+/// Sends a request to the Firefly `endpoint` in its own span, recording the outcome in metrics.
+///
+/// Any error returned has had the secrets stripped from its url.
+pub async fn send(
+    endpoint: &'static str,
+    request: reqwest::RequestBuilder,
+) -> reqwest::Result<reqwest::Response> {
+    let span = tracing::info_span!(
+        "firefly",
+        endpoint,
+        http.status_code = field::Empty,
+        error = field::Empty
+    );
+    let res = request
+        .send()
+        .instrument(span.clone())
+        .await
+        .map_err(redact_error);
+
+    metrics::firefly_request(endpoint, &res);
+    match &res {
+        Ok(res) => span.record("http.status_code", res.status().as_u16()),
+        Err(e) => span.record("error", field::display(e)),
+    };
+    res
+}
+
 pub async fn auth(instance: &mut User) {
     use crate::schema::users::dsl::*;
     dotenv().ok();
@@ -44,24 +77,24 @@ pub async fn auth(instance: &mut User) {
     )
     .unwrap();
 
-    let res = instance
-        .http_client
-        .get(url)
-        .header(
+    let res = send(
+        "gettoken",
+        instance.http_client.get(url).header(
             header::COOKIE,
             header::HeaderValue::from_str(&cookie).unwrap(),
             // HACK: need to make this not hard-coded
-        )
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
+        ),
+    )
+    .await
+    .unwrap()
+    .text()
+    .await
+    .unwrap();
 
     let txt = parse_xml(res);
     if let Some(secret) = txt.first() {
         if secret != "Invalid token" {
+            redact::register(secret);
             instance.connection.secret = secret.to_string();
             diesel::update(users)
                 .filter(email.eq(&instance.connection.email))
@@ -69,6 +102,7 @@ pub async fn auth(instance: &mut User) {
                 .execute(&mut db_conn)
                 .unwrap();
         } else {
+            tracing::error!("firefly rejected the session id while refreshing the secret");
             panic!("invalid sessionid")
         }
     };
@@ -92,24 +126,438 @@ pub fn add_user_to_db(instance: &mut User, new_email: &str) {
     let new_task_user_relation = NewTasksPG {
         user_email: new_email,
         firefly_tasks: json!({"empty": true}),
-        local_tasks: json!([{"due_date":"2023","is_done":false,"set_date":"2022","title":"finish this","id":100,"setter_key":"self","setter_name":"rohan","tags":[]}]),
+        local_tasks: json!([{"due_date":"2023","is_done":false,"set_date":"2022","title":"finish this","id":100,"setter_key":"self","setter_name":"rohan","tags":[],"version":1}]),
     };
     diesel::insert_into(tasks::table)
         .values(&new_task_user_relation)
         .execute(&mut db_conn)
         .expect("error create task-user relation");
+    let loc_tasks = serde_json::from_value::<Vec<AVTask>>(new_task_user_relation.local_tasks)
+        .expect("the first local tasks are malformed");
+    changelog::record(&mut db_conn, new_email, "local", &[], &loc_tasks)
+        .and_then(|_| {
+            audit::record(
+                &mut db_conn,
+                new_email,
+                "local",
+                Actor::System,
+                &[],
+                &loc_tasks,
+            )
+        })
+        .expect("error logging the first local tasks");
 }
 
-pub fn update_tasks_db(instance: &mut User) {
+/// Caches the Firefly tasks of `instance`, queueing webhooks for whatever changed since the last
+/// sync. Nothing is queued on the first sync, as everything would look new, though it is all
+/// logged as created in the [`changelog`] and [`audit`] log.
+///
+/// The tasks must be every task the user has at Firefly, as they are diffed against (and then
+/// replace) what was cached.
+pub fn update_tasks_db(instance: &mut User) -> QueryResult<()> {
     use crate::schema::tasks::dsl::*;
 
     let mut db_conn = instance.db_conn.clone().get().unwrap();
+    let email = &instance.connection.email;
 
-    diesel::update(tasks)
-        .filter(user_email.eq(&instance.connection.email))
-        .set(firefly_tasks.eq::<serde_json::Value>(serde_json::to_value(&instance.tasks).unwrap()))
-        .execute(&mut db_conn)
-        .unwrap();
+    db_conn.transaction(|db_conn| {
+        let previous = tasks
+            .filter(user_email.eq(email))
+            .select(firefly_tasks)
+            .for_update()
+            .first::<serde_json::Value>(db_conn)?;
+
+        diesel::update(tasks)
+            .filter(user_email.eq(email))
+            .set(
+                firefly_tasks
+                    .eq::<serde_json::Value>(serde_json::to_value(&instance.tasks).unwrap()),
+            )
+            .execute(db_conn)?;
+
+        if let Ok(previous) = serde_json::from_value::<Vec<AVTask>>(previous) {
+            let events = event::diff("firefly", &previous, &instance.tasks);
+            webhook::enqueue(db_conn, email, &events)?;
+            changelog::record(db_conn, email, "firefly", &previous, &instance.tasks)?;
+            audit::record(
+                db_conn,
+                email,
+                "firefly",
+                Actor::Sync,
+                &previous,
+                &instance.tasks,
+            )?;
+        } else {
+            changelog::record(db_conn, email, "firefly", &[], &instance.tasks)?;
+            audit::record(db_conn, email, "firefly", Actor::Sync, &[], &instance.tasks)?;
+        }
+        Ok(())
+    })
+}
+
+/// Runs `f` on the cached Firefly task `task_id` of `email`, then writes the tasks back, for
+/// changes lantern made at Firefly that shouldn't wait for the next sync to show. Gives what `f`
+/// returns, or `None` if the task isn't cached.
+pub fn update_firefly_task<R>(
+    db_conn: &mut PgConnection,
+    email: &str,
+    actor: Actor,
+    task_id: usize,
+    f: impl FnOnce(&mut AVTask) -> R,
+) -> QueryResult<Option<R>> {
+    use crate::schema::tasks::dsl::*;
+
+    db_conn.transaction(|db_conn| {
+        let previous = tasks
+            .filter(user_email.eq(email))
+            .select(firefly_tasks)
+            .for_update()
+            .first::<serde_json::Value>(db_conn)?;
+        let Ok(before) = serde_json::from_value::<Vec<AVTask>>(previous) else {
+            return Ok(None);
+        };
+        let mut after = before.clone();
+        let Some(task) = after.iter_mut().find(|task| task.id == task_id) else {
+            return Ok(None);
+        };
+        let res = f(task);
+        if after == before {
+            return Ok(Some(res));
+        }
+
+        diesel::update(tasks)
+            .filter(user_email.eq(email))
+            .set(firefly_tasks.eq(serde_json::to_value(&after).unwrap()))
+            .execute(db_conn)?;
+        webhook::enqueue(db_conn, email, &event::diff("firefly", &before, &after))?;
+        changelog::record(db_conn, email, "firefly", &before, &after)?;
+        audit::record(db_conn, email, "firefly", actor, &before, &after)?;
+        Ok(Some(res))
+    })
+}
+
+/// Gets the local tasks of `email`, along with the Firefly tasks cached at their last unfiltered
+/// sync, leaving out those the user [hid](trash).
+///
+/// Neither list is fetched from Firefly; anything that doesn't parse is treated as empty (the
+/// Firefly tasks are a placeholder object until the first unfiltered sync).
+pub fn cached_tasks(
+    db_conn: &mut PgConnection,
+    email: &str,
+) -> QueryResult<(Vec<AVTask>, Vec<AVTask>)> {
+    use crate::schema::tasks::dsl::*;
+
+    let row = tasks
+        .filter(user_email.eq(email))
+        .first::<TasksPG>(db_conn)?;
+    let parse = |value: serde_json::Value| {
+        serde_json::from_value::<Vec<AVTask>>(value).unwrap_or_else(|e| {
+            tracing::debug!(error = %e, "stored tasks are not a list of tasks");
+            vec![]
+        })
+    };
+
+    let hidden = trash::hidden(db_conn, email)?;
+    let mut ff_tasks = parse(row.firefly_tasks);
+    ff_tasks.retain(|task| !hidden.contains(&task_key("firefly", task.id)));
+    Ok((parse(row.local_tasks), ff_tasks))
+}
+
+/// Gets the task `id` of `origin` (`local` or `firefly`) from the tasks [cached](cached_tasks) for
+/// `email`. Unknown origins have no tasks.
+pub fn find_task(
+    db_conn: &mut PgConnection,
+    email: &str,
+    origin: &str,
+    id: usize,
+) -> QueryResult<Option<AVTask>> {
+    let (local, firefly) = cached_tasks(db_conn, email)?;
+    let found = match origin {
+        "local" => local,
+        "firefly" => firefly,
+        _ => return Ok(None),
+    };
+    Ok(found.into_iter().find(|task| task.id == id))
+}
+
+/// What a write to the local tasks expects to find, for clients to make their writes conditional
+/// on the tasks they last read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    /// Writes whatever the tasks are.
+    None,
+    /// The local tasks as a whole are at this version.
+    Collection(i64),
+    /// The task `id` is at `version`; a task that doesn't exist is left to the write to handle.
+    Task { id: usize, version: u64 },
+}
+
+impl Precondition {
+    /// Fails with [`WriteError::Conflict`] unless the local tasks, at `current` as a whole, meet
+    /// the precondition.
+    fn check(self, current: i64, loc_tasks: &[AVTask]) -> Result<(), WriteError> {
+        match self {
+            Precondition::Collection(expected) if expected != current => Err(WriteError::Conflict(
+                format!("the tasks are at version {}, not {}", current, expected),
+            )),
+            Precondition::Task {
+                id: task_id,
+                version: expected,
+            } => match loc_tasks.iter().find(|task| task.id == task_id) {
+                Some(task) if task.version != expected => Err(WriteError::Conflict(format!(
+                    "the task is at version {}, not {}",
+                    task.version, expected
+                ))),
+                _ => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WriteError {
+    /// The tasks changed since the client read them; it should read them again, reapply its change
+    /// and retry.
+    #[error("{0}; get the tasks again, reapply the change and retry")]
+    Conflict(String),
+
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+/// The local tasks of a user, as [`update_local_tasks`] hands them over to be changed.
+///
+/// Tasks that are added must be numbered by [`next_id`](LocalTasks::next_id), or
+/// [`claim`](LocalTasks::claim) an id of their own, so that no two tasks ever share an id: tags,
+/// checklists and the like are kept by [task key](task_key), and would pass to any task that got
+/// the id of one that was removed.
+#[derive(Debug)]
+pub struct LocalTasks {
+    tasks: Vec<AVTask>,
+    /// No local task has had this id, or any after it.
+    next_id: usize,
+}
+
+impl LocalTasks {
+    /// An id no local task has had, for a task that is being added.
+    pub fn next_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Takes `id` for a task that is being added, unless a local task has had it before.
+    pub fn claim(&mut self, id: usize) -> bool {
+        if id < self.next_id {
+            return false;
+        }
+        self.next_id = id + 1;
+        true
+    }
+}
+
+impl Deref for LocalTasks {
+    type Target = Vec<AVTask>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tasks
+    }
+}
+
+impl DerefMut for LocalTasks {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tasks
+    }
+}
+
+/// Runs `f` on the local tasks of `email`, then writes them back, recording `actor` as the one who
+/// changed them in the [`audit`] log.
+///
+/// The row is locked for the duration, so concurrent updates can't overwrite each other. Tasks
+/// that `f` removes for good (rather than to the [trash]) are [forgotten](forget).
+pub fn update_local_tasks<R>(
+    db_conn: &mut PgConnection,
+    email: &str,
+    actor: Actor,
+    f: impl FnOnce(&mut LocalTasks) -> R,
+) -> QueryResult<R> {
+    write_local_tasks(db_conn, email, actor, |_, _| Ok(()), f).map(|(res, _)| res)
+}
+
+/// Like [`update_local_tasks`], but fails with [`WriteError::Conflict`] (without running `f`)
+/// unless the tasks meet `expected`. Gives the version the tasks are at afterwards along with what
+/// `f` returns.
+///
+/// Every task that `f` adds or changes has its version bumped, as do the tasks as a whole if any
+/// did; versions set by `f` itself are ignored.
+pub fn update_local_tasks_if<R>(
+    db_conn: &mut PgConnection,
+    email: &str,
+    actor: Actor,
+    expected: Precondition,
+    f: impl FnOnce(&mut LocalTasks) -> R,
+) -> Result<(R, i64), WriteError> {
+    write_local_tasks(
+        db_conn,
+        email,
+        actor,
+        |current, loc_tasks| expected.check(current, loc_tasks),
+        f,
+    )
+}
+
+/// Does the writing for [`update_local_tasks`] and [`update_local_tasks_if`], running `check` on
+/// the version and tasks found before `f`, which is only run if `check` passes.
+fn write_local_tasks<R, E: From<diesel::result::Error>>(
+    db_conn: &mut PgConnection,
+    email: &str,
+    actor: Actor,
+    check: impl FnOnce(i64, &[AVTask]) -> Result<(), E>,
+    f: impl FnOnce(&mut LocalTasks) -> R,
+) -> Result<(R, i64), E> {
+    use crate::schema::tasks::dsl::*;
+
+    db_conn.transaction(|db_conn| {
+        let row = tasks
+            .filter(user_email.eq(email))
+            .for_update()
+            .first::<TasksPG>(db_conn)?;
+        let loc_tasks = serde_json::from_value::<Vec<AVTask>>(row.local_tasks)
+            .map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))?;
+
+        check(row.version, &loc_tasks)?;
+
+        let before = loc_tasks.clone();
+        let mut loc_tasks = LocalTasks {
+            next_id: next_free_id(row.next_local_id, &loc_tasks),
+            tasks: loc_tasks,
+        };
+        let res = f(&mut loc_tasks);
+        let next_id = next_free_id(loc_tasks.next_id as i64, &loc_tasks);
+        let mut loc_tasks = loc_tasks.tasks;
+        for task in &mut loc_tasks {
+            let stored = before.iter().find(|stored| stored.id == task.id);
+            strip_computed(task, stored);
+        }
+        if !bump_versions(&before, &mut loc_tasks) {
+            return Ok((res, row.version));
+        }
+
+        let new_version = diesel::update(tasks)
+            .filter(user_email.eq(email))
+            .set((
+                local_tasks.eq(serde_json::to_value(&loc_tasks).unwrap()),
+                version.eq(version + 1),
+                next_local_id.eq(next_id as i64),
+            ))
+            .returning(version)
+            .get_result(db_conn)?;
+        forget_removed(db_conn, email, &before, &loc_tasks)?;
+        webhook::enqueue(db_conn, email, &event::diff("local", &before, &loc_tasks))?;
+        changelog::record(db_conn, email, "local", &before, &loc_tasks)?;
+        audit::record(db_conn, email, "local", actor, &before, &loc_tasks)?;
+        Ok((res, new_version))
+    })
+}
+
+/// Takes off `task` what is only worked out when tasks are read: its checklist and progress, the
+/// merge it is in, and its priority and custom tags, all of which are kept elsewhere. A task read
+/// as the first of a merge may also be done only because another task of it is, so it keeps the
+/// done state of `stored`, the task as it was before.
+pub fn strip_computed(task: &mut AVTask, stored: Option<&AVTask>) {
+    if !task.merged.is_empty() {
+        if let Some(stored) = stored {
+            task.is_done = stored.is_done;
+        }
+    }
+    task.checklist.clear();
+    task.progress = None;
+    task.merged.clear();
+    task.tags
+        .retain(|tag| !matches!(tag, Tag::Priority { .. } | Tag::Custom { .. }));
+}
+
+/// Readies `task`, as a client pushed it, to replace `stored` (or to be added, if `None`): on top
+/// of [stripping](strip_computed) it, the series and calendar entry it came from are lantern's to
+/// set, so they are kept as they were.
+pub fn from_client(task: &mut AVTask, stored: Option<&AVTask>) {
+    strip_computed(task, stored);
+    task.occurrence = stored.and_then(|stored| stored.occurrence.clone());
+    task.import_uid = stored.and_then(|stored| stored.import_uid.clone());
+}
+
+/// The lowest id past both `stored` and every id in `loc_tasks`; never 0, which marks a task that
+/// is yet to be numbered.
+fn next_free_id(stored: i64, loc_tasks: &[AVTask]) -> usize {
+    loc_tasks
+        .iter()
+        .map(|task| task.id + 1)
+        .chain([stored.max(1) as usize])
+        .max()
+        .unwrap_or(1)
+}
+
+/// [Forgets](forget) the tasks of `before` that are missing from `after`, unless they went to the
+/// trash, which keeps them for when they are restored.
+fn forget_removed(
+    db_conn: &mut PgConnection,
+    email: &str,
+    before: &[AVTask],
+    after: &[AVTask],
+) -> QueryResult<()> {
+    let kept = after.iter().map(|task| task.id).collect::<HashSet<_>>();
+    let removed = before
+        .iter()
+        .filter(|task| !kept.contains(&task.id))
+        .map(|task| task_key("local", task.id))
+        .collect::<Vec<_>>();
+    if removed.is_empty() {
+        return Ok(());
+    }
+    let trashed = trash::in_trash(db_conn, email, &removed)?;
+    let gone = removed
+        .into_iter()
+        .filter(|key| !trashed.contains(key))
+        .collect::<Vec<_>>();
+    forget(db_conn, email, &gone)
+}
+
+/// Drops everything kept apart from the tasks `keys` of `email` (their tags, checklists and
+/// merges), as the tasks are gone for good.
+pub fn forget(db_conn: &mut PgConnection, email: &str, keys: &[String]) -> QueryResult<()> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    custom_tag::forget(db_conn, email, keys)?;
+    checklist::forget(db_conn, email, keys)?;
+    dedup::forget(db_conn, email, keys)
+}
+
+/// Bumps the version of every task in `after` that is new or differs from `before`, and says
+/// whether any did.
+fn bump_versions(before: &[AVTask], after: &mut [AVTask]) -> bool {
+    let before = before
+        .iter()
+        .map(|task| (task.id, task))
+        .collect::<std::collections::HashMap<_, _>>();
+    let mut changed = before.len() != after.len();
+    for task in after {
+        match before.get(&task.id) {
+            Some(old) => {
+                task.version = old.version;
+                if task != *old {
+                    task.version += 1;
+                    changed = true;
+                }
+            }
+            None => {
+                task.version = 1;
+                changed = true;
+            }
+        }
+    }
+    changed
 }
 
 /// Converts the serialised response [`RawFFTask`], that is received from Firefly, into [`AVTask`]. A
@@ -124,7 +572,7 @@ pub fn rawtask_to_task(tasks: Vec<RawFFTask>) -> Option<Vec<AVTask>> {
         let due_date = task.due_date?;
         standard_tasks.push({
             AVTask {
-                due_date: (&due_date).to_owned(),
+                due_date: due_date.clone(),
                 is_done: task.is_done?,
                 set_date: task.set_date?,
                 title: task.title?,
@@ -135,16 +583,36 @@ pub fn rawtask_to_task(tasks: Vec<RawFFTask>) -> Option<Vec<AVTask>> {
                         0
                     }
                 },
-                setter_key: (&setter.guid?).to_owned(), // this or guid. not sure
-                setter_name: (&setter.name?).to_owned(),
-                tags: vec![
-                    Tag::Source {
-                        source: "FF".into(),
-                    },
-                    Tag::DueDate {
-                        date: (&due_date).to_owned(),
-                    },
-                ],
+                setter_key: setter.guid?, // this or guid. not sure
+                setter_name: setter.name?,
+                // tasks that came without a source aren't tagged with one, as no source filter
+                // takes them in either
+                tags: task
+                    .task_source
+                    .as_ref()
+                    .map(|source| Tag::Source {
+                        source: source.to_string(),
+                    })
+                    .into_iter()
+                    .chain([Tag::DueDate {
+                        date: due_date.clone(),
+                    }])
+                    .collect(),
+                classes: task
+                    .classes
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|class| class.classname)
+                    .collect(),
+                file_submission_required: task.file_submission_required.unwrap_or(false),
+                has_file_submission: task.has_file_submission.unwrap_or(false),
+                is_marked: task.mark.and_then(|mark| mark.is_marked).unwrap_or(false),
+                checklist: vec![],
+                progress: None,
+                occurrence: None,
+                merged: vec![],
+                version: 0,
+                import_uid: None,
             }
         })
     }
@@ -0,0 +1,336 @@
+//! Webhooks the user subscribes to, which are sent [task events](super::event) as they happen.
+//!
+//! Events are queued as deliveries in the same transaction as the write that caused them, and
+//! sent by the [dispatcher](run) afterwards, so a slow or failing endpoint never holds up a sync
+//! and nothing is lost over a restart. Failed deliveries are retried with exponential backoff.
+//!
+//! Every delivery is a JSON `POST` carrying the headers:
+//! - `X-Lantern-Event`: the kind of event, e.g. `task.completed`
+//! - `X-Lantern-Delivery`: the id of the delivery, the same across retries
+//! - `X-Lantern-Signature-256`: `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with
+//!   the secret of the subscription
+use super::event::{Event, EventKind};
+use super::shutdown::Shutdown;
+use crate::models::{
+    NewWebhookDeliveryPG, NewWebhookSubscriptionPG, WebhookDeliveryPG, WebhookSubscriptionPG,
+};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use hmac::{Hmac, Mac};
+use reqwest::header;
+use serde::Serialize;
+use sha2::Sha256;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// How often pending deliveries are sent, unless `LANTERN_WEBHOOK_INTERVAL` says otherwise.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Deliveries that may fail before one is given up on; with the backoff below that is a little
+/// over four hours of retrying.
+const MAX_ATTEMPTS: i32 = 10;
+
+/// Wait before the first retry, doubled after each failure up to [`MAX_BACKOFF`].
+const BASE_BACKOFF: ChronoDuration = ChronoDuration::seconds(30);
+const MAX_BACKOFF: ChronoDuration = ChronoDuration::hours(6);
+
+/// Deliveries sent per tick, so that a backlog doesn't hold up shutdown.
+const BATCH_SIZE: i64 = 100;
+
+/// How long an endpoint gets to respond before the delivery is considered failed.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const SIGNATURE_HEADER: &str = "X-Lantern-Signature-256";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SubscriptionError {
+    #[error("invalid subscription: {0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+/// What is sent to the endpoint.
+#[derive(Serialize)]
+struct Payload<'a> {
+    occurred_at: DateTime<Utc>,
+    #[serde(flatten)]
+    details: &'a Event,
+}
+
+/// Subscribes `url` to the `event_types` (every event, if empty) of `email`.
+///
+/// The secret deliveries are signed with is generated unless one is given.
+pub fn subscribe(
+    db_conn: &mut PgConnection,
+    email: &str,
+    url: &str,
+    event_types: &[String],
+    secret: Option<&str>,
+) -> Result<WebhookSubscriptionPG, SubscriptionError> {
+    use crate::schema::webhook_subscriptions;
+
+    match reqwest::Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => {
+            return Err(SubscriptionError::Invalid(String::from(
+                "the url must be an http(s) url",
+            )))
+        }
+    }
+    for event_type in event_types {
+        EventKind::from_str(event_type).map_err(|_| {
+            let known = EventKind::ALL.map(|kind| kind.to_string()).join(", ");
+            SubscriptionError::Invalid(format!(
+                "unknown event type {}, expected one of {}",
+                event_type, known
+            ))
+        })?;
+    }
+    let secret = match secret {
+        Some(secret) => secret.to_string(),
+        None => Uuid::new_v4().simple().to_string() + &Uuid::new_v4().simple().to_string(),
+    };
+
+    Ok(diesel::insert_into(webhook_subscriptions::table)
+        .values(&NewWebhookSubscriptionPG {
+            user_email: email,
+            url,
+            secret: &secret,
+            event_types,
+        })
+        .get_result(db_conn)?)
+}
+
+/// Gets the webhook subscriptions of `email`.
+pub fn subscriptions(
+    db_conn: &mut PgConnection,
+    email: &str,
+) -> QueryResult<Vec<WebhookSubscriptionPG>> {
+    use crate::schema::webhook_subscriptions::dsl::*;
+
+    webhook_subscriptions
+        .filter(user_email.eq(email))
+        .order(id)
+        .load(db_conn)
+}
+
+/// Removes the subscription `subscription` of `email`, along with its deliveries. Returns whether
+/// there was one to remove.
+pub fn unsubscribe(
+    db_conn: &mut PgConnection,
+    email: &str,
+    subscription: i32,
+) -> QueryResult<bool> {
+    use crate::schema::webhook_subscriptions::dsl::*;
+
+    diesel::delete(
+        webhook_subscriptions
+            .filter(id.eq(subscription))
+            .filter(user_email.eq(email)),
+    )
+    .execute(db_conn)
+    .map(|removed| removed > 0)
+}
+
+/// The most recent deliveries to the subscriptions of `email` (or only to `subscription`).
+pub fn deliveries(
+    db_conn: &mut PgConnection,
+    email: &str,
+    subscription: Option<i32>,
+    limit: i64,
+) -> QueryResult<Vec<WebhookDeliveryPG>> {
+    use crate::schema::webhook_deliveries::dsl::*;
+    use crate::schema::webhook_subscriptions;
+
+    let mut query = webhook_deliveries
+        .inner_join(webhook_subscriptions::table)
+        .filter(webhook_subscriptions::user_email.eq(email))
+        .select(webhook_deliveries::all_columns())
+        .order(id.desc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(subscription) = subscription {
+        query = query.filter(subscription_id.eq(subscription));
+    }
+    query.load(db_conn)
+}
+
+/// Queues a delivery of each of `events` to every subscription of `email` that wants it.
+///
+/// Meant to be called in the transaction that wrote the change, so the two can't diverge.
+pub fn enqueue(db_conn: &mut PgConnection, email: &str, events: &[Event]) -> QueryResult<()> {
+    use crate::schema::webhook_deliveries;
+
+    if events.is_empty() {
+        return Ok(());
+    }
+    let subs = subscriptions(db_conn, email)?;
+    let occurred_at = Utc::now();
+
+    let event_types = events
+        .iter()
+        .map(|event| event.kind.to_string())
+        .collect::<Vec<_>>();
+    let queued = events
+        .iter()
+        .zip(&event_types)
+        .flat_map(|(event, event_type)| {
+            subs.iter()
+                .filter(|sub| sub.event_types.is_empty() || sub.event_types.contains(event_type))
+                .map(move |sub| NewWebhookDeliveryPG {
+                    subscription_id: sub.id,
+                    event_type,
+                    payload: serde_json::to_value(Payload {
+                        occurred_at,
+                        details: event,
+                    })
+                    .unwrap(),
+                })
+        })
+        .collect::<Vec<_>>();
+    if queued.is_empty() {
+        return Ok(());
+    }
+
+    tracing::debug!(
+        events = events.len(),
+        deliveries = queued.len(),
+        "queued webhooks"
+    );
+    diesel::insert_into(webhook_deliveries::table)
+        .values(&queued)
+        .execute(db_conn)?;
+    Ok(())
+}
+
+/// The value of [`SIGNATURE_HEADER`] for `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// How long to wait before retrying a delivery that has failed `attempts` times.
+pub(crate) fn backoff(attempts: i32) -> ChronoDuration {
+    let doublings = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    (BASE_BACKOFF * 2_i32.pow(doublings)).min(MAX_BACKOFF)
+}
+
+/// Sends the pending deliveries whose (next) attempt is due by `now`, stopping early on shutdown.
+///
+/// No connection is held while a delivery is being sent, as an endpoint may take up to ten seconds
+/// to respond.
+pub async fn dispatch(
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    http_client: &reqwest::Client,
+    now: DateTime<Utc>,
+    shutdown: &Shutdown,
+) -> color_eyre::Result<()> {
+    use crate::schema::webhook_deliveries::dsl::*;
+    use crate::schema::webhook_subscriptions;
+
+    let ready = webhook_deliveries
+        .inner_join(webhook_subscriptions::table)
+        .filter(state.eq("pending"))
+        .filter(next_attempt_at.le(now))
+        .order(id)
+        .limit(BATCH_SIZE)
+        .load::<(WebhookDeliveryPG, WebhookSubscriptionPG)>(&mut db_pool.get()?)?;
+
+    for (delivery, sub) in ready {
+        if shutdown.is_triggered() {
+            break;
+        }
+        let body = delivery.payload.to_string();
+        let res = http_client
+            .post(&sub.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Lantern-Event", &delivery.event_type)
+            .header("X-Lantern-Delivery", delivery.id)
+            .header(SIGNATURE_HEADER, sign(&sub.secret, body.as_bytes()))
+            .body(body)
+            .send()
+            .await;
+
+        let status = res
+            .as_ref()
+            .ok()
+            .map(|res| i32::from(res.status().as_u16()));
+        // the url of a webhook may carry a token of its own, so keep it out of errors
+        let error = match res.and_then(|res| res.error_for_status()) {
+            Ok(_) => None,
+            Err(e) => Some(e.without_url().to_string()),
+        };
+        let tried = delivery.attempts + 1;
+        let target = webhook_deliveries.find(delivery.id);
+        let mut db_conn = db_pool.get()?;
+
+        match error {
+            None => {
+                diesel::update(target)
+                    .set((
+                        state.eq("delivered"),
+                        attempts.eq(tried),
+                        last_status.eq(status),
+                        last_error.eq(None::<String>),
+                        delivered_at.eq(Utc::now()),
+                    ))
+                    .execute(&mut db_conn)?;
+            }
+            Some(e) => {
+                let given_up = tried >= MAX_ATTEMPTS;
+                tracing::warn!(
+                    error = %e,
+                    delivery = delivery.id,
+                    subscription = sub.id,
+                    attempts = tried,
+                    given_up,
+                    "failed to deliver webhook"
+                );
+                diesel::update(target)
+                    .set((
+                        state.eq(if given_up { "failed" } else { "pending" }),
+                        attempts.eq(tried),
+                        last_status.eq(status),
+                        last_error.eq(Some(e)),
+                        next_attempt_at.eq(Utc::now() + backoff(tried)),
+                    ))
+                    .execute(&mut db_conn)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends queued deliveries until shutdown.
+pub async fn run(db_conn: Pool<ConnectionManager<PgConnection>>, shutdown: Shutdown) {
+    let period = std::env::var("LANTERN_WEBHOOK_INTERVAL")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let http_client = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let span = tracing::info_span!("webhooks");
+                let res = dispatch(&db_conn, &http_client, Utc::now(), &shutdown)
+                    .instrument(span)
+                    .await;
+                if let Err(e) = res {
+                    tracing::error!(error = %e, "failed to send webhooks");
+                }
+            }
+            _ = shutdown.triggered() => break,
+        }
+    }
+}
@@ -1,2 +1,18 @@
+use crate::lumos::telemetry::TraceQueries;
+
+use color_eyre::{eyre::Context, Result};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+
 pub mod models;
 pub mod schema;
+
+/// Connects to the database at `DATABASE_URL`, tracing every query.
+pub fn pool() -> Result<Pool<ConnectionManager<PgConnection>>> {
+    let db_url = std::env::var("DATABASE_URL").wrap_err("DATABASE_URL must be set")?;
+    Pool::builder()
+        .test_on_check_out(true)
+        .connection_customizer(Box::new(TraceQueries))
+        .build(ConnectionManager::<PgConnection>::new(db_url))
+        .wrap_err("Could not build connection pool")
+}
@@ -1,5 +1,22 @@
+use super::schema::checklist_items;
+use super::schema::custom_tags;
+use super::schema::feed_tokens;
+use super::schema::priority_weights;
+use super::schema::reminder_rules;
+use super::schema::reminders;
+use super::schema::task_audit;
+use super::schema::task_changes;
+use super::schema::task_details;
+use super::schema::task_listings;
+use super::schema::task_merges;
+use super::schema::task_series;
+use super::schema::task_tags;
 use super::schema::tasks;
+use super::schema::trashed_tasks;
 use super::schema::users;
+use super::schema::webhook_deliveries;
+use super::schema::webhook_subscriptions;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use diesel::prelude::*;
 use serde::Serialize;
 use serde_json;
@@ -20,6 +37,10 @@ pub struct TasksPG {
     pub user_email: String,
     pub local_tasks: serde_json::Value,
     pub firefly_tasks: serde_json::Value,
+    /// Bumped whenever the local tasks change.
+    pub version: i64,
+    /// No local task has had this id, or any after it.
+    pub next_local_id: i64,
 }
 
 #[derive(Insertable)]
@@ -37,3 +58,319 @@ pub struct NewTasksPG<'a> {
     pub local_tasks: serde_json::Value,
     pub firefly_tasks: serde_json::Value,
 }
+
+#[derive(Queryable, Debug)]
+#[diesel(table_name = feed_tokens)]
+pub struct FeedTokenPG {
+    pub id: i32,
+    pub user_email: String,
+    pub token: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = feed_tokens)]
+pub struct NewFeedTokenPG<'a> {
+    pub user_email: &'a str,
+    pub token: &'a str,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = reminder_rules)]
+pub struct ReminderRulePG {
+    pub id: i32,
+    pub user_email: String,
+    pub minutes_before: i32,
+    pub channel: String,
+    pub target: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = reminder_rules)]
+pub struct NewReminderRulePG<'a> {
+    pub user_email: &'a str,
+    pub minutes_before: i32,
+    pub channel: &'a str,
+    pub target: Option<&'a str>,
+}
+
+#[derive(Queryable, Debug)]
+#[diesel(table_name = reminders)]
+pub struct ReminderPG {
+    pub id: i32,
+    pub rule_id: i32,
+    pub user_email: String,
+    pub task_key: String,
+    pub title: String,
+    pub due_at: DateTime<Utc>,
+    pub fire_at: DateTime<Utc>,
+    pub state: String,
+    pub attempts: i32,
+    pub sent_at: Option<DateTime<Utc>>,
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = reminders)]
+pub struct NewReminderPG<'a> {
+    pub rule_id: i32,
+    pub user_email: &'a str,
+    pub task_key: &'a str,
+    pub title: &'a str,
+    pub due_at: DateTime<Utc>,
+    pub fire_at: DateTime<Utc>,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = webhook_subscriptions)]
+pub struct WebhookSubscriptionPG {
+    pub id: i32,
+    pub user_email: String,
+    pub url: String,
+    pub secret: String,
+    pub event_types: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webhook_subscriptions)]
+pub struct NewWebhookSubscriptionPG<'a> {
+    pub user_email: &'a str,
+    pub url: &'a str,
+    pub secret: &'a str,
+    pub event_types: &'a [String],
+}
+
+#[derive(Queryable, Debug)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct WebhookDeliveryPG {
+    pub id: i32,
+    pub subscription_id: i32,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub state: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewWebhookDeliveryPG<'a> {
+    pub subscription_id: i32,
+    pub event_type: &'a str,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Queryable, Debug)]
+#[diesel(table_name = priority_weights)]
+pub struct PriorityWeightsPG {
+    pub id: i32,
+    pub user_email: String,
+    pub weights: serde_json::Value,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = priority_weights)]
+pub struct NewPriorityWeightsPG<'a> {
+    pub user_email: &'a str,
+    pub weights: serde_json::Value,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = custom_tags)]
+pub struct CustomTagPG {
+    pub id: i32,
+    pub user_email: String,
+    pub name: String,
+    pub colour: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = custom_tags)]
+pub struct NewCustomTagPG<'a> {
+    pub user_email: &'a str,
+    pub name: &'a str,
+    pub colour: &'a str,
+}
+
+#[derive(Queryable, Insertable, Debug)]
+#[diesel(table_name = task_tags)]
+pub struct TaskTagPG {
+    pub tag_id: i32,
+    pub task_key: String,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = checklist_items)]
+pub struct ChecklistItemPG {
+    pub id: i32,
+    pub user_email: String,
+    pub task_key: String,
+    pub position: i32,
+    pub title: String,
+    pub is_done: bool,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = checklist_items)]
+pub struct NewChecklistItemPG<'a> {
+    pub user_email: &'a str,
+    pub task_key: &'a str,
+    pub position: i32,
+    pub title: &'a str,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = task_series)]
+pub struct TaskSeriesPG {
+    pub id: i32,
+    pub user_email: String,
+    pub title: String,
+    pub frequency: serde_json::Value,
+    pub timezone: String,
+    pub starts_at: NaiveDateTime,
+    pub until: Option<NaiveDate>,
+    pub generated_until: Option<NaiveDateTime>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = task_series)]
+pub struct NewTaskSeriesPG<'a> {
+    pub user_email: &'a str,
+    pub title: &'a str,
+    pub frequency: serde_json::Value,
+    pub timezone: &'a str,
+    pub starts_at: NaiveDateTime,
+    pub until: Option<NaiveDate>,
+    pub generated_until: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = task_merges)]
+pub struct TaskMergePG {
+    pub id: i32,
+    pub user_email: String,
+    pub task_keys: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = task_merges)]
+pub struct NewTaskMergePG<'a> {
+    pub user_email: &'a str,
+    pub task_keys: &'a [String],
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = task_changes)]
+pub struct TaskChangePG {
+    pub seq: i64,
+    pub user_email: String,
+    pub task_key: String,
+    pub kind: String,
+    pub task: Option<serde_json::Value>,
+    pub changed_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = task_changes)]
+pub struct NewTaskChangePG<'a> {
+    pub user_email: &'a str,
+    pub task_key: String,
+    pub kind: &'a str,
+    pub task: Option<serde_json::Value>,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = task_audit)]
+pub struct TaskAuditPG {
+    pub id: i64,
+    pub user_email: String,
+    pub task_key: String,
+    pub action: String,
+    pub actor: String,
+    pub fields: Vec<String>,
+    pub old_value: Option<serde_json::Value>,
+    pub new_value: Option<serde_json::Value>,
+    pub recorded_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = task_audit)]
+pub struct NewTaskAuditPG<'a> {
+    pub user_email: &'a str,
+    pub task_key: String,
+    pub action: &'a str,
+    pub actor: &'a str,
+    pub fields: Vec<String>,
+    pub old_value: Option<serde_json::Value>,
+    pub new_value: Option<serde_json::Value>,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = trashed_tasks)]
+pub struct TrashedTaskPG {
+    pub id: i32,
+    pub user_email: String,
+    pub task_key: String,
+    pub task: Option<serde_json::Value>,
+    pub deleted_at: DateTime<Utc>,
+    pub purged_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = trashed_tasks)]
+pub struct NewTrashedTaskPG<'a> {
+    pub user_email: &'a str,
+    pub task_key: &'a str,
+    pub task: serde_json::Value,
+}
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = task_details)]
+pub struct TaskDetailPG {
+    pub id: i32,
+    pub user_email: String,
+    pub task_key: String,
+    pub detail: serde_json::Value,
+    pub fetched_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = task_details)]
+pub struct NewTaskDetailPG<'a> {
+    pub user_email: &'a str,
+    pub task_key: &'a str,
+    pub detail: serde_json::Value,
+}
+
+#[derive(Queryable, Debug)]
+#[diesel(table_name = task_listings)]
+pub struct TaskListingPG {
+    pub id: String,
+    pub user_email: String,
+    pub tasks: serde_json::Value,
+    pub version: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = task_listings)]
+pub struct NewTaskListingPG<'a> {
+    pub id: &'a str,
+    pub user_email: &'a str,
+    pub tasks: serde_json::Value,
+    pub version: i64,
+}
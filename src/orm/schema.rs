@@ -1,12 +1,170 @@
 #![cfg_attr(rustfmt, rustfmt_skip)]
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    checklist_items (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        task_key -> Varchar,
+        position -> Int4,
+        title -> Varchar,
+        is_done -> Bool,
+        created_at -> Timestamptz,
+        completed_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    custom_tags (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        name -> Varchar,
+        colour -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    feed_tokens (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        token -> Varchar,
+        created_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    priority_weights (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        weights -> Jsonb,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    reminder_rules (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        minutes_before -> Int4,
+        channel -> Varchar,
+        target -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    reminders (id) {
+        id -> Int4,
+        rule_id -> Int4,
+        user_email -> Varchar,
+        task_key -> Varchar,
+        title -> Varchar,
+        due_at -> Timestamptz,
+        fire_at -> Timestamptz,
+        state -> Varchar,
+        attempts -> Int4,
+        sent_at -> Nullable<Timestamptz>,
+        next_attempt_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    task_audit (id) {
+        id -> Int8,
+        user_email -> Varchar,
+        task_key -> Text,
+        action -> Varchar,
+        actor -> Varchar,
+        fields -> Array<Text>,
+        old_value -> Nullable<Jsonb>,
+        new_value -> Nullable<Jsonb>,
+        recorded_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    task_changes (seq) {
+        seq -> Int8,
+        user_email -> Varchar,
+        task_key -> Text,
+        kind -> Varchar,
+        task -> Nullable<Jsonb>,
+        changed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    task_details (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        task_key -> Text,
+        detail -> Jsonb,
+        fetched_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    task_listings (id) {
+        id -> Varchar,
+        user_email -> Varchar,
+        tasks -> Jsonb,
+        version -> Int8,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    task_merges (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        task_keys -> Array<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    task_series (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        title -> Varchar,
+        frequency -> Jsonb,
+        timezone -> Varchar,
+        starts_at -> Timestamp,
+        until -> Nullable<Date>,
+        generated_until -> Nullable<Timestamp>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    task_tags (tag_id, task_key) {
+        tag_id -> Int4,
+        task_key -> Varchar,
+    }
+}
+
 diesel::table! {
     tasks (id) {
         id -> Int4,
         user_email -> Varchar,
         local_tasks -> Jsonb,
         firefly_tasks -> Jsonb,
+        version -> Int8,
+        next_local_id -> Int8,
+    }
+}
+
+diesel::table! {
+    trashed_tasks (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        task_key -> Text,
+        task -> Nullable<Jsonb>,
+        deleted_at -> Timestamptz,
+        purged_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -19,7 +177,54 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        subscription_id -> Int4,
+        event_type -> Varchar,
+        payload -> Jsonb,
+        state -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_status -> Nullable<Int4>,
+        last_error -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        delivered_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    webhook_subscriptions (id) {
+        id -> Int4,
+        user_email -> Varchar,
+        url -> Varchar,
+        secret -> Varchar,
+        event_types -> Array<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::joinable!(reminders -> reminder_rules (rule_id));
+diesel::joinable!(task_tags -> custom_tags (tag_id));
+diesel::joinable!(webhook_deliveries -> webhook_subscriptions (subscription_id));
+
 diesel::allow_tables_to_appear_in_same_query!(
+    checklist_items,
+    custom_tags,
+    feed_tokens,
+    priority_weights,
+    reminder_rules,
+    reminders,
+    task_audit,
+    task_changes,
+    task_details,
+    task_listings,
+    task_merges,
+    task_series,
+    task_tags,
     tasks,
+    trashed_tasks,
     users,
+    webhook_deliveries,
+    webhook_subscriptions,
 );
@@ -0,0 +1,66 @@
+use lantern::lumos::dedup::{score, similarity, DUE_WINDOW_HOURS, THRESHOLD};
+use lantern::lumos::task::AVTask;
+
+use chrono::{Duration, TimeZone, Utc};
+
+fn task(title: &str, due_in_hours: i64) -> AVTask {
+    let due = Utc.with_ymd_and_hms(2026, 10, 26, 9, 0, 0).unwrap() + Duration::hours(due_in_hours);
+    AVTask {
+        title: title.to_string(),
+        due_date: due.to_rfc3339(),
+        setter_name: String::from("Ms Tan"),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn identical_titles_are_alike() {
+    assert_eq!(
+        similarity("Chemistry worksheet 3", "Chemistry worksheet 3"),
+        1.0
+    );
+    // case, spacing and punctuation don't count
+    assert_eq!(
+        similarity("Chemistry Worksheet #3", "chemistry worksheet 3"),
+        1.0
+    );
+}
+
+#[test]
+fn near_identical_titles_are_alike() {
+    let alike = similarity("Chemistry worksheet 3", "Chemistry worksheet three");
+    assert!(alike > 0.7 && alike < 1.0, "{}", alike);
+}
+
+#[test]
+fn unrelated_titles_are_not_alike() {
+    assert!(similarity("Chemistry worksheet 3", "History essay draft") < 0.3);
+    assert_eq!(similarity("a", "b"), 0.0);
+}
+
+#[test]
+fn scores_duplicates_above_the_threshold() {
+    let a = task("Chemistry worksheet 3", 0);
+    let b = task("Chemistry Worksheet #3", 2);
+    assert_eq!(score(&a, &b), Some(1.0));
+
+    let unrelated = task("History essay draft", 0);
+    assert!(score(&a, &unrelated).is_some_and(|score| score < THRESHOLD));
+}
+
+#[test]
+fn only_scores_tasks_due_within_the_window() {
+    let a = task("Chemistry worksheet 3", 0);
+    assert!(score(&a, &task("Chemistry worksheet 3", DUE_WINDOW_HOURS)).is_some());
+    assert!(score(&a, &task("Chemistry worksheet 3", -DUE_WINDOW_HOURS)).is_some());
+    assert_eq!(
+        score(&a, &task("Chemistry worksheet 3", DUE_WINDOW_HOURS + 1)),
+        None
+    );
+
+    let undated = AVTask {
+        due_date: String::from("someday"),
+        ..task("Chemistry worksheet 3", 0)
+    };
+    assert_eq!(score(&a, &undated), None);
+}
@@ -0,0 +1,34 @@
+use lantern::lumos::detail::to_markdown;
+
+#[test]
+fn keeps_web_and_mail_links() {
+    let markdown = to_markdown(
+        r#"<p><a href="https://example.com/sheet.pdf">sheet</a> or <a href="mailto:tan@school.uk">email me</a></p>"#,
+    );
+    assert!(
+        markdown.contains("https://example.com/sheet.pdf"),
+        "{}",
+        markdown
+    );
+    assert!(markdown.contains("mailto:tan@school.uk"), "{}", markdown);
+}
+
+#[test]
+fn drops_links_to_anything_else() {
+    for href in [
+        "javascript:alert(1)",
+        "ftp://example.com/sheet.pdf",
+        "data:text/html,hi",
+        "tel:+441234567890",
+    ] {
+        let markdown = to_markdown(&format!(r#"<a href="{}">sheet</a>"#, href));
+        assert!(!markdown.contains(href), "{}", markdown);
+        assert!(markdown.contains("sheet"), "{}", markdown);
+    }
+}
+
+#[test]
+fn drops_scripts() {
+    let markdown = to_markdown("<p>Read chapter 3</p><script>alert(1)</script>");
+    assert_eq!(markdown, "Read chapter 3");
+}
@@ -0,0 +1,29 @@
+use lantern::lumos::ics::feed_url;
+use lantern::lumos::rpc::light::Filter;
+
+#[test]
+fn feed_urls_encode_the_filter() {
+    let filter = Filter {
+        status: String::from("Todo"),
+        sources: vec![String::from("Clubs & Societies"), String::from("#1=A")],
+        ..Default::default()
+    };
+    let url = feed_url("abc", &filter).unwrap();
+    assert!(url
+        .ends_with("/v1/calendar/abc/tasks.ics?status=Todo&source=Clubs+%26+Societies%2C%231%3DA"));
+
+    let decoded = url::Url::parse(&url).unwrap();
+    assert_eq!(
+        decoded.query_pairs().collect::<Vec<_>>(),
+        [
+            ("status".into(), "Todo".into()),
+            ("source".into(), "Clubs & Societies,#1=A".into()),
+        ]
+    );
+}
+
+#[test]
+fn feed_urls_without_a_filter_have_no_query() {
+    let url = feed_url("abc", &Filter::default()).unwrap();
+    assert!(url.ends_with("/v1/calendar/abc/tasks.ics"), "{}", url);
+}
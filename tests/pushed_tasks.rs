@@ -0,0 +1,110 @@
+use lantern::lumos::dedup::collapse;
+use lantern::lumos::task::{AVTask, ChecklistItem, Occurrence, Tag};
+use lantern::lumos::user::utils::{from_client, strip_computed};
+use lantern::models::TaskMergePG;
+
+use chrono::{NaiveDate, Utc};
+
+/// A local task as it is stored.
+fn stored() -> AVTask {
+    AVTask {
+        id: 4,
+        title: String::from("Revise for the mock"),
+        due_date: String::from("2026-11-02"),
+        setter_key: String::from("self"),
+        tags: vec![Tag::DueDate {
+            date: String::from("2026-11-02"),
+        }],
+        version: 3,
+        import_uid: Some(String::from("mock@school")),
+        ..Default::default()
+    }
+}
+
+/// `task` as it is read: collapsed with a duplicate that is done, and with its checklist,
+/// priority and custom tags put on.
+fn read(task: AVTask) -> AVTask {
+    let duplicate = AVTask {
+        id: 9,
+        is_done: true,
+        ..Default::default()
+    };
+    let merge = TaskMergePG {
+        id: 1,
+        user_email: String::from("a@b"),
+        task_keys: vec![String::from("local:4"), String::from("local:9")],
+        created_at: Utc::now(),
+    };
+    let mut read = collapse(&[merge], vec![("local", vec![task, duplicate])]).remove(0);
+    read.checklist = vec![ChecklistItem {
+        id: 1,
+        title: String::from("Past papers"),
+        is_done: true,
+    }];
+    read.progress = Some(1.0);
+    read.tags.push(Tag::Priority {
+        priority: String::from("0.8"),
+    });
+    read.tags.push(Tag::Custom {
+        id: 2,
+        name: String::from("revision"),
+        colour: String::from("#808080"),
+    });
+    read
+}
+
+#[test]
+fn a_task_read_and_pushed_back_is_unchanged() {
+    let mut pushed = read(stored());
+    assert!(pushed.is_done);
+    assert_eq!(pushed.merged.len(), 2);
+
+    from_client(&mut pushed, Some(&stored()));
+    assert_eq!(pushed, stored());
+}
+
+#[test]
+fn edits_survive_being_pushed_back() {
+    let mut pushed = read(stored());
+    pushed.title = String::from("Revise for the mocks");
+    from_client(&mut pushed, Some(&stored()));
+
+    assert_eq!(pushed.title, "Revise for the mocks");
+    assert!(!pushed.is_done);
+    assert!(pushed.checklist.is_empty() && pushed.merged.is_empty());
+}
+
+#[test]
+fn clients_cant_set_the_series_or_calendar_entry() {
+    let mut pushed = AVTask {
+        occurrence: Some(Occurrence {
+            series_id: 1,
+            at: NaiveDate::from_ymd_opt(2026, 11, 2)
+                .unwrap()
+                .and_hms_opt(9, 0, 0)
+                .unwrap(),
+            detached: false,
+        }),
+        import_uid: Some(String::from("made-up")),
+        ..stored()
+    };
+    from_client(&mut pushed, None);
+    assert_eq!((pushed.occurrence, pushed.import_uid), (None, None));
+
+    let mut pushed = AVTask {
+        import_uid: Some(String::from("made-up")),
+        ..stored()
+    };
+    from_client(&mut pushed, Some(&stored()));
+    assert_eq!(pushed.import_uid, stored().import_uid);
+}
+
+#[test]
+fn a_task_outside_any_merge_keeps_its_done_state() {
+    let mut done = AVTask {
+        is_done: true,
+        ..stored()
+    };
+    strip_computed(&mut done, Some(&stored()));
+    assert!(done.is_done);
+}
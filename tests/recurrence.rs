@@ -0,0 +1,173 @@
+use lantern::lumos::recurrence::{Frequency, Series};
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use chrono_tz::{Europe::London, Tz};
+
+fn at(date: &str) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap()
+}
+
+fn series(frequency: Frequency, timezone: Tz, starts_at: &str, until: Option<&str>) -> Series {
+    Series {
+        title: String::from("vocab test"),
+        frequency,
+        timezone,
+        starts_at: at(starts_at),
+        until: until.map(|until| NaiveDate::parse_from_str(until, "%Y-%m-%d").unwrap()),
+    }
+}
+
+#[test]
+fn keeps_the_local_time_across_daylight_saving() {
+    // British Summer Time ends at 2am on 25 October 2026
+    let daily = series(Frequency::Daily, London, "2026-10-24 08:00", None);
+    let due = daily
+        .occurrences()
+        .take(3)
+        .map(|at| daily.due(at).with_timezone(&Utc))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        due,
+        [
+            Utc.with_ymd_and_hms(2026, 10, 24, 7, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2026, 10, 25, 8, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2026, 10, 26, 8, 0, 0).unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn moves_skipped_times_forward_and_takes_repeated_times_first() {
+    // 1am to 2am is skipped on 29 March 2026, and 1am to 2am happens twice on 25 October 2026
+    let skipped = series(Frequency::Daily, London, "2026-03-29 01:30", None);
+    assert_eq!(
+        skipped.due(at("2026-03-29 01:30")).with_timezone(&Utc),
+        Utc.with_ymd_and_hms(2026, 3, 29, 1, 30, 0).unwrap()
+    );
+
+    let repeated = series(Frequency::Daily, London, "2026-10-25 01:30", None);
+    assert_eq!(
+        repeated.due(at("2026-10-25 01:30")).with_timezone(&Utc),
+        Utc.with_ymd_and_hms(2026, 10, 25, 0, 30, 0).unwrap()
+    );
+}
+
+#[test]
+fn monthly_falls_back_to_the_last_day_of_short_months() {
+    let monthly = series(Frequency::Monthly, London, "2026-01-31 09:00", None);
+    assert_eq!(
+        monthly.occurrences().take(4).collect::<Vec<_>>(),
+        [
+            at("2026-01-31 09:00"),
+            at("2026-02-28 09:00"),
+            at("2026-03-31 09:00"),
+            at("2026-04-30 09:00"),
+        ]
+    );
+
+    let leap = series(Frequency::Monthly, London, "2028-01-31 09:00", None);
+    assert_eq!(leap.occurrences().nth(1), Some(at("2028-02-29 09:00")));
+}
+
+#[test]
+fn weekly_stops_after_until() {
+    // 19 October 2026 is a Monday
+    let weekly = series(
+        Frequency::Weekly {
+            weekdays: vec![Weekday::Mon, Weekday::Thu],
+        },
+        London,
+        "2026-10-19 16:00",
+        Some("2026-11-01"),
+    );
+    assert_eq!(
+        weekly.occurrences().collect::<Vec<_>>(),
+        [
+            at("2026-10-19 16:00"),
+            at("2026-10-22 16:00"),
+            at("2026-10-26 16:00"),
+            at("2026-10-29 16:00"),
+        ]
+    );
+}
+
+#[test]
+fn until_takes_in_its_own_day() {
+    let daily = series(
+        Frequency::Daily,
+        London,
+        "2026-10-19 23:30",
+        Some("2026-10-20"),
+    );
+    assert_eq!(daily.occurrences().count(), 2);
+}
+
+#[test]
+fn occurrences_after_jump_to_the_same_occurrences() {
+    let all = [
+        series(Frequency::Daily, London, "2026-01-01 08:00", None),
+        series(
+            Frequency::EveryNDays { days: 3 },
+            London,
+            "2026-01-01 08:00",
+            None,
+        ),
+        series(
+            Frequency::Weekly {
+                weekdays: vec![Weekday::Mon, Weekday::Thu],
+            },
+            London,
+            "2026-01-01 08:00",
+            None,
+        ),
+        series(Frequency::Monthly, London, "2026-01-31 09:00", None),
+    ];
+    for after in ["2025-12-01 00:00", "2026-03-31 09:00", "2026-10-25 07:59"] {
+        let after = at(after);
+        for series in &all {
+            assert_eq!(
+                series.occurrences_after(after).take(5).collect::<Vec<_>>(),
+                series
+                    .occurrences()
+                    .filter(|at| *at > after)
+                    .take(5)
+                    .collect::<Vec<_>>(),
+                "{:?} after {}",
+                series.frequency,
+                after
+            );
+        }
+    }
+}
+
+#[test]
+fn a_series_that_fell_behind_only_catches_up_on_the_last_day() {
+    let daily = series(Frequency::Daily, London, "2025-01-01 08:00", None);
+    let now = Utc.with_ymd_and_hms(2026, 10, 26, 12, 0, 0).unwrap();
+
+    // last generated well over a year ago, as if the server had been down since
+    let (pending, last) = daily.pending(Some(at("2025-02-01 08:00")), false, now);
+    let first = at("2026-10-26 08:00");
+    assert_eq!(
+        pending,
+        (0..8)
+            .map(|day| first + Duration::days(day))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(last, Some(at("2026-11-02 08:00")));
+
+    // nothing generated yet, for a series that started long ago
+    let (pending, _) = daily.pending(None, false, now);
+    assert_eq!(pending.first(), Some(&first));
+}
+
+#[test]
+fn a_series_with_nothing_due_soon_still_gets_its_next_occurrence() {
+    let monthly = series(Frequency::Monthly, London, "2025-01-31 09:00", None);
+    let now = Utc.with_ymd_and_hms(2026, 10, 15, 12, 0, 0).unwrap();
+
+    let (pending, _) = monthly.pending(Some(at("2025-03-31 09:00")), false, now);
+    assert_eq!(pending, [at("2026-10-31 09:00")]);
+    let (pending, _) = monthly.pending(Some(at("2025-03-31 09:00")), true, now);
+    assert!(pending.is_empty());
+}
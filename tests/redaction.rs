@@ -0,0 +1,142 @@
+use lantern::lumos::error::FireflyError;
+use lantern::lumos::redact::{self, MakeRedacting};
+use lantern::lumos::user::utils::send;
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+const SECRET: &str = "0123456789ABCDEF0123456789ABCDEF";
+const DEVICE_ID: &str = "5d0f9d4e-6a4f-4a7b-9c1e-2f3b4c5d6e7f";
+
+/// Url of a port that nothing listens on, carrying the credentials as Firefly expects them.
+fn unreachable_url() -> reqwest::Url {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    reqwest::Url::parse_with_params(
+        &format!("http://127.0.0.1:{}/Login/api/gettoken", port),
+        [
+            ("ffauth_device_id", DEVICE_ID),
+            ("ffauth_secret", SECRET),
+            ("device_id", DEVICE_ID),
+            ("app_id", "avagarde"),
+        ],
+    )
+    .unwrap()
+}
+
+fn assert_redacted(output: &str) {
+    assert!(!output.contains(SECRET), "secret leaked: {}", output);
+    assert!(!output.contains(DEVICE_ID), "device id leaked: {}", output);
+}
+
+#[test]
+fn redacts_query_params_in_text() {
+    let text = format!(
+        "GET https://example.com/api?ffauth_device_id={DEVICE_ID}&ffauth_secret={SECRET}&device_id={DEVICE_ID}&app_id=avagarde failed"
+    );
+    let redacted = redact::redact(&text);
+
+    assert_redacted(&redacted);
+    assert!(redacted.contains("ffauth_secret=REDACTED"));
+    assert!(redacted.contains("&device_id=REDACTED"));
+    assert!(redacted.contains("app_id=avagarde failed"));
+}
+
+#[test]
+fn redacts_url() {
+    let mut url = unreachable_url();
+    redact::redact_url(&mut url);
+
+    assert_redacted(url.as_str());
+    assert!(url.as_str().contains("app_id=avagarde"));
+}
+
+#[tokio::test]
+async fn failing_request_never_prints_secret() {
+    let err = send("gettoken", reqwest::Client::new().get(unreachable_url()))
+        .await
+        .expect_err("nothing should be listening");
+
+    assert_redacted(&err.to_string());
+    assert_redacted(&format!("{:?}", err));
+
+    let err = FireflyError::from(err);
+    assert_redacted(&err.to_string());
+    assert_redacted(&format!("{:?}", err));
+
+    let report = color_eyre::Report::from(err);
+    assert_redacted(&format!("{}", report));
+    assert_redacted(&format!("{:?}", report));
+}
+
+#[tokio::test]
+async fn unredacted_reqwest_error_is_redacted_on_conversion() {
+    let err = reqwest::Client::new()
+        .get(unreachable_url())
+        .send()
+        .await
+        .expect_err("nothing should be listening");
+    assert!(err.to_string().contains(SECRET));
+
+    let err = FireflyError::from(err);
+    assert_redacted(&err.to_string());
+    assert_redacted(&format!("{:?}", err));
+}
+
+#[test]
+fn registered_secrets_are_redacted_anywhere() {
+    redact::register(SECRET);
+
+    let text = format!("<token>{}</token>", SECRET);
+    let redacted = redact::redact(&text);
+    assert_redacted(&redacted);
+    assert_eq!(redacted, "<token>REDACTED</token>");
+
+    let misc = FireflyError::Misc(format!("malformed response: {}", SECRET));
+    assert_redacted(&misc.to_string());
+}
+
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Buffer {
+    type Writer = Buffer;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[test]
+fn tracing_fields_are_redacted() {
+    let buf = Buffer::default();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_writer(MakeRedacting(buf.clone()))
+        .finish();
+
+    tracing::subscriber::with_default(subscriber, || {
+        let url = unreachable_url();
+        tracing::error!(url = %url, "request to {} failed", url);
+    });
+
+    let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    assert_redacted(&output);
+    assert!(output.contains("ffauth_secret=REDACTED"));
+    serde_json::from_str::<serde_json::Value>(output.trim()).expect("log line is still json");
+}